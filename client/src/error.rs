@@ -0,0 +1,70 @@
+use std::fmt;
+
+use reqwest::StatusCode;
+
+#[derive(Debug)]
+pub enum Error {
+    // The server couldn't be reached, or answered with something that isn't the API
+    Http(reqwest::Error),
+    // The server turned the request down
+    Api(ApiError),
+    // An event stream had an event we don't understand
+    Stream(String),
+}
+
+// An error response from the server.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: StatusCode,
+    // One of the server's error codes, like `not_found` or `rate_limited`
+    pub code: String,
+    pub message: String,
+    // Identifies the request in the server's logs, empty if the server didn't send one
+    pub request_id: String,
+    // How many seconds to wait before trying again, for rate limited or busy servers
+    pub retry_after: Option<u64>,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "request failed: {e}"),
+            Error::Api(e) => write!(f, "{e}"),
+            Error::Stream(e) => write!(f, "invalid event stream: {e}"),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)?;
+        if let Some(secs) = self.retry_after {
+            write!(f, ", try again in {secs}s")?;
+        }
+        if !self.request_id.is_empty() {
+            write!(f, " [request {}]", self.request_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<ApiError> for Error {
+    fn from(e: ApiError) -> Self {
+        Error::Api(e)
+    }
+}
@@ -0,0 +1,309 @@
+// A client for the Zirco playground's API.
+//
+//     let client = Client::new("https://play.zirco.dev").with_api_key(key);
+//     let job = client.submit(TaskType::Execute, code).await?;
+//     let mut events = client.stream(job).await?;
+//     while let Some(event) = events.next().await { ... }
+
+mod error;
+mod models;
+
+pub use error::{ApiError, Error};
+pub use models::*;
+
+use eventsource_stream::{EventStreamError, Eventsource};
+use futures::{Stream, StreamExt};
+use reqwest::{Method, RequestBuilder, Response, StatusCode, header};
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    // The server's address, without a trailing slash
+    base: String,
+    api_key: Option<String>,
+}
+
+impl Client {
+    pub fn new(server_url: &str) -> Self {
+        Client {
+            http: reqwest::Client::new(),
+            base: server_url.trim_end_matches('/').to_string(),
+            api_key: None,
+        }
+    }
+
+    // Identifies as the owner of `key`, for the limits of its tier rather than anonymous ones.
+    pub fn with_api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self.http.request(method, format!("{}{path}", self.base));
+        match &self.api_key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        }
+    }
+
+    // Sends the request, turning error responses into `ApiError`s.
+    async fn send(&self, request: RequestBuilder) -> Result<Response, Error> {
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let request_id = header(header::HeaderName::from_static("x-request-id"));
+        let retry_after = header(header::RETRY_AFTER).and_then(|v| v.parse().ok());
+
+        let error = match response.json::<ErrorResponse>().await {
+            Ok(body) => ApiError {
+                status,
+                code: body.error.code,
+                message: body.error.message,
+                request_id: body.error.request_id,
+                retry_after,
+            },
+            // Not the API, maybe a proxy in front of it
+            Err(_) => ApiError {
+                status,
+                code: "unknown".to_string(),
+                message: status
+                    .canonical_reason()
+                    .unwrap_or("Request failed")
+                    .to_string(),
+                request_id: request_id.unwrap_or_default(),
+                retry_after,
+            },
+        };
+        Err(error.into())
+    }
+
+    // Queues a job, returning its ID.
+    pub async fn submit(&self, task: TaskType, code: impl Into<String>) -> Result<Uuid, Error> {
+        let request = ExecuteRequest {
+            task,
+            code: code.into(),
+        };
+        let response = self
+            .send(self.request(Method::POST, "/api/v1/execute").json(&request))
+            .await?;
+        Ok(response.json::<ExecuteResponse>().await?.job_id)
+    }
+
+    pub async fn job(&self, job: Uuid) -> Result<JobStatus, Error> {
+        let response = self
+            .send(self.request(Method::GET, &format!("/api/v1/jobs/{job}")))
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    // Cancels a job that hasn't started yet.
+    pub async fn cancel(&self, job: Uuid) -> Result<JobStatus, Error> {
+        let response = self
+            .send(self.request(Method::DELETE, &format!("/api/v1/jobs/{job}")))
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    // The job's result, or `None` if it hasn't finished yet. Jobs that were cancelled or
+    // expired, or whose result was discarded, fail with a `gone` error.
+    pub async fn results(&self, job: Uuid) -> Result<Option<JobResult>, Error> {
+        let response = self
+            .send(self.request(Method::GET, &format!("/api/v1/results/{job}")))
+            .await?;
+        if response.status() == StatusCode::ACCEPTED {
+            return Ok(None);
+        }
+        Ok(Some(response.json().await?))
+    }
+
+    // Follows a job's progress until it finishes, or the server stops waiting on it. Unknown
+    // jobs fail with a `not_found` error.
+    pub async fn stream(
+        &self,
+        job: Uuid,
+    ) -> Result<impl Stream<Item = Result<JobEvent, Error>> + use<>, Error> {
+        let response = self
+            .send(self.request(Method::GET, &format!("/api/v1/stream/{job}")))
+            .await?;
+
+        let events = response.bytes_stream().eventsource().map(|event| {
+            let event = event.map_err(|e| match e {
+                EventStreamError::Transport(e) => Error::Http(e),
+                e => Error::Stream(e.to_string()),
+            })?;
+            parse_event(&event.event, &event.data)
+        });
+        Ok(events)
+    }
+
+    // The version of the Zirco toolchain the server runs jobs with.
+    pub async fn version(&self) -> Result<String, Error> {
+        let response = self
+            .send(self.request(Method::GET, "/api/v1/version"))
+            .await?;
+        Ok(response.json::<VersionResponse>().await?.version)
+    }
+}
+
+fn parse_event(name: &str, data: &str) -> Result<JobEvent, Error> {
+    fn json<T: serde::de::DeserializeOwned>(data: &str) -> Result<T, Error> {
+        serde_json::from_str(data).map_err(|e| Error::Stream(e.to_string()))
+    }
+
+    Ok(match name {
+        "queued" | "compiling" | "linking" | "running" => JobEvent::State(json(data)?),
+        "pending" => JobEvent::Pending(json(data)?),
+        "output" => JobEvent::Output(json(data)?),
+        "complete" => JobEvent::Complete(json(data)?),
+        "cancelled" => JobEvent::Cancelled(json(data)?),
+        "expired" => JobEvent::Expired(json(data)?),
+        "gone" => JobEvent::Gone(json(data)?),
+        "timeout" => JobEvent::Timeout,
+        "not_found" => {
+            let error: StreamError = json(data)?;
+            return Err(ApiError {
+                status: StatusCode::NOT_FOUND,
+                code: "not_found".to_string(),
+                message: error.error,
+                request_id: String::new(),
+                retry_after: None,
+            }
+            .into());
+        }
+        name => return Err(Error::Stream(format!("unexpected event `{name}`"))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    // Answers one request with `response`, returning the server's address.
+    fn respond_once(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while request.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                line.clear();
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        format!("http://{addr}")
+    }
+
+    async fn api_error(response: &'static str) -> ApiError {
+        let client = Client::new(&respond_once(response));
+        match client.version().await {
+            Err(Error::Api(e)) => e,
+            other => panic!("expected an API error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn error_responses_are_decoded() {
+        let error = api_error(
+            "HTTP/1.1 429 Too Many Requests\r\n\
+             Retry-After: 3\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: 78\r\n\
+             Connection: close\r\n\r\n\
+             {\"error\":{\"code\":\"rate_limited\",\"message\":\"Slow down\",\
+             \"requestId\":\"req-1234\"}}",
+        )
+        .await;
+        assert_eq!(error.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(error.code, "rate_limited");
+        assert_eq!(error.message, "Slow down");
+        assert_eq!(error.request_id, "req-1234");
+        assert_eq!(error.retry_after, Some(3));
+    }
+
+    #[tokio::test]
+    async fn error_responses_from_elsewhere_are_described_by_their_status() {
+        let error = api_error(
+            "HTTP/1.1 502 Bad Gateway\r\n\
+             X-Request-Id: proxy-1\r\n\
+             Content-Type: text/html\r\n\
+             Content-Length: 12\r\n\
+             Connection: close\r\n\r\n\
+             <h1>502</h1>",
+        )
+        .await;
+        assert_eq!(error.status, StatusCode::BAD_GATEWAY);
+        assert_eq!(error.code, "unknown");
+        assert_eq!(error.message, "Bad Gateway");
+        assert_eq!(error.request_id, "proxy-1");
+        assert_eq!(error.retry_after, None);
+    }
+
+    #[test]
+    fn events_are_parsed_by_name() {
+        let status = r#"{"id":"00000000-0000-0000-0000-000000000000","task":"execute",
+                         "state":"running","worker":0,"history":[]}"#;
+        let Ok(JobEvent::State(status)) = parse_event("running", status) else {
+            panic!("expected a state event");
+        };
+        assert_eq!(status.state, JobState::Running);
+        assert_eq!(status.node, None);
+
+        let output = r#"{"stream":"stderr","offset":4,"data":"oops"}"#;
+        let Ok(JobEvent::Output(output)) = parse_event("output", output) else {
+            panic!("expected an output event");
+        };
+        assert_eq!(output.stream, OutputStream::Stderr);
+        assert_eq!((output.offset, output.data.as_str()), (4, "oops"));
+
+        let result = r#"{"stdout":"hi","stderr":"","exit_code":3}"#;
+        let Ok(JobEvent::Complete(result)) = parse_event("complete", result) else {
+            panic!("expected a complete event");
+        };
+        assert_eq!((result.stdout.as_str(), result.exit_code), ("hi", 3));
+
+        let timeout = r#"{"error":"Timed out waiting for the job"}"#;
+        assert!(matches!(
+            parse_event("timeout", timeout),
+            Ok(JobEvent::Timeout)
+        ));
+    }
+
+    #[test]
+    fn unknown_jobs_and_bad_events_are_errors() {
+        match parse_event("not_found", r#"{"error":"Job not found"}"#) {
+            Err(Error::Api(e)) => {
+                assert_eq!(e.status, StatusCode::NOT_FOUND);
+                assert_eq!(
+                    (e.code.as_str(), e.message.as_str()),
+                    ("not_found", "Job not found")
+                );
+            }
+            other => panic!("expected an API error, got {other:?}"),
+        }
+        assert!(matches!(
+            parse_event("exploded", "{}"),
+            Err(Error::Stream(e)) if e.contains("exploded")
+        ));
+        assert!(matches!(
+            parse_event("output", r#"{"stream":"stdin"}"#),
+            Err(Error::Stream(_))
+        ));
+    }
+}
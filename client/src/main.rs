@@ -0,0 +1,331 @@
+use std::{
+    io::{IsTerminal, Read, Write},
+    path::PathBuf,
+    process::exit,
+};
+
+use clap::{Parser, Subcommand};
+use futures::StreamExt;
+use uuid::Uuid;
+use zirco_playground_client::{
+    Client, Error, JobEvent, JobResult, JobState, OutputEvent, OutputStream, TaskType,
+};
+
+#[derive(Parser)]
+#[command(
+    name = "zplay",
+    version,
+    about = "Runs Zirco code on a playground server"
+)]
+struct Cli {
+    #[arg(
+        long,
+        global = true,
+        env = "ZPLAY_SERVER",
+        default_value = "http://localhost:3000",
+        help = "The playground server to use"
+    )]
+    server: String,
+    #[arg(
+        long,
+        global = true,
+        env = "ZPLAY_API_KEY",
+        hide_env_values = true,
+        help = "API key to identify with, for higher limits"
+    )]
+    api_key: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(
+        about = "Runs a file, printing its output and exiting with its exit code",
+        long_about = "Runs a file, printing its output and exiting with its exit code. \
+                      Output is printed as the program runs, except for jobs run by remote \
+                      workers, whose output is printed once they finish."
+    )]
+    Run {
+        #[arg(help = "The file to run, or - for standard input")]
+        file: PathBuf,
+        #[arg(long, default_value = "execute", help = "execute, lint, tast or llvm")]
+        task: TaskType,
+        #[arg(short, long, help = "Don't show the job's progress")]
+        quiet: bool,
+    },
+    #[command(about = "Prints the version of the server's Zirco toolchain")]
+    Version,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let mut client = Client::new(&cli.server);
+    if let Some(key) = cli.api_key {
+        client = client.with_api_key(key);
+    }
+
+    let code = match cli.command {
+        Command::Run { file, task, quiet } => {
+            let code = match read_code(&file) {
+                Ok(code) => code,
+                Err(e) => fail(format_args!("can't read {}: {e}", file.display())),
+            };
+            // Progress goes to stderr, so only show it to people
+            let progress = Progress(!quiet && std::io::stderr().is_terminal());
+            let mut printed = Printed::default();
+            match run(&client, task, code, &progress, &mut printed).await {
+                Ok(result) => {
+                    progress.clear();
+                    printed.finish(&result);
+                    result.exit_code
+                }
+                Err(e) => {
+                    progress.clear();
+                    fail(e)
+                }
+            }
+        }
+        Command::Version => match client.version().await {
+            Ok(version) => {
+                println!("{version}");
+                0
+            }
+            Err(e) => fail(e),
+        },
+    };
+    exit(code);
+}
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("zplay: {message}");
+    exit(1);
+}
+
+fn read_code(file: &PathBuf) -> std::io::Result<String> {
+    if file.as_os_str() == "-" {
+        let mut code = String::new();
+        std::io::stdin().read_to_string(&mut code)?;
+        Ok(code)
+    } else {
+        std::fs::read_to_string(file)
+    }
+}
+
+// A status line on stderr, overwritten as the job moves along.
+struct Progress(bool);
+
+impl Progress {
+    fn show(&self, status: &str) {
+        if self.0 {
+            eprint!("\r\x1b[K{status}");
+        }
+    }
+
+    fn clear(&self) {
+        if self.0 {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+// How much of the job's stdout and stderr was printed as it ran, so the rest can be printed
+// from the result.
+#[derive(Default)]
+struct Printed {
+    stdout: usize,
+    stderr: usize,
+}
+
+impl Printed {
+    fn print(&mut self, output: &OutputEvent) {
+        let Some(new) = self.unseen(output) else {
+            return;
+        };
+        match output.stream {
+            OutputStream::Stdout => {
+                print!("{new}");
+                let _ = std::io::stdout().flush();
+            }
+            OutputStream::Stderr => eprint!("{new}"),
+        }
+    }
+
+    // The part of the output that wasn't printed yet, counting it as printed.
+    fn unseen<'a>(&mut self, output: &'a OutputEvent) -> Option<&'a str> {
+        let printed = match output.stream {
+            OutputStream::Stdout => &mut self.stdout,
+            OutputStream::Stderr => &mut self.stderr,
+        };
+        // Streaming the job again sends what was already printed
+        let new = output.data.get(printed.saturating_sub(output.offset)..)?;
+        if output.offset > *printed || new.is_empty() {
+            return None;
+        }
+        *printed = output.offset + output.data.len();
+        Some(new)
+    }
+
+    fn finish(&self, result: &JobResult) {
+        let (stdout, stderr) = self.rest(result);
+        print!("{stdout}");
+        eprint!("{stderr}");
+        let _ = std::io::stdout().flush();
+    }
+
+    // What of the result's stdout and stderr wasn't printed yet.
+    fn rest<'a>(&self, result: &'a JobResult) -> (&'a str, &'a str) {
+        (
+            result.stdout.get(self.stdout..).unwrap_or_default(),
+            result.stderr.get(self.stderr..).unwrap_or_default(),
+        )
+    }
+}
+
+// Why a run ended without a result.
+enum RunError {
+    Client(Error),
+    Ended(JobState),
+    Gone,
+    Interrupted(Uuid),
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunError::Client(e) => write!(f, "{e}"),
+            RunError::Ended(JobState::Cancelled) => write!(f, "the job was cancelled"),
+            RunError::Ended(state) => {
+                write!(f, "the job ended without a result ({})", state.as_str())
+            }
+            RunError::Gone => write!(f, "the job's result is no longer available"),
+            RunError::Interrupted(job) => write!(f, "interrupted, job {job} may still be running"),
+        }
+    }
+}
+
+impl From<Error> for RunError {
+    fn from(e: Error) -> Self {
+        RunError::Client(e)
+    }
+}
+
+async fn run(
+    client: &Client,
+    task: TaskType,
+    code: String,
+    progress: &Progress,
+    printed: &mut Printed,
+) -> Result<JobResult, RunError> {
+    progress.show("submitting");
+    let job = client.submit(task, code).await?;
+
+    tokio::select! {
+        result = follow(client, job, progress, printed) => result,
+        _ = tokio::signal::ctrl_c() => {
+            // Only works if the job hasn't started yet, otherwise it finishes on its own
+            match client.cancel(job).await {
+                Ok(_) => Err(RunError::Ended(JobState::Cancelled)),
+                Err(_) => Err(RunError::Interrupted(job)),
+            }
+        }
+    }
+}
+
+async fn follow(
+    client: &Client,
+    job: Uuid,
+    progress: &Progress,
+    printed: &mut Printed,
+) -> Result<JobResult, RunError> {
+    loop {
+        let mut events = client.stream(job).await?;
+        while let Some(event) = events.next().await {
+            match event? {
+                JobEvent::State(status) => progress.show(status.state.as_str()),
+                JobEvent::Pending(pending) => match pending.position {
+                    Some(0) => progress.show("queued, next up"),
+                    Some(position) => progress.show(&format!("queued, {position} ahead")),
+                    None => progress.show("queued"),
+                },
+                JobEvent::Output(output) => {
+                    progress.clear();
+                    printed.print(&output);
+                }
+                JobEvent::Complete(result) => return Ok(result),
+                JobEvent::Cancelled(status) | JobEvent::Expired(status) => {
+                    return Err(RunError::Ended(status.state));
+                }
+                JobEvent::Gone(_) => return Err(RunError::Gone),
+                // The job is still going, so follow it again
+                JobEvent::Timeout => break,
+            }
+        }
+
+        // The stream ended before the job did, see if it finished in the meantime
+        if let Some(result) = client.results(job).await? {
+            return Ok(result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(stream: OutputStream, offset: usize, data: &str) -> OutputEvent {
+        OutputEvent {
+            stream,
+            offset,
+            data: data.to_string(),
+        }
+    }
+
+    #[test]
+    fn output_is_printed_once_across_streams() {
+        let mut printed = Printed::default();
+        let stdout = |offset, data| output(OutputStream::Stdout, offset, data);
+        assert_eq!(printed.unseen(&stdout(0, "héllo")), Some("héllo"));
+        assert_eq!(printed.unseen(&stdout(6, " wor")), Some(" wor"));
+
+        // Streaming the job again starts over, with more output by now
+        assert_eq!(printed.unseen(&stdout(0, "héllo")), None);
+        assert_eq!(printed.unseen(&stdout(0, "héllo world")), Some("ld"));
+        assert_eq!(printed.unseen(&stdout(6, " world\n")), Some("\n"));
+        assert_eq!(printed.unseen(&stdout(13, "")), None);
+
+        // Output past a gap would be printed out of order
+        assert_eq!(printed.unseen(&stdout(20, "?")), None);
+
+        let stderr = output(OutputStream::Stderr, 0, "oops");
+        assert_eq!(printed.unseen(&stderr), Some("oops"));
+        assert_eq!((printed.stdout, printed.stderr), (13, 4));
+    }
+
+    #[test]
+    fn invalid_output_is_counted_as_sent() {
+        let mut printed = Printed::default();
+        let stdout = |offset, data| output(OutputStream::Stdout, offset, data);
+        assert_eq!(printed.unseen(&stdout(0, "a\u{fffd}")), Some("a\u{fffd}"));
+        assert_eq!(printed.unseen(&stdout(0, "a\u{fffd}b")), Some("b"));
+
+        let result = JobResult {
+            stdout: "a\u{fffd}bc".to_string(),
+            stderr: "error".to_string(),
+            exit_code: 1,
+        };
+        assert_eq!(printed.rest(&result), ("c", "error"));
+    }
+
+    #[test]
+    fn results_cut_short_print_nothing_more() {
+        let mut printed = Printed::default();
+        printed.unseen(&output(OutputStream::Stdout, 0, "a long line"));
+        let result = JobResult {
+            stdout: "a lo".to_string(),
+            ..JobResult::default()
+        };
+        assert_eq!(printed.rest(&result), ("", ""));
+    }
+}
@@ -0,0 +1,191 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// The types of the playground's public API, as the server sends and expects them.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskType {
+    Execute,
+    Lint,
+    Tast,
+    Llvm,
+}
+
+impl TaskType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TaskType::Execute => "execute",
+            TaskType::Lint => "lint",
+            TaskType::Tast => "tast",
+            TaskType::Llvm => "llvm",
+        }
+    }
+}
+
+impl FromStr for TaskType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "execute" => Ok(TaskType::Execute),
+            "lint" => Ok(TaskType::Lint),
+            "tast" => Ok(TaskType::Tast),
+            "llvm" => Ok(TaskType::Llvm),
+            _ => Err(format!("unknown task type: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JobResult {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Compiling,
+    Linking,
+    Running,
+    // The job produced a result (which may still be a compile error or non-zero exit)
+    Completed,
+    // The server could not produce a result for the job
+    Failed,
+    Cancelled,
+    // The job waited in the queue past its deadline
+    Expired,
+}
+
+impl JobState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Compiling => "compiling",
+            JobState::Linking => "linking",
+            JobState::Running => "running",
+            JobState::Completed => "completed",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+            JobState::Expired => "expired",
+        }
+    }
+
+    pub fn is_terminal(self) -> bool {
+        matches!(
+            self,
+            JobState::Completed | JobState::Failed | JobState::Cancelled | JobState::Expired
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateTransition {
+    pub state: JobState,
+    // Unix timestamp in milliseconds
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub id: Uuid,
+    pub task: TaskType,
+    pub state: JobState,
+    pub worker: Option<usize>,
+    // The remote worker node running the job, if it isn't running on the server itself
+    #[serde(default)]
+    pub node: Option<String>,
+    pub history: Vec<StateTransition>,
+}
+
+// The job's place in the queue, which is left out if it is just leaving the queue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingEvent {
+    pub state: JobState,
+    // Number of queued jobs ahead of this one
+    #[serde(default)]
+    pub position: Option<usize>,
+    // Unix timestamp in milliseconds
+    #[serde(default)]
+    pub estimated_start: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+// Some of what a running job printed. Streaming a job again repeats its output from the start.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputEvent {
+    pub stream: OutputStream,
+    // Where `data` starts in the stream, in bytes of the text the result has for it, so invalid
+    // UTF-8 counts as the replacement character it was sent as
+    pub offset: usize,
+    pub data: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecuteRequest {
+    pub task: TaskType,
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecuteResponse {
+    #[serde(rename = "jobId")]
+    pub job_id: Uuid,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionResponse {
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamError {
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: ErrorDetails,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorDetails {
+    // One of the server's error codes, like `not_found` or `rate_limited`
+    pub code: String,
+    pub message: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+}
+
+// What happened to a job, as streamed by `Client::stream`.
+#[derive(Debug, Clone)]
+pub enum JobEvent {
+    // The job entered a new state on its way to finishing
+    State(JobStatus),
+    // The job moved up in the queue
+    Pending(PendingEvent),
+    // The job printed something while running. Jobs run by remote workers only have their
+    // output in the result.
+    Output(OutputEvent),
+    // The job finished, this is the last event
+    Complete(JobResult),
+    // The job ended without a result, this is the last event
+    Cancelled(JobStatus),
+    Expired(JobStatus),
+    // The job finished a while ago and its result was discarded, this is the last event
+    Gone(JobStatus),
+    // The server stopped waiting on the job, which is still going. Stream it again to keep
+    // following it.
+    Timeout,
+}
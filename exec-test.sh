@@ -14,23 +14,8 @@ if [ -z "$CODE" ]; then
     CODE='fn main() -> i32 { return 0; }'
 fi
 
-jobId=$(curl -s -X POST "$SERVER/api/v1/execute" \
+# /api/v1/run holds the response until the job finishes (or the timeout runs out)
+curl -s -X POST "$SERVER/api/v1/run" \
     -H "Content-Type: application/json" \
-    -d "$(jq -n --arg task execute --arg code "$CODE" '{task: $task, code: $code}')" | jq -r '.jobId')
-
-echo "Job ID: $jobId"
-
-set +e
-for i in {1..30}; do
-    echo "Checking for result (attempt $i)..."
-
-    # Wait until you get anything besides a http error
-    curl "$SERVER/api/v1/results/$jobId" -s -o /dev/null -w "%{http_code}" | grep -q "200"
-    if [ $? -eq 0 ]; then
-        result=$(curl -s "$SERVER/api/v1/results/$jobId")
-        echo "Result: $result"
-        exit 0
-    fi
-
-    sleep 1
-done
+    -d "$(jq -n --arg task execute --arg code "$CODE" '{task: $task, code: $code, timeout: 30}')" \
+    -w "\nHTTP %{http_code}\n"
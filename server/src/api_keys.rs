@@ -0,0 +1,222 @@
+use std::{collections::HashMap, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::models::TaskType;
+
+// The quotas and limits that apply to a client. Anonymous clients get the `anonymous` tier
+// from the configuration, which can be overridden in the API keys file like any other tier.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tier {
+    // Rate limit tokens refilled per second, and the bucket size. Submitting a job costs
+    // tokens depending on its task type, and the CPU time it used is charged once it finishes.
+    pub requests_per_second: f64,
+    pub burst: u32,
+    pub task_costs: TaskCosts,
+    pub cpu_second_cost: f64,
+    // How many of the client's jobs may run at the same time
+    pub max_running: usize,
+    // Clients in higher priority tiers are served first when the queue is contended
+    pub priority: i32,
+    // Limits applied to each job
+    pub cpu_seconds: u64,
+    pub memory_bytes: u64,
+    pub max_output_bytes: usize,
+}
+
+impl Default for Tier {
+    fn default() -> Self {
+        Tier {
+            requests_per_second: 10.0,
+            burst: 10,
+            task_costs: TaskCosts::default(),
+            cpu_second_cost: 1.0,
+            max_running: 2,
+            priority: 0,
+            cpu_seconds: 30,
+            memory_bytes: 512 * 1024 * 1024,
+            max_output_bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl Tier {
+    pub fn validate(&self) -> Result<(), String> {
+        let costs = &self.task_costs;
+        let checks = [
+            (
+                self.requests_per_second > 0.0,
+                "requests_per_second must be positive",
+            ),
+            (self.burst >= 1, "burst must be at least 1"),
+            (self.max_running >= 1, "max_running must be at least 1"),
+            (self.cpu_seconds >= 1, "cpu_seconds must be at least 1"),
+            (
+                self.memory_bytes >= 1024 * 1024,
+                "memory_bytes must be at least 1 MiB",
+            ),
+            (
+                self.cpu_second_cost >= 0.0
+                    && [costs.execute, costs.lint, costs.tast, costs.llvm]
+                        .iter()
+                        .all(|cost| *cost >= 0.0),
+                "costs must not be negative",
+            ),
+            // The bucket never holds more than `burst` tokens, so a dearer task could never
+            // be admitted
+            (
+                [costs.execute, costs.lint, costs.tast, costs.llvm]
+                    .iter()
+                    .all(|cost| *cost <= f64::from(self.burst)),
+                "task costs must not exceed burst",
+            ),
+        ];
+        match checks.iter().find(|(ok, _)| !ok) {
+            Some((_, message)) => Err(message.to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TaskCosts {
+    pub execute: f64,
+    pub lint: f64,
+    pub tast: f64,
+    pub llvm: f64,
+}
+
+impl Default for TaskCosts {
+    fn default() -> Self {
+        TaskCosts {
+            execute: 1.0,
+            lint: 0.5,
+            tast: 0.5,
+            llvm: 0.5,
+        }
+    }
+}
+
+impl TaskCosts {
+    pub fn cost(&self, task: TaskType) -> f64 {
+        match task {
+            TaskType::Execute => self.execute,
+            TaskType::Lint => self.lint,
+            TaskType::Tast => self.tast,
+            TaskType::Llvm => self.llvm,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyEntry {
+    key: String,
+    // Identifies the key's owner in logs and scheduling, so the key itself is never logged
+    name: String,
+    tier: String,
+    // Admin keys can also use the operator endpoints under /api/v1/admin
+    #[serde(default)]
+    admin: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ApiKeysFile {
+    #[serde(default)]
+    tiers: HashMap<String, Tier>,
+    #[serde(default)]
+    keys: Vec<KeyEntry>,
+}
+
+// An API key holder, as resolved from a request's key.
+#[derive(Debug, Clone)]
+pub struct KeyHolder {
+    pub name: String,
+    pub tier: Arc<Tier>,
+    pub admin: bool,
+}
+
+#[derive(Debug)]
+pub struct ApiKeys {
+    pub anonymous: Arc<Tier>,
+    keys: HashMap<String, KeyHolder>,
+}
+
+impl ApiKeys {
+    // Loads API keys and tiers from a TOML file. A missing file means there are no keys and
+    // everyone is anonymous, with the configured anonymous tier.
+    pub fn load(path: &str, anonymous: &Tier) -> Result<Self, String> {
+        let file: ApiKeysFile = match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                toml::from_str(&contents).map_err(|e| format!("Invalid API keys file: {e}"))?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("No API keys file at {path}, all clients are anonymous");
+                ApiKeysFile::default()
+            }
+            Err(e) => return Err(format!("Failed to read API keys file: {e}")),
+        };
+
+        let mut tiers = HashMap::new();
+        for (name, tier) in file.tiers {
+            tier.validate()
+                .map_err(|e| format!("Invalid tier {name} in API keys file: {e}"))?;
+            tiers.insert(name, Arc::new(tier));
+        }
+
+        let mut keys = HashMap::new();
+        for entry in file.keys {
+            let tier = tiers
+                .get(&entry.tier)
+                .ok_or_else(|| format!("API key {} uses unknown tier {}", entry.name, entry.tier))?
+                .clone();
+            let holder = KeyHolder {
+                name: entry.name,
+                tier,
+                admin: entry.admin,
+            };
+            if keys.insert(entry.key, holder).is_some() {
+                return Err("Duplicate API key in API keys file".to_string());
+            }
+        }
+
+        info!("Loaded {} API keys in {} tiers", keys.len(), tiers.len());
+
+        Ok(ApiKeys {
+            anonymous: tiers
+                .get("anonymous")
+                .cloned()
+                .unwrap_or_else(|| Arc::new(anonymous.clone())),
+            keys,
+        })
+    }
+
+    pub fn resolve(&self, key: &str) -> Option<&KeyHolder> {
+        self.keys.get(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tasks_must_fit_in_the_bucket() {
+        let mut tier = Tier {
+            burst: 2,
+            ..Tier::default()
+        };
+        tier.task_costs.execute = 2.0;
+        assert!(tier.validate().is_ok());
+
+        tier.task_costs.execute = 2.5;
+        assert_eq!(
+            tier.validate().unwrap_err(),
+            "task costs must not exceed burst"
+        );
+    }
+}
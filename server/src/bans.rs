@@ -0,0 +1,88 @@
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{Connection, params};
+
+use crate::models::Ban;
+
+// Clients operators have banned. Bans are stored in the database and cached here, since every
+// request checks them.
+#[derive(Debug, Default)]
+pub struct Bans(RwLock<HashMap<String, Ban>>);
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+impl Bans {
+    pub fn load(conn: &mut Connection) -> rusqlite::Result<Self> {
+        let mut stmt = conn.prepare("SELECT client, reason, created_at FROM bans")?;
+        let bans = stmt
+            .query_map([], |row| {
+                Ok(Ban {
+                    client: row.get(0)?,
+                    reason: row.get(1)?,
+                    created_at: row.get(2)?,
+                })
+            })?
+            .map(|ban| ban.map(|ban| (ban.client.clone(), ban)))
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Bans(RwLock::new(bans)))
+    }
+
+    pub fn is_banned(&self, client: &str) -> bool {
+        self.0
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains_key(client)
+    }
+
+    pub fn list(&self) -> Vec<Ban> {
+        let mut bans: Vec<Ban> = self
+            .0
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .cloned()
+            .collect();
+        bans.sort_by_key(|ban| ban.created_at);
+        bans
+    }
+
+    // Caches a ban once it has been stored.
+    pub fn add(&self, ban: Ban) {
+        self.0
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(ban.client.clone(), ban);
+    }
+
+    pub fn remove(&self, client: &str) -> bool {
+        self.0
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(client)
+            .is_some()
+    }
+}
+
+// Stores a ban, replacing any earlier ban of the same client.
+pub fn insert(conn: &Connection, mut ban: Ban) -> rusqlite::Result<Ban> {
+    ban.created_at = now();
+    conn.execute(
+        "INSERT OR REPLACE INTO bans (client, reason, created_at) VALUES (?1, ?2, ?3)",
+        params![ban.client, ban.reason, ban.created_at],
+    )?;
+    Ok(ban)
+}
+
+pub fn delete(conn: &Connection, client: &str) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM bans WHERE client = ?1", params![client])?;
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use futures::{StreamExt, stream::FuturesUnordered};
+use tokio::sync::{Mutex, watch};
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::{
+    jobs,
+    models::{BatchEntry, BatchItem, BatchProgress, BatchStatus, Batches, JobState, Jobs},
+};
+
+// How long a batch is kept around after its last job finishes. This matches how long the
+// jobs themselves are kept.
+const BATCH_TTL: Duration = Duration::from_mins(10);
+
+pub fn new() -> Batches {
+    Batches {
+        entries: Arc::new(Mutex::new(HashMap::new())),
+    }
+}
+
+// Registers a batch of jobs, which must already have been inserted into the job map, and
+// starts tracking their progress.
+pub async fn insert(batches: &Batches, jobs: &Jobs, id: Uuid, job_ids: Vec<Uuid>) {
+    let mut watchers = FuturesUnordered::new();
+    for (index, job_id) in job_ids.iter().enumerate() {
+        let status = jobs::subscribe(jobs, *job_id).await;
+        watchers.push(async move {
+            // A job that is gone before we got to watch it can only have expired
+            let state = match status {
+                Some(mut status) => status
+                    .wait_for(|status| status.state.is_terminal())
+                    .await
+                    .map(|status| status.state)
+                    .unwrap_or(JobState::Expired),
+                None => JobState::Expired,
+            };
+            (index, state)
+        });
+    }
+
+    let progress = watch::Sender::new(BatchProgress {
+        total: job_ids.len(),
+        ..Default::default()
+    });
+
+    batches.entries.lock().await.insert(
+        id,
+        BatchEntry {
+            jobs: job_ids,
+            progress: progress.clone(),
+        },
+    );
+
+    let batches = batches.clone();
+    tokio::spawn(async move {
+        while let Some((index, state)) = watchers.next().await {
+            progress.send_modify(|progress| {
+                match state {
+                    JobState::Completed => progress.completed += 1,
+                    JobState::Failed => progress.failed += 1,
+                    JobState::Cancelled => progress.cancelled += 1,
+                    _ => progress.expired += 1,
+                }
+                progress.finished.push(index);
+            });
+        }
+        debug!("Batch {id} is done");
+
+        tokio::time::sleep(BATCH_TTL).await;
+        debug!("Removing batch {id}");
+        batches.entries.lock().await.remove(&id);
+    });
+}
+
+// The job IDs of a batch, and a receiver for its progress.
+pub async fn subscribe(
+    batches: &Batches,
+    id: Uuid,
+) -> Option<(Vec<Uuid>, watch::Receiver<BatchProgress>)> {
+    batches
+        .entries
+        .lock()
+        .await
+        .get(&id)
+        .map(|entry| (entry.jobs.clone(), entry.progress.subscribe()))
+}
+
+pub async fn item(jobs: &Jobs, index: usize, id: Uuid) -> BatchItem {
+    BatchItem {
+        index,
+        id,
+        state: jobs::status(jobs, id)
+            .await
+            .map_or(JobState::Expired, |status| status.state),
+        result: jobs::result(jobs, id).await,
+    }
+}
+
+pub async fn status(batches: &Batches, jobs: &Jobs, id: Uuid) -> Option<BatchStatus> {
+    let (job_ids, progress) = subscribe(batches, id).await?;
+
+    let mut items = Vec::with_capacity(job_ids.len());
+    for (index, job_id) in job_ids.into_iter().enumerate() {
+        items.push(item(jobs, index, job_id).await);
+    }
+
+    let progress = progress.borrow().clone();
+    Some(BatchStatus {
+        id,
+        progress,
+        items,
+    })
+}
@@ -0,0 +1,188 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{HeaderMap, header, request::Parts},
+};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    api_keys::Tier,
+    config::ServerConfig,
+    error::{ApiError, ErrorCode},
+    models::AppState,
+};
+
+// Identifies the client behind a request, for scheduling and rate limiting purposes, along
+// with the tier of limits that applies to it.
+//
+// Clients presenting an API key (as `Authorization: Bearer <key>` or `X-Api-Key`) are
+// identified by the key's name. Anonymous clients are identified by IP: the peer address, or
+// the address in the proxy headers if the peer is one of `server.trusted_proxies`.
+//
+// Clients that operators banned are turned away with 403.
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub key: String,
+    pub tier: Arc<Tier>,
+}
+
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    bearer.or_else(|| headers.get("x-api-key").and_then(|v| v.to_str().ok()))
+}
+
+// The client's address as passed on by our proxies. Every proxy appends the address it got
+// the request from to `X-Forwarded-For`, so the last one that isn't one of ours is the
+// client, and anything before it may be made up.
+fn forwarded_ip(headers: &HeaderMap, server: &ServerConfig) -> Option<IpAddr> {
+    let hops: Vec<IpAddr> = headers
+        .get("x-forwarded-for")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            v.split(',')
+                .filter_map(|ip| ip.trim().parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    let forwarded_for = hops
+        .iter()
+        .rev()
+        .find(|ip| !server.is_trusted_proxy(**ip))
+        .or(hops.first())
+        .copied();
+
+    forwarded_for.or_else(|| {
+        headers
+            .get("x-real-ip")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|ip| ip.trim().parse().ok())
+    })
+}
+
+fn invalid_key() -> ApiError {
+    ApiError::new(ErrorCode::Unauthorized, "Invalid API key.")
+}
+
+fn banned() -> ApiError {
+    ApiError::new(ErrorCode::Banned, "This client has been banned.")
+}
+
+impl FromRequestParts<AppState> for Client {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(key) = api_key(&parts.headers) {
+            let api_keys = state.api_keys.get();
+            let holder = api_keys.resolve(key.trim()).ok_or_else(invalid_key)?;
+
+            let key = format!("key:{}", holder.name);
+            if state.bans.is_banned(&key) {
+                return Err(banned());
+            }
+            return Ok(Client {
+                key,
+                tier: holder.tier.clone(),
+            });
+        }
+
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_canonical());
+        let config = state.config.get();
+        let ip = match peer {
+            Some(peer) if config.server.is_trusted_proxy(peer) => {
+                forwarded_ip(&parts.headers, &config.server).or(Some(peer))
+            }
+            peer => peer,
+        };
+
+        let key = ip.ok_or_else(ApiError::internal)?.to_string();
+        if state.bans.is_banned(&key) {
+            return Err(banned());
+        }
+        Ok(Client {
+            key,
+            tier: state.api_keys.get().anonymous.clone(),
+        })
+    }
+}
+
+// An operator, holding an API key marked as an admin key.
+#[derive(Debug, Clone)]
+pub struct Admin {
+    pub name: String,
+}
+
+impl FromRequestParts<AppState> for Admin {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let key = api_key(&parts.headers).ok_or_else(|| {
+            ApiError::new(ErrorCode::Unauthorized, "An admin API key is required.")
+        })?;
+        let api_keys = state.api_keys.get();
+        let holder = api_keys.resolve(key.trim()).ok_or_else(invalid_key)?;
+
+        if !holder.admin {
+            return Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "This API key is not an admin key.",
+            ));
+        }
+        Ok(Admin {
+            name: holder.name.clone(),
+        })
+    }
+}
+
+// A remote worker, presenting the shared worker token as `Authorization: Bearer <token>`.
+// The worker API doesn't exist while no token is configured.
+#[derive(Debug, Clone)]
+pub struct WorkerToken;
+
+impl FromRequestParts<AppState> for WorkerToken {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let config = state.config.get();
+        if !config.remote.enabled() {
+            return Err(ApiError::not_found(
+                "This server doesn't accept remote workers.",
+            ));
+        }
+        // Compared in constant time, so response times don't give the token away
+        let valid = api_key(&parts.headers).is_some_and(|token| {
+            bool::from(
+                token
+                    .trim()
+                    .as_bytes()
+                    .ct_eq(config.remote.token.as_bytes()),
+            )
+        });
+        if !valid {
+            return Err(ApiError::new(
+                ErrorCode::Unauthorized,
+                "Invalid worker token.",
+            ));
+        }
+        Ok(WorkerToken)
+    }
+}
@@ -1,47 +1,335 @@
-use std::time::Duration;
+use std::{
+    any::Any,
+    collections::{HashMap, hash_map::Entry},
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use crate::sandbox;
+use futures::FutureExt;
+use tokio::{
+    sync::{Notify, watch},
+    task::AbortHandle,
+};
+
+use crate::{
+    config::{Config, Reloadable},
+    jobs,
+    metrics::Metrics,
+    rate_limit::RateLimiter,
+    sandbox,
+    scheduler::Scheduler,
+};
 use tracing::{debug, error, info};
 
-use crate::models::{Job, JobResult, Results};
+use crate::models::{Job, JobResult, JobState, Jobs};
 
-pub async fn worker(i: usize, rx: async_channel::Receiver<Job>, results: Results) {
-    info!("Worker {i} started");
+// How long a worker that panicked outside of a job waits before starting over
+const RESTART_DELAY: Duration = Duration::from_secs(1);
 
-    loop {
-        let job = match rx.recv().await {
-            Ok(job) => job,
-            Err(e) => {
-                error!("Worker {i} shutting down: {e}");
+// The compilation workers. Their number can change while the server runs: new workers start
+// right away, and retired ones stop once they have finished their current job.
+pub struct Workers {
+    pool: Mutex<Pool>,
+    count: watch::Sender<usize>,
+    // Notified whenever a worker stops
+    stopped: Notify,
+    scheduler: Arc<Scheduler>,
+    jobs: Jobs,
+    metrics: Arc<Metrics>,
+    rate_limiter: Arc<RateLimiter>,
+    config: Arc<Reloadable<Config>>,
+}
+
+#[derive(Default)]
+struct Pool {
+    // The worker tasks that are still running, by index
+    tasks: HashMap<usize, AbortHandle>,
+    // Set when the server shuts down, after which no workers are started
+    stopping: bool,
+}
+
+impl Workers {
+    pub fn new(
+        scheduler: Arc<Scheduler>,
+        jobs: Jobs,
+        metrics: Arc<Metrics>,
+        rate_limiter: Arc<RateLimiter>,
+        config: Arc<Reloadable<Config>>,
+    ) -> Arc<Self> {
+        Arc::new(Workers {
+            pool: Mutex::new(Pool::default()),
+            count: watch::Sender::new(0),
+            stopped: Notify::new(),
+            scheduler,
+            jobs,
+            metrics,
+            rate_limiter,
+            config,
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Pool> {
+        self.pool.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Number of workers the pool is meant to have, not counting retired ones that are still
+    // finishing a job.
+    pub fn count(&self) -> usize {
+        *self.count.borrow()
+    }
+
+    // Starts or retires workers until there are `count` of them.
+    pub fn scale(self: &Arc<Self>, count: usize) {
+        let mut pool = self.lock();
+        if pool.stopping {
+            return;
+        }
+        let previous = self.count.send_replace(count);
+        if previous != count {
+            info!("Scaling from {previous} to {count} workers");
+        }
+        self.metrics.workers.set(count as i64);
+
+        // A worker that was retired but hasn't stopped yet just carries on
+        for i in 0..count {
+            if let Entry::Vacant(entry) = pool.tasks.entry(i) {
+                entry.insert(tokio::spawn(supervise(self.clone(), i)).abort_handle());
+            }
+        }
+    }
+
+    // Retires every worker for good. They stop once they are done with their current job.
+    pub fn stop(&self) {
+        let mut pool = self.lock();
+        pool.stopping = true;
+        self.count.send_replace(0);
+        self.metrics.workers.set(0);
+    }
+
+    // Waits until every worker has stopped.
+    pub async fn stopped(&self) {
+        loop {
+            // Register for wakeups before checking, so we can't miss a worker stopping
+            let stopped = self.stopped.notified();
+            tokio::pin!(stopped);
+            stopped.as_mut().enable();
+
+            if self.lock().tasks.is_empty() {
                 return;
             }
+            stopped.await;
+        }
+    }
+
+    // Stops every worker right away, killing the processes of the jobs they were running.
+    // Returns the number of workers that were still running.
+    pub fn abort(&self) -> usize {
+        let mut pool = self.lock();
+        pool.stopping = true;
+        self.count.send_replace(0);
+        let aborted = pool.tasks.len();
+        for (_, task) in pool.tasks.drain() {
+            task.abort();
+        }
+        aborted
+    }
+
+    // Whether worker `i` has been retired, in which case it is forgotten and must stop.
+    fn retire(&self, i: usize) -> bool {
+        let mut pool = self.lock();
+        if i < *self.count.borrow() {
+            return false;
+        }
+        pool.tasks.remove(&i);
+        self.stopped.notify_waiters();
+        true
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+// Host load average over the last minute, per CPU
+fn load_per_cpu() -> Option<f64> {
+    let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+    let load: f64 = loadavg.split_whitespace().next()?.parse().ok()?;
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    Some(load / cpus as f64)
+}
+
+// Periodically resizes the pool to fit the queue, within the configured bounds.
+pub async fn autoscale(workers: Arc<Workers>) {
+    loop {
+        tokio::time::sleep(workers.config.get().workers.scale_interval()).await;
+
+        let config = workers.config.get();
+        let current = workers.count();
+        let busy = workers.metrics.busy_workers.get().max(0) as usize;
+        let wanted = busy + workers.scheduler.runnable_len();
+        let overloaded = load_per_cpu().is_some_and(|load| load > config.workers.max_load);
+
+        // Grow straight to what the queue needs, unless the host is already busy enough, but
+        // shrink one worker at a time so a short lull doesn't empty the pool
+        let target = if wanted > current && !overloaded {
+            wanted
+        } else if wanted < current {
+            current - 1
+        } else {
+            current
+        };
+        let target = target.clamp(config.workers.min, config.workers.max);
+
+        if target != current {
+            workers.scale(target);
+        } else if wanted > current && overloaded {
+            debug!("Not adding workers for {wanted} jobs, the host is overloaded");
+        }
+    }
+}
+
+// Runs worker `i`, starting it over if it panics.
+async fn supervise(workers: Arc<Workers>, i: usize) {
+    while let Err(panic) = AssertUnwindSafe(worker(&workers, i)).catch_unwind().await {
+        error!("Worker {i} panicked: {}", panic_message(&*panic));
+        workers.metrics.worker_panics.inc();
+        tokio::time::sleep(RESTART_DELAY).await;
+    }
+}
+
+async fn worker(workers: &Workers, i: usize) {
+    info!("Worker {i} started");
+    let jobs = &workers.jobs;
+    let metrics = &workers.metrics;
+    let mut count = workers.count.subscribe();
+
+    loop {
+        if workers.retire(i) {
+            info!("Worker {i} stopped");
+            return;
+        }
+
+        // Holding the slot counts the job against its client's running limit
+        let (job, _slot) = tokio::select! {
+            next = workers.scheduler.pop() => next,
+            _ = count.changed() => continue,
         };
 
         debug!("Worker {i} received job: {job:?}");
 
         let id = job.id;
-        let result = match sandbox::sandboxed_execution(job).await {
-            Ok(res) => res,
-            Err(e) => {
-                error!("Worker {i} failed to execute job {}: {e}", id);
+        let task = job.task_type;
+        if job.deadline < Instant::now() {
+            debug!("Worker {i} dropping job {id}, which waited past its deadline");
+            if jobs::expire(jobs, id).await {
+                metrics.job_finished(task, JobState::Expired);
+            }
+            continue;
+        }
+
+        if !jobs::claim(jobs, id, i, None).await {
+            debug!("Worker {i} skipping job {id}, which is no longer queued");
+            continue;
+        }
+
+        // The job runs under the configuration it started with, even if it is reloaded
+        let config = workers.config.get();
+
+        metrics.busy_workers.inc();
+        let ran = AssertUnwindSafe(run_job(workers, i, job, &config))
+            .catch_unwind()
+            .await;
+        metrics.busy_workers.dec();
+
+        // A panic fails the job, and the worker carries on with the next one
+        if let Err(panic) = ran {
+            error!(
+                "Worker {i} panicked while running job {id}: {}",
+                panic_message(&*panic)
+            );
+            metrics.worker_panics.inc();
+            let _ = tokio::fs::remove_dir_all(format!("{}/{id}", config.paths.work_dir)).await;
+            let result = JobResult {
+                stdout: "".to_string(),
+                stderr: "Fatal execution error: the worker crashed".to_string(),
+                exit_code: -1,
+            };
+            if jobs::finish(jobs, id, JobState::Failed, result).await {
+                metrics.job_finished(task, JobState::Failed);
+            }
+        }
+    }
+}
+
+async fn run_job(workers: &Workers, i: usize, job: Job, config: &Config) {
+    let jobs = &workers.jobs;
+    let metrics = &workers.metrics;
+    let id = job.id;
+    let client = job.client.clone();
+    let tier = job.tier.clone();
+    let task = job.task_type;
+
+    let mut cpu_time = Duration::ZERO;
+    let report = |state| jobs::set_state(jobs, id, state).map(drop);
+    // What the program prints is published as it runs, for clients streaming the job
+    let Some(output) = jobs::output(jobs, id).await else {
+        return;
+    };
+    let on_output = |stream, data: &[u8]| {
+        output.send_modify(|o| o.get_mut(stream).extend_from_slice(data));
+    };
+    // An operator may cancel the job while it runs, which kills its processes
+    let ran = tokio::select! {
+        ran = sandbox::sandboxed_execution(
+            job.into(),
+            config,
+            &mut cpu_time,
+            report,
+            &on_output,
+        ) => ran,
+        () = jobs::finished(jobs, id) => {
+            debug!("Worker {i} stopped job {id}, which was cancelled");
+            let _ = tokio::fs::remove_dir_all(format!("{}/{id}", config.paths.work_dir)).await;
+            return;
+        }
+    };
+    let (state, result) = match ran {
+        Ok(res) => (JobState::Completed, res),
+        Err(e) => {
+            error!("Worker {i} failed to execute job {}: {e}", id);
+            (
+                JobState::Failed,
                 JobResult {
                     stdout: "".to_string(),
                     stderr: format!("Fatal execution error: {e}"),
                     exit_code: -1,
-                }
-            }
-        };
+                },
+            )
+        }
+    };
 
-        results.lock().await.insert(id, result);
+    // Charge the client for the CPU time the job actually used
+    workers.rate_limiter.debit(
+        &client,
+        &tier,
+        cpu_time.as_secs_f64() * tier.cpu_second_cost,
+    );
+    debug!("Job {id} used {cpu_time:?} of CPU time");
 
-        debug!("Worker {i} completed job {id}");
+    let result = sandbox::truncate_output(result, tier.max_output_bytes);
+    if !jobs::finish(jobs, id, state, result).await {
+        return;
+    }
 
-        // Clean up job from results after some time to prevent memory bloat
-        let results_clone = results.clone();
-        tokio::spawn(async move {
-            tokio::time::sleep(Duration::from_mins(5)).await;
-            debug!("Worker {i} cleaning up results for job {id}");
-            results_clone.lock().await.remove(&id);
-        });
+    metrics.job_finished(task, state);
+    if let Some(status) = jobs::status(jobs, id).await {
+        metrics.observe_stages(&status);
     }
+
+    debug!("Worker {i} completed job {id}");
 }
@@ -0,0 +1,666 @@
+use std::{
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::http::HeaderValue;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::api_keys::Tier;
+
+// Server configuration, read from a TOML file (`CONFIG_FILE`, `./config.toml` by default)
+// with every setting overridable from the environment as `ZPLAY_<SECTION>_<KEY>`, e.g.
+// `ZPLAY_WORKERS_MAX=8` or `ZPLAY_LIMITS_LINT_TIMEOUT_SECS=5`. See `config.example.toml`.
+//
+// The configuration is reloaded on SIGHUP or `POST /api/v1/admin/reload`. Most settings
+// apply to new jobs straight away; see `restart_required` for the ones that don't.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub paths: PathsConfig,
+    pub workers: WorkersConfig,
+    pub queue: QueueConfig,
+    pub sessions: SessionsConfig,
+    pub limits: LimitsConfig,
+    pub remote: RemoteConfig,
+    // Limits for clients without an API key, unless the API keys file defines an `anonymous`
+    // tier of its own
+    pub anonymous: Tier,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub port: u16,
+    // The web frontend, served for any path that isn't an API route
+    pub static_dir: String,
+    // Origins allowed to call the API from a browser, or `*` for any
+    pub cors_origins: Vec<String>,
+    // Addresses or CIDR ranges of the reverse proxies whose `X-Forwarded-For` and `X-Real-IP`
+    // headers identify anonymous clients. Anyone else could make up a new address with every
+    // request.
+    pub trusted_proxies: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            port: 3000,
+            static_dir: "../web/public".to_string(),
+            cors_origins: vec!["*".to_string()],
+            trusted_proxies: vec!["127.0.0.1".to_string(), "::1".to_string()],
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|range| ip_in_range(ip, range) == Some(true))
+    }
+}
+
+// Whether `ip` is the address, or in the CIDR range, `range`. `None` if `range` is neither.
+fn ip_in_range(ip: IpAddr, range: &str) -> Option<bool> {
+    let (network, prefix) = match range.split_once('/') {
+        Some((network, prefix)) => (network.parse::<IpAddr>().ok()?, prefix.parse().ok()?),
+        None => {
+            let network = range.parse::<IpAddr>().ok()?;
+            (network, if network.is_ipv4() { 32 } else { 128 })
+        }
+    };
+
+    match (network, ip.to_canonical()) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) if prefix <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            Some(u32::from(network) & mask == u32::from(ip) & mask)
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) if prefix <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            Some(u128::from(network) & mask == u128::from(ip) & mask)
+        }
+        (IpAddr::V4(_), _) if prefix <= 32 => Some(false),
+        (IpAddr::V6(_), _) if prefix <= 128 => Some(false),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConfig {
+    pub database: String,
+    pub api_keys: String,
+    pub work_dir: String,
+    // Where the Zirco toolchain is unpacked, with `bin/`, `include/` and `libzr/` inside
+    pub toolchain: String,
+    pub seccomp_policy: String,
+    pub metrics_file: String,
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        PathsConfig {
+            database: "./playground.db".to_string(),
+            api_keys: "./api-keys.toml".to_string(),
+            work_dir: "./work".to_string(),
+            toolchain: "./zrc-nightly".to_string(),
+            seccomp_policy: "./seccomp.policy".to_string(),
+            metrics_file: "./metrics.json".to_string(),
+        }
+    }
+}
+
+impl PathsConfig {
+    pub fn toolchain_bin(&self, name: &str) -> String {
+        format!("{}/bin/{name}", self.toolchain)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkersConfig {
+    // The pool grows towards `max` while jobs are queued, as long as the host's load average
+    // per CPU stays below `max_load`, and shrinks back to `min` when it is idle
+    pub min: usize,
+    pub max: usize,
+    pub max_load: f64,
+    pub scale_interval_secs: u64,
+    pub metrics_interval_secs: u64,
+    // How long running jobs get to finish when the server shuts down
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for WorkersConfig {
+    fn default() -> Self {
+        WorkersConfig {
+            min: 2,
+            max: 8,
+            max_load: 1.0,
+            scale_interval_secs: 5,
+            metrics_interval_secs: 10,
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+impl WorkersConfig {
+    pub fn scale_interval(&self) -> Duration {
+        Duration::from_secs(self.scale_interval_secs)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    pub depth: usize,
+    pub timeout_secs: u64,
+    // Batches can hold far more jobs than the interactive queue, and they take longer to get
+    // through, so their jobs get a separate limit and more time to wait
+    pub batch_depth: usize,
+    pub batch_timeout_secs: u64,
+    pub max_batch_size: usize,
+    // Keep queued jobs in the database, so they survive a restart or crash
+    pub durable: bool,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            depth: 64,
+            timeout_secs: 60,
+            batch_depth: 1024,
+            batch_timeout_secs: 60 * 60,
+            max_batch_size: 500,
+            durable: false,
+        }
+    }
+}
+
+impl QueueConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    pub fn batch_timeout(&self) -> Duration {
+        Duration::from_secs(self.batch_timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionsConfig {
+    pub max: usize,
+    // Interactive sessions are closed when the user stops typing for this long, and killed
+    // after the time limit regardless
+    pub idle_timeout_secs: u64,
+    pub time_limit_secs: u64,
+}
+
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        SessionsConfig {
+            max: 8,
+            idle_timeout_secs: 5 * 60,
+            time_limit_secs: 30 * 60,
+        }
+    }
+}
+
+impl SessionsConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+// Limits for running a toolchain step. Memory is limited by the client's tier.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StepLimits {
+    // Wall clock time
+    pub timeout_secs: u64,
+    pub cpu_seconds: u64,
+    pub max_file_bytes: u64,
+}
+
+impl Default for StepLimits {
+    fn default() -> Self {
+        StepLimits {
+            timeout_secs: 10,
+            cpu_seconds: 10,
+            max_file_bytes: 100 * 1024 * 1024,
+        }
+    }
+}
+
+impl StepLimits {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+// Limits for each toolchain step. Running the program itself is limited by the client's tier.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub lint: StepLimits,
+    pub tast: StepLimits,
+    pub llvm: StepLimits,
+    pub compile: StepLimits,
+    pub link: StepLimits,
+    // File descriptors available to programs in the jail
+    pub max_open_files: u32,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            lint: StepLimits::default(),
+            tast: StepLimits::default(),
+            llvm: StepLimits::default(),
+            compile: StepLimits::default(),
+            link: StepLimits::default(),
+            max_open_files: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteConfig {
+    // Shared secret remote workers authenticate with. The worker API is disabled while it is
+    // empty.
+    pub token: String,
+    // Jobs a remote worker stops sending heartbeats for are queued again after this long
+    pub lease_secs: u64,
+    // Used when running as a remote worker: the API server to pull jobs from, the name to
+    // register under (the host name if empty) and how many jobs to run at once
+    pub server_url: String,
+    pub name: String,
+    pub slots: usize,
+}
+
+impl Default for RemoteConfig {
+    fn default() -> Self {
+        RemoteConfig {
+            token: "".to_string(),
+            lease_secs: 30,
+            server_url: "http://localhost:3000".to_string(),
+            name: "".to_string(),
+            slots: 2,
+        }
+    }
+}
+
+impl RemoteConfig {
+    pub fn enabled(&self) -> bool {
+        !self.token.is_empty()
+    }
+
+    pub fn lease(&self) -> Duration {
+        Duration::from_secs(self.lease_secs)
+    }
+}
+
+pub fn path() -> String {
+    std::env::var("CONFIG_FILE").unwrap_or_else(|_| "./config.toml".to_string())
+}
+
+// A value that can be replaced while the server runs. Readers take a snapshot, which stays
+// the same for as long as they hold on to it, so a job started under the old configuration
+// finishes under it.
+#[derive(Debug)]
+pub struct Reloadable<T>(RwLock<Arc<T>>);
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Reloadable(RwLock::new(Arc::new(value)))
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn set(&self, value: T) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(value);
+    }
+}
+
+// Environment variables from before the configuration file, still honoured
+const LEGACY_ENV: &[(&str, &str)] = &[
+    ("PORT", "ZPLAY_SERVER_PORT"),
+    ("DATABASE_PATH", "ZPLAY_PATHS_DATABASE"),
+    ("API_KEYS_FILE", "ZPLAY_PATHS_API_KEYS"),
+    // A fixed number of workers, as before autoscaling
+    ("NUM_WORKERS", "ZPLAY_WORKERS_MIN"),
+    ("NUM_WORKERS", "ZPLAY_WORKERS_MAX"),
+    ("QUEUE_DEPTH", "ZPLAY_QUEUE_DEPTH"),
+    ("QUEUE_TIMEOUT_SECS", "ZPLAY_QUEUE_TIMEOUT_SECS"),
+    ("BATCH_QUEUE_DEPTH", "ZPLAY_QUEUE_BATCH_DEPTH"),
+    ("BATCH_QUEUE_TIMEOUT_SECS", "ZPLAY_QUEUE_BATCH_TIMEOUT_SECS"),
+    ("MAX_SESSIONS", "ZPLAY_SESSIONS_MAX"),
+    (
+        "SESSION_IDLE_TIMEOUT_SECS",
+        "ZPLAY_SESSIONS_IDLE_TIMEOUT_SECS",
+    ),
+    ("SESSION_TIME_LIMIT_SECS", "ZPLAY_SESSIONS_TIME_LIMIT_SECS"),
+];
+
+// Looks up an environment variable in `env`, falling back to the legacy name for it.
+fn env_var(name: &str, env: &dyn Fn(&str) -> Option<String>) -> Option<String> {
+    env(name).or_else(|| {
+        let (legacy, _) = LEGACY_ENV.iter().find(|(_, current)| *current == name)?;
+        env(legacy)
+    })
+}
+
+// Parses an environment variable as the same type as the setting's default value.
+fn parse_env_value(name: &str, raw: &str, like: &toml::Value) -> Result<toml::Value, String> {
+    let invalid = |e: &dyn std::fmt::Display| format!("Invalid value for {name}: {e}");
+    Ok(match like {
+        toml::Value::Integer(_) => toml::Value::Integer(raw.parse().map_err(|e| invalid(&e))?),
+        toml::Value::Float(_) => toml::Value::Float(raw.parse().map_err(|e| invalid(&e))?),
+        toml::Value::Boolean(_) => toml::Value::Boolean(raw.parse().map_err(|e| invalid(&e))?),
+        // Lists are comma separated
+        toml::Value::Array(_) => toml::Value::Array(
+            raw.split(',')
+                .map(|item| toml::Value::String(item.trim().to_string()))
+                .collect(),
+        ),
+        _ => toml::Value::String(raw.to_string()),
+    })
+}
+
+// Overrides settings in `table` from the environment, for every setting that has a default.
+fn apply_env_overrides(
+    table: &mut toml::Table,
+    defaults: &toml::Table,
+    prefix: &str,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<(), String> {
+    for (key, default) in defaults {
+        let name = format!("{prefix}_{}", key.to_uppercase());
+        if let toml::Value::Table(defaults) = default {
+            let section = table
+                .entry(key.clone())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            let toml::Value::Table(section) = section else {
+                return Err(format!("Invalid configuration: {key} must be a table"));
+            };
+            apply_env_overrides(section, defaults, &name, env)?;
+        } else if let Some(raw) = env_var(&name, env) {
+            table.insert(key.clone(), parse_env_value(&name, &raw, default)?);
+        }
+    }
+    Ok(())
+}
+
+impl Config {
+    // Loads the configuration file, if there is one, applies environment overrides and
+    // validates the result.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let table = match std::fs::read_to_string(path) {
+            Ok(contents) => contents
+                .parse::<toml::Table>()
+                .map_err(|e| format!("Invalid configuration file: {e}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("No configuration file at {path}, using defaults");
+                toml::Table::new()
+            }
+            Err(e) => return Err(format!("Failed to read configuration file: {e}")),
+        };
+        Config::from_table(table, &|name| std::env::var(name).ok())
+    }
+
+    // Builds the configuration from the file's contents and the environment `env`.
+    fn from_table(
+        mut table: toml::Table,
+        env: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<Self, String> {
+        let defaults = toml::Table::try_from(Config::default())
+            .map_err(|e| format!("Failed to serialize default configuration: {e}"))?;
+        apply_env_overrides(&mut table, &defaults, "ZPLAY", env)?;
+
+        let config: Config = table
+            .try_into()
+            .map_err(|e| format!("Invalid configuration: {e}"))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Settings that are only read at startup, which differ between this configuration and
+    // `new`. Changing them takes a restart.
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        [
+            ("server.port", self.server.port != new.server.port),
+            (
+                "server.static_dir",
+                self.server.static_dir != new.server.static_dir,
+            ),
+            (
+                "server.cors_origins",
+                self.server.cors_origins != new.server.cors_origins,
+            ),
+            ("paths.database", self.paths.database != new.paths.database),
+            ("queue.depth", self.queue.depth != new.queue.depth),
+            (
+                "queue.batch_depth",
+                self.queue.batch_depth != new.queue.batch_depth,
+            ),
+            ("queue.durable", self.queue.durable != new.queue.durable),
+            ("sessions.max", self.sessions.max != new.sessions.max),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        let mut require = |ok: bool, message: &str| {
+            if !ok {
+                errors.push(message.to_string());
+            }
+        };
+
+        // Without remote workers, some local worker has to run the jobs
+        require(
+            self.workers.max > 0 || self.remote.enabled(),
+            "workers.max must be at least 1 unless remote workers are enabled",
+        );
+        require(
+            self.workers.max >= self.workers.min,
+            "workers.max must be at least workers.min",
+        );
+        require(
+            self.workers.max_load > 0.0,
+            "workers.max_load must be positive",
+        );
+        require(
+            self.workers.scale_interval_secs > 0,
+            "workers.scale_interval_secs must be at least 1",
+        );
+        require(
+            self.workers.metrics_interval_secs > 0,
+            "workers.metrics_interval_secs must be at least 1",
+        );
+        require(self.queue.depth > 0, "queue.depth must be at least 1");
+        require(
+            self.queue.timeout_secs > 0,
+            "queue.timeout_secs must be at least 1",
+        );
+        require(
+            self.queue.max_batch_size > 0,
+            "queue.max_batch_size must be at least 1",
+        );
+        require(
+            self.queue.batch_depth >= self.queue.max_batch_size,
+            "queue.batch_depth must be at least queue.max_batch_size",
+        );
+        require(
+            self.queue.batch_timeout_secs > 0,
+            "queue.batch_timeout_secs must be at least 1",
+        );
+        require(
+            self.sessions.idle_timeout_secs > 0,
+            "sessions.idle_timeout_secs must be at least 1",
+        );
+        require(
+            self.sessions.time_limit_secs > 0,
+            "sessions.time_limit_secs must be at least 1",
+        );
+
+        require(
+            self.remote.lease_secs >= 3,
+            "remote.lease_secs must be at least 3",
+        );
+        require(self.remote.slots > 0, "remote.slots must be at least 1");
+
+        let limits = &self.limits;
+        for (name, step) in [
+            ("lint", &limits.lint),
+            ("tast", &limits.tast),
+            ("llvm", &limits.llvm),
+            ("compile", &limits.compile),
+            ("link", &limits.link),
+        ] {
+            require(
+                step.timeout_secs > 0 && step.cpu_seconds > 0 && step.max_file_bytes > 0,
+                &format!("limits.{name} must all be at least 1"),
+            );
+        }
+        require(
+            limits.max_open_files >= 3,
+            "limits.max_open_files must be at least 3",
+        );
+
+        if let Err(e) = self.anonymous.validate() {
+            errors.push(format!("anonymous: {e}"));
+        }
+
+        for origin in &self.server.cors_origins {
+            if origin != "*" && HeaderValue::from_str(origin).is_err() {
+                errors.push(format!("server.cors_origins: invalid origin {origin:?}"));
+            }
+        }
+        for range in &self.server.trusted_proxies {
+            // Any address will do, this only checks that the range parses
+            if ip_in_range(IpAddr::from([0, 0, 0, 0]), range).is_none() {
+                errors.push(format!(
+                    "server.trusted_proxies: invalid address or range {range:?}"
+                ));
+            }
+        }
+
+        if !std::path::Path::new(&self.server.static_dir).is_dir() {
+            warn!(
+                "Static directory {} does not exist, the frontend won't be served",
+                self.server.static_dir
+            );
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid configuration:\n  {}", errors.join("\n  ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(file: &str, env: &[(&str, &str)]) -> Result<Config, String> {
+        let table = file.parse::<toml::Table>().unwrap();
+        Config::from_table(table, &|name| {
+            env.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let config = load(
+            "[workers]\nmin = 1\nmax = 4\n",
+            &[
+                ("ZPLAY_WORKERS_MAX", "6"),
+                ("ZPLAY_LIMITS_LINT_TIMEOUT_SECS", "5"),
+                (
+                    "ZPLAY_SERVER_CORS_ORIGINS",
+                    "https://a.example, https://b.example",
+                ),
+                ("ZPLAY_QUEUE_DURABLE", "true"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!((config.workers.min, config.workers.max), (1, 6));
+        assert_eq!(config.limits.lint.timeout_secs, 5);
+        assert_eq!(
+            config.server.cors_origins,
+            ["https://a.example", "https://b.example"]
+        );
+        assert!(config.queue.durable);
+        // Everything else keeps its default
+        assert_eq!(config.queue.depth, QueueConfig::default().depth);
+    }
+
+    #[test]
+    fn legacy_variables_are_honoured() {
+        let config = load("", &[("NUM_WORKERS", "3"), ("PORT", "8080")]).unwrap();
+        assert_eq!((config.workers.min, config.workers.max), (3, 3));
+        assert_eq!(config.server.port, 8080);
+
+        // The current name wins
+        let config = load("", &[("PORT", "8080"), ("ZPLAY_SERVER_PORT", "9090")]).unwrap();
+        assert_eq!(config.server.port, 9090);
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let error = load("", &[("ZPLAY_WORKERS_MAX", "many")]).unwrap_err();
+        assert!(
+            error.starts_with("Invalid value for ZPLAY_WORKERS_MAX"),
+            "{error}"
+        );
+
+        let error = load("[workers]\nfrobnicate = true\n", &[]).unwrap_err();
+        assert!(error.contains("frobnicate"), "{error}");
+
+        // Every problem is reported at once
+        let error = load(
+            "[workers]\nmin = 4\nmax = 2\n[queue]\ndepth = 0\n",
+            &[("ZPLAY_SERVER_TRUSTED_PROXIES", "10.0.0.0/40")],
+        )
+        .unwrap_err();
+        for problem in ["workers.max", "queue.depth", "server.trusted_proxies"] {
+            assert!(error.contains(problem), "{error}");
+        }
+    }
+
+    #[test]
+    fn trusted_proxies_match_addresses_and_ranges() {
+        let server = ServerConfig {
+            trusted_proxies: vec!["10.1.0.0/16".to_string(), "::1".to_string()],
+            ..ServerConfig::default()
+        };
+        let trusted = |ip: &str| server.is_trusted_proxy(ip.parse().unwrap());
+
+        assert!(trusted("10.1.2.3"));
+        assert!(!trusted("10.2.0.1"));
+        assert!(trusted("::1"));
+        assert!(!trusted("127.0.0.1"));
+        // IPv4 peers of a dual-stack socket
+        assert!(trusted("::ffff:10.1.0.1"));
+    }
+}
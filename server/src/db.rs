@@ -0,0 +1,88 @@
+use std::sync::{Arc, Mutex};
+
+use rusqlite::Connection;
+use tracing::info;
+
+pub type Db = Arc<Mutex<Connection>>;
+
+// Schema migrations, applied in order. The index of the last applied migration (+1) is
+// tracked in SQLite's `user_version` pragma, so only append to this list.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE snippets (
+        id TEXT PRIMARY KEY NOT NULL,
+        task TEXT NOT NULL,
+        toolchain TEXT NOT NULL,
+        flags TEXT NOT NULL,
+        code TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );",
+    // Snippets become lineages of immutable revisions.
+    "CREATE TABLE snippet_revisions (
+        snippet_id TEXT NOT NULL REFERENCES snippets(id),
+        revision INTEGER NOT NULL,
+        parent_revision INTEGER,
+        task TEXT NOT NULL,
+        toolchain TEXT NOT NULL,
+        flags TEXT NOT NULL,
+        code TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        PRIMARY KEY (snippet_id, revision)
+    );
+    INSERT INTO snippet_revisions
+        SELECT id, 1, NULL, task, toolchain, flags, code, created_at FROM snippets;
+    ALTER TABLE snippets DROP COLUMN task;
+    ALTER TABLE snippets DROP COLUMN toolchain;
+    ALTER TABLE snippets DROP COLUMN flags;
+    ALTER TABLE snippets DROP COLUMN code;
+    ALTER TABLE snippets ADD COLUMN forked_from_id TEXT;
+    ALTER TABLE snippets ADD COLUMN forked_from_revision INTEGER;",
+    // Jobs kept across restarts when the queue is durable. `started_at` is set before a job
+    // runs, and `state` once it finishes.
+    "CREATE TABLE queued_jobs (
+        id TEXT PRIMARY KEY NOT NULL,
+        client TEXT NOT NULL,
+        tier TEXT NOT NULL,
+        task TEXT NOT NULL,
+        code TEXT NOT NULL,
+        batch_id TEXT,
+        deadline INTEGER NOT NULL,
+        started_at INTEGER,
+        state TEXT NOT NULL
+    );",
+    "CREATE TABLE bans (
+        client TEXT PRIMARY KEY NOT NULL,
+        reason TEXT,
+        created_at INTEGER NOT NULL
+    );",
+];
+
+pub fn open(path: &str) -> rusqlite::Result<Db> {
+    let mut conn = Connection::open(path)?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Applying database migration {}", i + 1);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+
+    Ok(Arc::new(Mutex::new(conn)))
+}
+
+// Runs a closure against the database on the blocking thread pool.
+pub async fn with_db<T, F>(db: &Db, f: F) -> rusqlite::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+{
+    let db = db.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut conn = db.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut conn)
+    })
+    .await
+    .expect("database task panicked")
+}
@@ -0,0 +1,290 @@
+use axum::{
+    extract::{
+        FromRequest, FromRequestParts, Request,
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+    },
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+// Every failed request is answered with a JSON body like
+//
+//     {"error": {"code": "not_found", "message": "Unknown job ID.", "requestId": "..."}}
+//
+// where `code` is one of the `ErrorCode`s below and never changes for a given failure, while
+// the message is meant for people. The request ID is also sent as `X-Request-Id` on every
+// response, and appears in the server's logs.
+
+// How much of a plain text error body is kept as the message when converting it
+const MAX_MESSAGE_BYTES: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // The path or query string is malformed
+    InvalidRequest,
+    // The body isn't valid JSON, or doesn't have the expected shape
+    InvalidBody,
+    UnsupportedMediaType,
+    // The request is well-formed but asks for something that doesn't make sense
+    ValidationFailed,
+    Unauthorized,
+    Forbidden,
+    Banned,
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    // The job's result is not available anymore, or never will be
+    Gone,
+    PayloadTooLarge,
+    InvalidConfig,
+    RateLimited,
+    QueueFull,
+    SessionsFull,
+    ShuttingDown,
+    Unavailable,
+    Internal,
+}
+
+impl ErrorCode {
+    fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest | ErrorCode::InvalidBody => StatusCode::BAD_REQUEST,
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::ValidationFailed | ErrorCode::InvalidConfig => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden | ErrorCode::Banned => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::Gone => StatusCode::GONE,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::QueueFull
+            | ErrorCode::SessionsFull
+            | ErrorCode::ShuttingDown
+            | ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // The closest code for an error response that didn't come with one.
+    fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => ErrorCode::UnsupportedMediaType,
+            StatusCode::UNPROCESSABLE_ENTITY => ErrorCode::ValidationFailed,
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::METHOD_NOT_ALLOWED => ErrorCode::MethodNotAllowed,
+            StatusCode::CONFLICT => ErrorCode::Conflict,
+            StatusCode::GONE => ErrorCode::Gone,
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+            StatusCode::TOO_MANY_REQUESTS => ErrorCode::RateLimited,
+            StatusCode::SERVICE_UNAVAILABLE => ErrorCode::Unavailable,
+            status if status.is_client_error() => ErrorCode::InvalidRequest,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: ErrorDetails,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorDetails {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+}
+
+// A failed request, as handlers and extractors report it.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: ErrorCode,
+    message: String,
+    // Extra headers to answer with, like `Retry-After`
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError {
+            status: code.status(),
+            code,
+            message: message.into(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApiError::new(ErrorCode::NotFound, message)
+    }
+
+    // Something went wrong on our side. The details are logged where it happened rather than
+    // sent to the client.
+    pub fn internal() -> Self {
+        ApiError::new(ErrorCode::Internal, "Something went wrong on the server.")
+    }
+
+    // Answers with a status other than the code's usual one.
+    fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn with_headers(mut self, headers: &HeaderMap) -> Self {
+        self.headers.extend(
+            headers
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+        self
+    }
+
+    pub fn with_retry_after(mut self, secs: u64) -> Self {
+        self.headers.push((header::RETRY_AFTER, secs.into()));
+        self
+    }
+}
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// The ID of the request being handled, also available as a request extension.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorResponse {
+            error: ErrorDetails {
+                code: self.code,
+                message: self.message,
+                request_id: REQUEST_ID.try_with(Clone::clone).unwrap_or_default(),
+            },
+        };
+        let mut response = (self.status, axum::Json(body)).into_response();
+        response.headers_mut().extend(self.headers);
+        response
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        let code = match rejection {
+            JsonRejection::JsonDataError(_) | JsonRejection::JsonSyntaxError(_) => {
+                ErrorCode::InvalidBody
+            }
+            JsonRejection::MissingJsonContentType(_) => ErrorCode::UnsupportedMediaType,
+            _ => ErrorCode::from_status(rejection.status()),
+        };
+        ApiError::new(code, rejection.body_text()).with_status(rejection.status())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        ApiError::new(ErrorCode::InvalidRequest, rejection.body_text())
+            .with_status(rejection.status())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::new(ErrorCode::InvalidRequest, rejection.body_text())
+            .with_status(rejection.status())
+    }
+}
+
+// axum's extractors, rejecting requests with an `ApiError` instead of plain text.
+
+#[derive(Debug, FromRequest)]
+#[from_request(via(axum::Json), rejection(ApiError))]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+#[derive(Debug, FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(ApiError))]
+pub struct Path<T>(pub T);
+
+#[derive(Debug, FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ApiError))]
+pub struct Query<T>(pub T);
+
+// Takes the client's `X-Request-Id` if it sent a sensible one, or makes one up.
+fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 64)
+        .filter(|id| id.bytes().all(|b| b.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+// Tags every request with an ID, and turns error responses that aren't JSON yet, like the
+// ones axum sends for unknown methods or WebSocket upgrades that aren't, into `ApiError`s.
+pub async fn handle_errors(mut req: Request, next: Next) -> Response {
+    let id = request_id(req.headers());
+    req.extensions_mut().insert(RequestId(id.clone()));
+
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(req)).await;
+
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    let status = response.status();
+    if (status.is_client_error() || status.is_server_error()) && !is_json {
+        let (mut parts, body) = response.into_parts();
+        let is_text = parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("text/plain"));
+        let text = match axum::body::to_bytes(body, MAX_MESSAGE_BYTES).await {
+            Ok(bytes) if is_text => String::from_utf8_lossy(&bytes).trim().to_string(),
+            _ => String::new(),
+        };
+        let message = if text.is_empty() {
+            status
+                .canonical_reason()
+                .unwrap_or("Request failed")
+                .to_string()
+        } else {
+            text
+        };
+
+        parts.headers.remove(header::CONTENT_TYPE);
+        parts.headers.remove(header::CONTENT_LENGTH);
+        response = REQUEST_ID.sync_scope(id.clone(), || {
+            ApiError::new(ErrorCode::from_status(status), message)
+                .with_status(status)
+                .with_headers(&parts.headers)
+                .into_response()
+        });
+    }
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert("x-request-id", value);
+    }
+    response
+}
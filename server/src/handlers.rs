@@ -1,83 +1,743 @@
 use std::{convert::Infallible, time::Duration};
 
 use axum::{
-    Json,
-    extract::{Path, State},
-    http::StatusCode,
-    response::{Sse, sse::Event},
+    extract::{State, WebSocketUpgrade},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response, Sse, sse::Event},
 };
 use futures::{Stream, stream};
-use tracing::debug;
+use tokio::{sync::watch, time::Instant};
+use tracing::{debug, error, info};
 use uuid::Uuid;
 
-use crate::models::{AppState, ExecuteRequest, ExecuteResponse, Job, JobResult};
+use crate::{
+    bans, batches,
+    client::{Admin, Client, WorkerToken},
+    db::with_db,
+    error::{ApiError, ErrorCode, ErrorResponse, Json, Path, Query},
+    health, jobs,
+    metrics::LaneLabels,
+    models::{
+        AppState, Ban, BatchProgress, BatchRequest, BatchResponse, BatchStatus,
+        CreateSnippetRequest, DiffQuery, ExecuteRequest, ExecuteResponse, HeartbeatResponse, Job,
+        JobDetails, JobOutput, JobResult, JobState, JobStatus, JobsQuery, LocalWorkersStatus,
+        OutputEvent, OutputStream, PendingEvent, QueueStatus, Readiness, ReadinessCheck,
+        RegisterWorkerRequest, RegisterWorkerResponse, ReloadResponse, RemoteResult, RevisionQuery,
+        RevisionSummary, RunRequest, SaveRevisionRequest, Snippet, SnippetDiff, StageReport,
+        StreamError, TaskType, VersionResponse, WorkerHeartbeat, WorkersStatus,
+    },
+    openapi,
+    rate_limit::RateLimitStatus,
+    reload, remote, session, snippets,
+};
+
+// How many jobs can run at once, on this server's workers and remote ones
+fn worker_capacity(state: &AppState) -> usize {
+    state.workers.count() + state.remote_workers.capacity().1
+}
+
+// Takes `cost` tokens from the client's rate limit, answering 429 if it doesn't have enough.
+fn check_rate_limit(
+    state: &AppState,
+    client: &Client,
+    cost: f64,
+) -> Result<RateLimitStatus, ApiError> {
+    state
+        .rate_limiter
+        .check(&client.key, &client.tier, cost)
+        .map_err(|limited| {
+            let retry_after = ceil_secs(limited.retry_after).max(1);
+            debug!("Rate limiting {} (retry after {retry_after}s)", client.key);
+            state.metrics.rejected("rate_limited");
+
+            ApiError::new(
+                ErrorCode::RateLimited,
+                format!("Too many requests, try again in {retry_after}s."),
+            )
+            .with_headers(&rate_limit_headers(limited.status))
+            .with_retry_after(retry_after)
+        })
+}
+
+// Turns away new work once the server is shutting down.
+fn check_shutdown(state: &AppState) -> Result<(), ApiError> {
+    if state.shutdown.is_started() {
+        state.metrics.rejected("shutting_down");
+        return Err(ApiError::new(
+            ErrorCode::ShuttingDown,
+            "The server is shutting down.",
+        ));
+    }
+    Ok(())
+}
+
+fn unknown_job() -> ApiError {
+    ApiError::not_found("Unknown job ID.")
+}
+
+fn result_gone() -> ApiError {
+    ApiError::new(
+        ErrorCode::Gone,
+        "The job was cancelled or expired, or its result was discarded, so it is not available.",
+    )
+}
+
+// Rate limits and queues a job, returning its ID along with the client's rate limit headers.
+async fn submit_job(
+    state: &AppState,
+    client: Client,
+    req: ExecuteRequest,
+) -> Result<(HeaderMap, Uuid), ApiError> {
+    check_shutdown(state)?;
+    let cost = client.tier.task_costs.cost(req.task);
+    let rate_limit = check_rate_limit(state, &client, cost)?;
 
-pub async fn execute_code(
-    State(state): State<AppState>,
-    Json(req): Json<ExecuteRequest>,
-) -> Result<Json<ExecuteResponse>, StatusCode> {
     let job_id = uuid::Uuid::new_v4();
     let job = Job {
         id: job_id,
+        client: client.key.clone(),
+        tier: client.tier.clone(),
         task_type: req.task,
         code: req.code,
+        deadline: std::time::Instant::now() + state.config.get().queue.timeout(),
+        batch: None,
     };
 
     debug!("Sending new job {job_id} to work queue");
 
+    // Nothing was queued if this fails, so the client gets its tokens back
+    let refund = || {
+        state.rate_limiter.refund(&client.key, &client.tier, cost);
+    };
+    if let Err(e) = jobs::insert(&state.jobs, &job).await {
+        error!("Failed to store job {job_id}: {e}");
+        refund();
+        return Err(ApiError::internal());
+    }
+    if state.work_queue.try_push(job).is_err() {
+        jobs::remove(&state.jobs, job_id).await;
+        refund();
+
+        // The queue is full; tell the client when a slot is likely to free up
+        let retry_after =
+            ceil_secs(jobs::drain_interval(&state.jobs, worker_capacity(state)).await).max(1);
+        debug!("Work queue is full, rejecting job {job_id} (retry after {retry_after}s)");
+        state.metrics.rejected("queue_full");
+
+        return Err(ApiError::new(
+            ErrorCode::QueueFull,
+            format!("The playground is busy, try again in {retry_after}s."),
+        )
+        .with_retry_after(retry_after));
+    }
+
+    Ok((rate_limit_headers(rate_limit), job_id))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/execute",
+    tag = "jobs",
+    description = "Queues a job. Follow its progress with `/api/v1/stream/{job_id}`, or poll \
+                   `/api/v1/results/{job_id}` for its result.",
+    request_body = ExecuteRequest,
+    responses(
+        (status = 200, description = "The job was queued", body = ExecuteResponse, headers(
+                ("x-ratelimit-limit" = u64, description = "Size of the client's token bucket"),
+                ("x-ratelimit-remaining" = u64, description = "Tokens left in the bucket"),
+                ("x-ratelimit-reset" = u64, description = "Seconds until the bucket is full again"),
+            )),
+        (status = 429, description = "The client is rate limited", body = ErrorResponse, headers(("retry-after" = u64, description = "Seconds to wait before trying again"))),
+        (status = 503, description = "The queue is full, or the server is shutting down", body = ErrorResponse, headers(("retry-after" = u64, description = "Seconds to wait before trying again"))),
+    )
+)]
+pub async fn execute_code(
+    State(state): State<AppState>,
+    client: Client,
+    Json(req): Json<ExecuteRequest>,
+) -> Result<(HeaderMap, Json<ExecuteResponse>), ApiError> {
+    let (headers, job_id) = submit_job(&state, client, req).await?;
+    Ok((headers, Json(ExecuteResponse { job_id })))
+}
+
+// How long `/run` waits for a job by default, and at most
+const DEFAULT_RUN_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_RUN_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Runs a job and holds the response until it finishes, for clients that don't want to poll.
+// If the timeout runs out first, the job keeps going and its status is returned with 202 so
+// the client can pick up the result later.
+#[utoipa::path(
+    post,
+    path = "/api/v1/run",
+    tag = "jobs",
+    description = "Queues a job and holds the response until it finishes, for at most `timeout` \
+                   seconds (30 by default, 120 at most). If it takes longer, the job keeps \
+                   running and its status is returned with 202.",
+    request_body = RunRequest,
+    responses(
+        (status = 200, description = "The job finished", body = JobResult, headers(
+                ("x-ratelimit-limit" = u64, description = "Size of the client's token bucket"),
+                ("x-ratelimit-remaining" = u64, description = "Tokens left in the bucket"),
+                ("x-ratelimit-reset" = u64, description = "Seconds until the bucket is full again"),
+            )),
+        (status = 202, description = "The job is still running", body = JobStatus),
+        (status = 410, description = "The job was cancelled or expired", body = ErrorResponse),
+        (status = 429, description = "The client is rate limited", body = ErrorResponse, headers(("retry-after" = u64, description = "Seconds to wait before trying again"))),
+        (status = 503, description = "The queue is full, or the server is shutting down", body = ErrorResponse, headers(("retry-after" = u64, description = "Seconds to wait before trying again"))),
+    )
+)]
+pub async fn run_code(
+    State(state): State<AppState>,
+    client: Client,
+    Json(req): Json<RunRequest>,
+) -> Result<(HeaderMap, Response), ApiError> {
+    let timeout = req
+        .timeout
+        .map_or(DEFAULT_RUN_TIMEOUT, Duration::from_secs)
+        .min(MAX_RUN_TIMEOUT);
+
+    let (headers, job_id) = submit_job(&state, client, req.job).await?;
+    let mut status = jobs::subscribe(&state.jobs, job_id)
+        .await
+        .ok_or_else(result_gone)?;
+
+    let finished = tokio::time::timeout(
+        timeout,
+        status.wait_for(|status| status.state.is_terminal()),
+    )
+    .await
+    .map(|finished| finished.is_ok());
+
+    let response = match finished {
+        Ok(true) => match jobs::result(&state.jobs, job_id).await {
+            Some(result) => Json(result).into_response(),
+            // Cancelled or expired, the result will never be available
+            None => result_gone().into_response(),
+        },
+        // The job was cleaned up while we were waiting on it
+        Ok(false) => result_gone().into_response(),
+        Err(_) => {
+            debug!("Timed out waiting for job {job_id}");
+            let status = status.borrow().clone();
+            (StatusCode::ACCEPTED, Json(status)).into_response()
+        }
+    };
+
+    Ok((headers, response))
+}
+
+// Upgrades to a WebSocket running an interactive session. Sessions cost as much as an
+// execute job up front, and are charged for their CPU time when they end.
+#[utoipa::path(
+    get,
+    path = "/api/v1/session",
+    tag = "sessions",
+    description = "Upgrades to a WebSocket running a program interactively. The client sends \
+                   `SessionRequest`s as text messages, starting with `start`, and raw input as \
+                   binary messages. The server sends `SessionEvent`s as text messages, and the \
+                   program's terminal output as binary messages.",
+    responses(
+        (status = 101, description = "The session started"),
+        (status = 429, description = "The client is rate limited", body = ErrorResponse, headers(("retry-after" = u64, description = "Seconds to wait before trying again"))),
+        (status = 503, description = "Too many sessions are running, or the server is shutting down", body = ErrorResponse, headers(("retry-after" = u64, description = "Seconds to wait before trying again"))),
+    )
+)]
+pub async fn interactive_session(
+    State(state): State<AppState>,
+    client: Client,
+    ws: WebSocketUpgrade,
+) -> Result<(HeaderMap, Response), ApiError> {
+    check_shutdown(&state)?;
+    let cost = client.tier.task_costs.cost(TaskType::Execute);
+    let rate_limit = check_rate_limit(&state, &client, cost)?;
+
+    let Ok(permit) = state.sessions.clone().try_acquire_owned() else {
+        debug!("Too many interactive sessions, rejecting {}", client.key);
+        state.metrics.rejected("sessions_full");
+        return Err(ApiError::new(
+            ErrorCode::SessionsFull,
+            "Too many interactive sessions are running, try again later.",
+        ));
+    };
+
+    Ok((
+        rate_limit_headers(rate_limit),
+        ws.on_upgrade(move |socket| session::run(socket, state, client, permit)),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/batch",
+    tag = "batches",
+    description = "Queues several jobs at once, in a lane that only gets workers once no single \
+                   jobs are waiting.",
+    request_body = BatchRequest,
+    responses(
+        (status = 200, description = "The batch was queued", body = BatchResponse, headers(
+                ("x-ratelimit-limit" = u64, description = "Size of the client's token bucket"),
+                ("x-ratelimit-remaining" = u64, description = "Tokens left in the bucket"),
+                ("x-ratelimit-reset" = u64, description = "Seconds until the bucket is full again"),
+            )),
+        (status = 400, description = "The batch is empty", body = ErrorResponse),
+        (status = 413, description = "The batch has too many jobs", body = ErrorResponse),
+        (status = 429, description = "The client is rate limited", body = ErrorResponse, headers(("retry-after" = u64, description = "Seconds to wait before trying again"))),
+        (status = 503, description = "The queue is full, or the server is shutting down", body = ErrorResponse, headers(("retry-after" = u64, description = "Seconds to wait before trying again"))),
+    )
+)]
+pub async fn submit_batch(
+    State(state): State<AppState>,
+    client: Client,
+    Json(req): Json<BatchRequest>,
+) -> Result<(HeaderMap, Json<BatchResponse>), ApiError> {
+    check_shutdown(&state)?;
+    if req.jobs.is_empty() {
+        return Err(ApiError::new(
+            ErrorCode::InvalidRequest,
+            "A batch needs at least one job.",
+        ));
+    }
+    let max_batch_size = state.config.get().queue.max_batch_size;
+    if req.jobs.len() > max_batch_size {
+        return Err(ApiError::new(
+            ErrorCode::PayloadTooLarge,
+            format!("A batch can have at most {max_batch_size} jobs."),
+        ));
+    }
+
+    // A batch usually costs more than a client's bucket can hold, so it is accepted as long as
+    // the bucket is full enough, and the client is left in debt for the rest once it is queued
+    let cost: f64 = req
+        .jobs
+        .iter()
+        .map(|job| client.tier.task_costs.cost(job.task))
+        .sum();
+    let upfront = cost.min(client.tier.burst as f64);
+    let rate_limit = check_rate_limit(&state, &client, upfront)?;
+    let refund = || {
+        state
+            .rate_limiter
+            .refund(&client.key, &client.tier, upfront);
+    };
+
+    let batch_id = Uuid::new_v4();
+    let deadline = std::time::Instant::now() + state.config.get().queue.batch_timeout();
+    let batch: Vec<Job> = req
+        .jobs
+        .into_iter()
+        .map(|job| Job {
+            id: Uuid::new_v4(),
+            client: client.key.clone(),
+            tier: client.tier.clone(),
+            task_type: job.task,
+            code: job.code,
+            deadline,
+            batch: Some(batch_id),
+        })
+        .collect();
+    let job_ids: Vec<Uuid> = batch.iter().map(|job| job.id).collect();
+
+    debug!(
+        "Sending batch {batch_id} of {} jobs to work queue",
+        job_ids.len()
+    );
+
+    for (i, job) in batch.iter().enumerate() {
+        if let Err(e) = jobs::insert(&state.jobs, job).await {
+            error!("Failed to store batch {batch_id}: {e}");
+            for id in &job_ids[..i] {
+                jobs::remove(&state.jobs, *id).await;
+            }
+            refund();
+            return Err(ApiError::internal());
+        }
+    }
+    if state.work_queue.try_push_batch(batch).is_err() {
+        for id in &job_ids {
+            jobs::remove(&state.jobs, *id).await;
+        }
+        refund();
+
+        // The batch queue is full; estimate when there will be room for this batch
+        let drain_interval = jobs::drain_interval(&state.jobs, worker_capacity(&state)).await;
+        let retry_after = ceil_secs(drain_interval * job_ids.len() as u32).max(1);
+        debug!("Batch queue is full, rejecting batch {batch_id} (retry after {retry_after}s)");
+        state.metrics.rejected("batch_queue_full");
+
+        return Err(ApiError::new(
+            ErrorCode::QueueFull,
+            format!("The batch queue is full, try again in {retry_after}s."),
+        )
+        .with_retry_after(retry_after));
+    }
+
     state
-        .work_queue
-        .send(job)
+        .rate_limiter
+        .debit(&client.key, &client.tier, cost - upfront);
+    batches::insert(&state.batches, &state.jobs, batch_id, job_ids.clone()).await;
+
+    Ok((
+        rate_limit_headers(rate_limit),
+        Json(BatchResponse { batch_id, job_ids }),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/batch/{batch_id}",
+    tag = "batches",
+    params(("batch_id" = Uuid, Path, description = "The batch's ID")),
+    responses(
+        (status = 200, description = "The batch's progress and results so far", body = BatchStatus),
+        (status = 404, description = "Unknown batch", body = ErrorResponse),
+    )
+)]
+pub async fn get_batch(
+    Path(batch_id): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Result<Json<BatchStatus>, ApiError> {
+    batches::status(&state.batches, &state.jobs, batch_id)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .ok_or_else(|| ApiError::not_found("Unknown batch ID."))
+        .map(Json)
+}
+
+struct WatchedBatch {
+    job_ids: Vec<Uuid>,
+    progress: watch::Receiver<BatchProgress>,
+    // How many finished jobs have been reported so far
+    reported: usize,
+    // Whether there is progress that hasn't been reported yet
+    unannounced: bool,
+}
+
+enum BatchStreamState {
+    Start,
+    Watching(WatchedBatch),
+    Done,
+}
+
+// Streams a batch's progress: an `item` event with the result of each job as it finishes,
+// followed by a `progress` event with the totals, and a final `complete` event once every
+// job is done.
+#[utoipa::path(
+    get,
+    path = "/api/v1/batch/{batch_id}/stream",
+    tag = "batches",
+    description = "Streams a batch's progress as server-sent events, whose data is JSON:\n\n\
+                   - `item`: a `BatchItem`, as each job finishes\n\
+                   - `progress`: a `BatchProgress` with the totals so far\n\
+                   - `complete`: the final `BatchProgress`, once every job is done\n\
+                   - `expired`: a `BatchProgress`, if the batch was cleaned up meanwhile\n\
+                   - `not_found`: a `StreamError`, if the batch is unknown",
+    params(("batch_id" = Uuid, Path, description = "The batch's ID")),
+    responses(
+        (status = 200, description = "The event stream", content_type = "text/event-stream", body = String),
+    )
+)]
+pub async fn stream_batch(
+    Path(batch_id): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let jobs = state.jobs.clone();
+    let batches = state.batches.clone();
+
+    let stream = stream::unfold(BatchStreamState::Start, move |stream_state| {
+        let jobs = jobs.clone();
+        let batches = batches.clone();
+        async move {
+            let mut watched = match stream_state {
+                BatchStreamState::Done => return None,
+                BatchStreamState::Watching(watched) => watched,
+                BatchStreamState::Start => {
+                    let Some((job_ids, progress)) = batches::subscribe(&batches, batch_id).await
+                    else {
+                        let event = Event::default()
+                            .event("not_found")
+                            .json_data(StreamError {
+                                error: "Unknown batch ID.".to_string(),
+                            })
+                            .ok()?;
+
+                        return Some((Ok(event), BatchStreamState::Done));
+                    };
+
+                    WatchedBatch {
+                        job_ids,
+                        progress,
+                        reported: 0,
+                        unannounced: true,
+                    }
+                }
+            };
+
+            loop {
+                let progress = watched.progress.borrow_and_update().clone();
+
+                if let Some(&index) = progress.finished.get(watched.reported) {
+                    watched.reported += 1;
+                    watched.unannounced = true;
+                    let item = batches::item(&jobs, index, watched.job_ids[index]).await;
+                    let event = Event::default().event("item").json_data(&item).ok()?;
+                    return Some((Ok(event), BatchStreamState::Watching(watched)));
+                }
+
+                if progress.is_done() {
+                    let event = Event::default()
+                        .event("complete")
+                        .json_data(&progress)
+                        .ok()?;
+                    return Some((Ok(event), BatchStreamState::Done));
+                }
+
+                if watched.unannounced {
+                    watched.unannounced = false;
+                    let event = Event::default()
+                        .event("progress")
+                        .json_data(&progress)
+                        .ok()?;
+                    return Some((Ok(event), BatchStreamState::Watching(watched)));
+                }
+
+                if watched.progress.changed().await.is_err() {
+                    // The batch was cleaned up while we were watching it
+                    let event = Event::default()
+                        .event("expired")
+                        .json_data(&progress)
+                        .ok()?;
+                    return Some((Ok(event), BatchStreamState::Done));
+                }
+            }
+        }
+    });
 
-    Ok(Json(ExecuteResponse { job_id }))
+    Sse::new(stream).keep_alive(
+        axum::response::sse::KeepAlive::new()
+            .interval(Duration::from_secs(1))
+            .text("keep-alive"),
+    )
+}
+
+fn rate_limit_headers(status: RateLimitStatus) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("x-ratelimit-limit", status.limit.into());
+    headers.insert("x-ratelimit-remaining", status.remaining.into());
+    headers.insert("x-ratelimit-reset", ceil_secs(status.reset).into());
+    headers
+}
+
+// Whole seconds for headers like `Retry-After`, rounded up so clients don't retry too early
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+// How long a client may wait on a job's stream before we give up on it
+const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct WatchedJob {
+    status: watch::Receiver<JobStatus>,
+    queue_moved: watch::Receiver<()>,
+    output: watch::Receiver<JobOutput>,
+    // How much of stdout and stderr was sent, in bytes of the output and of the text sent for it
+    sent: [usize; 2],
+    offset: [usize; 2],
+    deadline: Instant,
+    last_state: Option<JobState>,
 }
 
 enum StreamState {
-    Pending(u32),
+    Start,
+    Watching(WatchedJob),
     Done,
 }
+
+fn state_event(status: &JobStatus) -> Option<Event> {
+    Event::default()
+        .event(status.state.as_str())
+        .json_data(status)
+        .ok()
+}
+
+// The output the job printed since it was last sent, if any. A character split across reads
+// is held back until the rest of it arrives. Invalid UTF-8 is replaced the way the job's result
+// replaces it, so offsets count bytes of that text rather than of the raw output.
+fn output_event(watched: &mut WatchedJob) -> Option<OutputEvent> {
+    let output = watched.output.borrow_and_update();
+    for ((stream, sent), offset) in [OutputStream::Stdout, OutputStream::Stderr]
+        .into_iter()
+        .zip(&mut watched.sent)
+        .zip(&mut watched.offset)
+    {
+        let Some(new) = output
+            .get(stream)
+            .get(*sent..)
+            .filter(|new| !new.is_empty())
+        else {
+            continue;
+        };
+        let mut data = String::new();
+        let mut len = 0;
+        for chunk in new.utf8_chunks() {
+            data.push_str(chunk.valid());
+            len += chunk.valid().len();
+            let invalid = chunk.invalid();
+            let incomplete = len + invalid.len() == new.len()
+                && std::str::from_utf8(invalid).is_err_and(|e| e.error_len().is_none());
+            if !invalid.is_empty() && !incomplete {
+                data.push(char::REPLACEMENT_CHARACTER);
+                len += invalid.len();
+            }
+        }
+        if len == 0 {
+            continue;
+        }
+        let event = OutputEvent {
+            stream,
+            offset: *offset,
+            data,
+        };
+        *sent += len;
+        *offset += event.data.len();
+        return Some(event);
+    }
+    None
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/stream/{job_id}",
+    tag = "jobs",
+    description = "Streams a job's progress as server-sent events, whose data is JSON:\n\n\
+                   - `queued`, `compiling`, `linking`, `running`: the job's `JobStatus`, as it \
+                   enters each state\n\
+                   - `pending`: a `PendingEvent` with the job's place in the queue, whenever it \
+                   moves\n\
+                   - `output`: an `OutputEvent` with what the program printed, while it runs. \
+                   Jobs run by remote workers only send their output with `complete`\n\
+                   - `complete`: the `JobResult`, once the job finished\n\
+                   - `cancelled`, `expired`: the final `JobStatus` of a job that ended without a \
+                   result\n\
+                   - `gone`: the final `JobStatus` of a job whose result was discarded after \
+                   being kept for a while\n\
+                   - `timeout`: a `StreamError`, if the job didn't finish within a minute\n\
+                   - `not_found`: a `StreamError`, if the job is unknown",
+    params(("job_id" = Uuid, Path, description = "The job's ID")),
+    responses(
+        (status = 200, description = "The event stream", content_type = "text/event-stream", body = String),
+    )
+)]
 pub async fn stream_results(
     Path(job_id): Path<Uuid>,
     State(state): State<AppState>,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    let results = state.results.clone();
+    let jobs = state.jobs.clone();
+    let scheduler = state.work_queue.clone();
 
-    let stream = stream::unfold(StreamState::Pending(0), move |count| {
-        let results = results.clone();
+    let stream = stream::unfold(StreamState::Start, move |stream_state| {
+        let jobs = jobs.clone();
+        let scheduler = scheduler.clone();
+        let state = state.clone();
         async move {
-            match count {
-                StreamState::Done => None,
-                StreamState::Pending(count) => {
-                    // Timeout after 60 seconds
-                    // We check every 500ms
-                    if count >= 120 {
+            let mut watched = match stream_state {
+                StreamState::Done => return None,
+                StreamState::Watching(watched) => watched,
+                StreamState::Start => {
+                    let Some(status) = jobs::subscribe(&jobs, job_id).await else {
                         let event = Event::default()
-                            .event("timeout")
-                            .json_data(serde_json::json!({
-                                "error": "Timed out waiting for results."
-                            }))
+                            .event("not_found")
+                            .json_data(StreamError {
+                                error: "Unknown job ID.".to_string(),
+                            })
                             .ok()?;
 
                         return Some((Ok(event), StreamState::Done));
+                    };
+
+                    let mut queue_moved = jobs.queue_moved.subscribe();
+                    // Report the initial queue position straight away
+                    queue_moved.mark_changed();
+                    // Cleaned up in the meantime, the closed channel never reports any output
+                    let output = jobs::subscribe_output(&jobs, job_id)
+                        .await
+                        .unwrap_or_else(|| watch::channel(JobOutput::default()).1);
+
+                    WatchedJob {
+                        status,
+                        queue_moved,
+                        output,
+                        sent: [0; 2],
+                        offset: [0; 2],
+                        deadline: Instant::now() + STREAM_TIMEOUT,
+                        last_state: None,
                     }
+                }
+            };
 
-                    // Check for result
-                    let result = results.lock().await.get(&job_id).cloned();
+            loop {
+                let status = watched.status.borrow_and_update().clone();
 
-                    if let Some(result) = result {
+                if status.state.is_terminal() {
+                    let event = match jobs::result(&jobs, job_id).await {
                         // Send complete event and end stream
-                        let event = Event::default().event("complete").json_data(&result).ok()?;
-                        return Some((Ok(event), StreamState::Done));
-                    }
+                        Some(result) => {
+                            Event::default().event("complete").json_data(&result).ok()?
+                        }
+                        // Finished a while ago, the result was discarded since
+                        None if matches!(status.state, JobState::Completed | JobState::Failed) => {
+                            Event::default().event("gone").json_data(&status).ok()?
+                        }
+                        // Cancelled or expired, there is no result to send
+                        None => state_event(&status)?,
+                    };
+                    return Some((Ok(event), StreamState::Done));
+                }
 
-                    // Still pending
-                    tokio::time::sleep(Duration::from_millis(500)).await;
+                if watched.last_state != Some(status.state) {
+                    // Announce the new state right away
+                    watched.last_state = Some(status.state);
+                    let event = state_event(&status)?;
+                    return Some((Ok(event), StreamState::Watching(watched)));
+                }
 
-                    let event = Event::default().event("pending").data("running");
+                if let Some(output) = output_event(&mut watched) {
+                    let event = Event::default().event("output").json_data(&output).ok()?;
+                    return Some((Ok(event), StreamState::Watching(watched)));
+                }
+
+                tokio::select! {
+                    changed = watched.status.changed() => {
+                        if changed.is_err() {
+                            // The job was cleaned up while we were watching it
+                            let event = Event::default()
+                                .event("gone")
+                                .json_data(&status)
+                                .ok()?;
+                            return Some((Ok(event), StreamState::Done));
+                        }
+                    }
+                    Ok(()) = watched.output.changed() => {}
+                    Ok(()) = watched.queue_moved.changed(), if status.state == JobState::Queued => {
+                        let queue = jobs::queue_position(&jobs, &scheduler, job_id, worker_capacity(&state))
+                            .await;
+                        let pending = PendingEvent {
+                            state: status.state,
+                            position: queue.as_ref().map(|queue| queue.position),
+                            estimated_start: queue.map(|queue| queue.estimated_start),
+                        };
+                        let event = Event::default().event("pending").json_data(&pending).ok()?;
+                        return Some((Ok(event), StreamState::Watching(watched)));
+                    }
+                    _ = tokio::time::sleep_until(watched.deadline) => {
+                        let event = Event::default()
+                            .event("timeout")
+                            .json_data(StreamError {
+                                error: "Timed out waiting for results.".to_string(),
+                            })
+                            .ok()?;
 
-                    Some((Ok(event), StreamState::Pending(count + 1)))
+                        return Some((Ok(event), StreamState::Done));
+                    }
                 }
             }
         }
@@ -90,36 +750,784 @@ pub async fn stream_results(
     )
 }
 
-pub async fn get_results(
+#[utoipa::path(
+    get,
+    path = "/api/v1/results/{job_id}",
+    tag = "jobs",
+    params(("job_id" = Uuid, Path, description = "The job's ID")),
+    responses(
+        (status = 200, description = "The job's result", body = JobResult),
+        (status = 202, description = "The job hasn't finished yet", body = JobStatus),
+        (status = 404, description = "Unknown job", body = ErrorResponse),
+        (status = 410, description = "The job was cancelled or its result expired", body = ErrorResponse),
+    )
+)]
+pub async fn get_results(Path(job_id): Path<Uuid>, State(state): State<AppState>) -> Response {
+    let entries = state.jobs.entries.lock().await;
+    let Some(entry) = entries.get(&job_id) else {
+        return unknown_job().into_response();
+    };
+
+    let status = entry.status.borrow();
+    match &entry.result {
+        Some(result) => Json(result).into_response(),
+        // Cancelled or expired, the result will never be available
+        None if status.state.is_terminal() => result_gone().into_response(),
+        // Still queued or in progress
+        None => (StatusCode::ACCEPTED, Json(&*status)).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/jobs/{job_id}",
+    tag = "jobs",
+    params(("job_id" = Uuid, Path, description = "The job's ID")),
+    responses(
+        (status = 200, description = "The job's status", body = JobStatus),
+        (status = 404, description = "Unknown job", body = ErrorResponse),
+    )
+)]
+pub async fn get_job(
     Path(job_id): Path<Uuid>,
     State(state): State<AppState>,
-) -> Result<Json<JobResult>, StatusCode> {
-    state
-        .results
-        .lock()
+) -> Result<Json<JobStatus>, ApiError> {
+    jobs::status(&state.jobs, job_id)
         .await
-        .get(&job_id)
-        .cloned()
-        .ok_or(StatusCode::NOT_FOUND)
+        .ok_or_else(unknown_job)
         .map(Json)
 }
 
-// memoized version of getting the version from the zrc binary
-pub async fn get_version() -> Json<serde_json::Value> {
-    use once_cell::sync::OnceCell;
-    static VERSION: OnceCell<String> = OnceCell::new();
+#[utoipa::path(
+    delete,
+    path = "/api/v1/jobs/{job_id}",
+    tag = "jobs",
+    description = "Cancels a job that hasn't started yet.",
+    params(("job_id" = Uuid, Path, description = "The job's ID")),
+    responses(
+        (status = 200, description = "The job was cancelled", body = JobStatus),
+        (status = 404, description = "Unknown job", body = ErrorResponse),
+        (status = 409, description = "The job has already started", body = ErrorResponse),
+    )
+)]
+pub async fn cancel_job(
+    Path(job_id): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Result<Json<JobStatus>, ApiError> {
+    if !jobs::cancel(&state.jobs, job_id).await {
+        // Either unknown, or already picked up by a worker
+        return Err(match jobs::status(&state.jobs, job_id).await {
+            Some(_) => ApiError::new(
+                ErrorCode::Conflict,
+                "The job has already started and can't be cancelled.",
+            ),
+            None => unknown_job(),
+        });
+    }
 
-    let version = VERSION.get_or_init(|| {
-        let output = std::process::Command::new("./zrc-nightly/bin/zrc")
-            .arg("--version")
-            .output()
-            .expect("Failed to execute zrc binary");
+    let status = jobs::status(&state.jobs, job_id)
+        .await
+        .ok_or_else(unknown_job)?;
+    state.metrics.job_finished(status.task, JobState::Cancelled);
+    Ok(Json(status))
+}
 
-        String::from_utf8_lossy(&output.stdout)
-            .trim()
-            .to_string()
-            .replace("zrc_cli", "Zirco")
-    });
+fn database_error(e: rusqlite::Error) -> ApiError {
+    error!("Database error: {e}");
+    ApiError::internal()
+}
+
+fn unknown_snippet() -> ApiError {
+    ApiError::not_found("Unknown snippet or revision.")
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/snippets",
+    tag = "snippets",
+    request_body = CreateSnippetRequest,
+    responses(
+        (status = 200, description = "The stored snippet", body = Snippet),
+        (status = 413, description = "The snippet is too large", body = ErrorResponse),
+    )
+)]
+pub async fn create_snippet(
+    State(state): State<AppState>,
+    Json(req): Json<CreateSnippetRequest>,
+) -> Result<Json<Snippet>, ApiError> {
+    if let Err(e) = snippets::validate(&req) {
+        debug!("Rejecting snippet: {e}");
+        return Err(ApiError::new(ErrorCode::PayloadTooLarge, e));
+    }
+
+    let snippet = with_db(&state.db, move |conn| snippets::insert(conn, req))
+        .await
+        .map_err(database_error)?;
+
+    debug!("Stored snippet {}", snippet.id);
+
+    Ok(Json(snippet))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/snippets/{id}",
+    tag = "snippets",
+    params(("id" = String, Path, description = "The snippet's ID"), RevisionQuery),
+    responses(
+        (status = 200, description = "The snippet, at the latest or the given revision", body = Snippet),
+        (status = 404, description = "Unknown snippet or revision", body = ErrorResponse),
+    )
+)]
+pub async fn get_snippet(
+    Path(id): Path<String>,
+    Query(query): Query<RevisionQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<Snippet>, ApiError> {
+    with_db(&state.db, move |conn| {
+        snippets::get(conn, &id, query.revision)
+    })
+    .await
+    .map_err(database_error)?
+    .ok_or_else(unknown_snippet)
+    .map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/snippets/{id}/revisions",
+    tag = "snippets",
+    params(("id" = String, Path, description = "The snippet's ID")),
+    request_body = SaveRevisionRequest,
+    responses(
+        (status = 200, description = "The new revision", body = Snippet),
+        (status = 404, description = "Unknown snippet or parent revision", body = ErrorResponse),
+        (status = 413, description = "The snippet is too large", body = ErrorResponse),
+    )
+)]
+pub async fn save_snippet_revision(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Json(req): Json<SaveRevisionRequest>,
+) -> Result<Json<Snippet>, ApiError> {
+    if let Err(e) = snippets::validate(&req.snippet) {
+        debug!("Rejecting snippet revision: {e}");
+        return Err(ApiError::new(ErrorCode::PayloadTooLarge, e));
+    }
+
+    let snippet = with_db(&state.db, move |conn| {
+        snippets::save_revision(conn, &id, req.parent, req.snippet)
+    })
+    .await
+    .map_err(database_error)?
+    .ok_or_else(unknown_snippet)?;
+
+    debug!(
+        "Stored snippet {} revision {}",
+        snippet.id, snippet.revision
+    );
+
+    Ok(Json(snippet))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/snippets/{id}/revisions",
+    tag = "snippets",
+    params(("id" = String, Path, description = "The snippet's ID")),
+    responses(
+        (status = 200, description = "The snippet's revisions", body = Vec<RevisionSummary>),
+        (status = 404, description = "Unknown snippet", body = ErrorResponse),
+    )
+)]
+pub async fn list_snippet_revisions(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<RevisionSummary>>, ApiError> {
+    with_db(&state.db, move |conn| snippets::list_revisions(conn, &id))
+        .await
+        .map_err(database_error)?
+        .ok_or_else(unknown_snippet)
+        .map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/snippets/{id}/diff",
+    tag = "snippets",
+    params(("id" = String, Path, description = "The snippet's ID"), DiffQuery),
+    responses(
+        (status = 200, description = "A unified diff between two revisions", body = SnippetDiff),
+        (status = 404, description = "Unknown snippet or revision", body = ErrorResponse),
+    )
+)]
+pub async fn diff_snippet_revisions(
+    Path(id): Path<String>,
+    Query(query): Query<DiffQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<SnippetDiff>, ApiError> {
+    let (from, to) = with_db(&state.db, move |conn| {
+        Ok((
+            snippets::get(conn, &id, Some(query.from))?,
+            snippets::get(conn, &id, Some(query.to))?,
+        ))
+    })
+    .await
+    .map_err(database_error)?;
+
+    let (Some(from), Some(to)) = (from, to) else {
+        return Err(unknown_snippet());
+    };
+
+    Ok(Json(SnippetDiff {
+        from: from.revision,
+        to: to.revision,
+        diff: snippets::diff(&from, &to),
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/snippets/{id}/fork",
+    tag = "snippets",
+    params(("id" = String, Path, description = "The snippet's ID"), RevisionQuery),
+    responses(
+        (status = 200, description = "The new snippet", body = Snippet),
+        (status = 404, description = "Unknown snippet or revision", body = ErrorResponse),
+    )
+)]
+pub async fn fork_snippet(
+    Path(id): Path<String>,
+    Query(query): Query<RevisionQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<Snippet>, ApiError> {
+    let snippet = with_db(&state.db, move |conn| {
+        snippets::fork(conn, &id, query.revision)
+    })
+    .await
+    .map_err(database_error)?
+    .ok_or_else(unknown_snippet)?;
+
+    debug!("Forked snippet into {}", snippet.id);
+
+    Ok(Json(snippet))
+}
+
+// Serves metrics in the Prometheus text format.
+pub async fn get_metrics(State(state): State<AppState>) -> Result<Response, ApiError> {
+    let metrics = &state.metrics;
+
+    let (queued, batch_queued) = state.work_queue.lane_lens();
+    let lane = |lane| metrics.queued_jobs.get_or_create(&LaneLabels { lane });
+    lane("interactive").set(queued as i64);
+    lane("batch").set(batch_queued as i64);
+
+    let (stored, results, result_bytes) = jobs::store_stats(&state.jobs).await;
+    metrics.stored_jobs.set(stored as i64);
+    metrics.stored_results.set(results as i64);
+    metrics.stored_result_bytes.set(result_bytes as i64);
+
+    let (remote_workers, _) = state.remote_workers.capacity();
+    metrics.remote_workers.set(remote_workers as i64);
+    metrics
+        .remote_jobs
+        .set(state.remote_workers.leased() as i64);
+
+    let body = metrics.encode().map_err(|e| {
+        error!("Failed to encode metrics: {e}");
+        ApiError::internal()
+    })?;
+
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        body,
+    )
+        .into_response())
+}
+
+// Liveness: answering at all means the process is up.
+pub async fn healthz() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+// Readiness: whether this instance can actually run jobs right now.
+pub async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let mut readiness =
+        health::readiness(&state.readiness, &state.config.get(), &state.metrics).await;
+    // Tell load balancers to stop sending traffic while we drain
+    if state.shutdown.is_started() {
+        readiness.ready = false;
+        readiness.checks.push(ReadinessCheck {
+            name: "shutdown",
+            ok: false,
+            error: Some("The server is shutting down".to_string()),
+        });
+    }
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+// memoized version of getting the version from the zrc binary, for the toolchain currently
+// configured
+#[utoipa::path(
+    get,
+    path = "/api/v1/version",
+    tag = "toolchain",
+    responses(
+        (status = 200, description = "The version of the Zirco toolchain jobs run with", body = VersionResponse),
+    )
+)]
+pub async fn get_version(State(state): State<AppState>) -> Result<Json<VersionResponse>, ApiError> {
+    // Keyed by the binary's path, so a reload that switches toolchains asks the new one. The
+    // lock is held while asking, so concurrent requests wait for one answer.
+    static VERSION: tokio::sync::Mutex<Option<(String, String)>> =
+        tokio::sync::Mutex::const_new(None);
+
+    let zrc = state.config.get().paths.toolchain_bin("zrc");
+    let mut cached = VERSION.lock().await;
+    let version = match &*cached {
+        Some((path, version)) if *path == zrc => {
+            state.metrics.cache_lookup("version", true);
+            version.clone()
+        }
+        _ => {
+            state.metrics.cache_lookup("version", false);
+            let output = tokio::process::Command::new(&zrc)
+                .arg("--version")
+                .output()
+                .await
+                .map_err(|e| {
+                    error!("Failed to execute {zrc}: {e}");
+                    ApiError::internal()
+                })?;
+
+            let version = String::from_utf8_lossy(&output.stdout)
+                .trim()
+                .to_string()
+                .replace("zrc_cli", "Zirco");
+            *cached = Some((zrc, version.clone()));
+            version
+        }
+    };
+
+    Ok(Json(VersionResponse { version }))
+}
+
+// The OpenAPI specification of the public API, which `/api/v1/docs` displays.
+pub async fn get_openapi() -> Json<&'static utoipa::openapi::OpenApi> {
+    Json(openapi::spec())
+}
+
+pub async fn reload_config(
+    State(state): State<AppState>,
+    admin: Admin,
+) -> Result<Json<ReloadResponse>, ApiError> {
+    info!("{} requested a configuration reload", admin.name);
+    reload::reload(&state)
+        .await
+        .map(|restart_required| Json(ReloadResponse { restart_required }))
+        .map_err(|e| {
+            error!("Failed to reload configuration: {e}");
+            ApiError::new(ErrorCode::InvalidConfig, e)
+        })
+}
+
+pub async fn list_jobs(
+    State(state): State<AppState>,
+    _: Admin,
+    Query(query): Query<JobsQuery>,
+) -> Json<Vec<JobDetails>> {
+    Json(jobs::list(&state.jobs, query.state).await)
+}
+
+pub async fn inspect_job(
+    State(state): State<AppState>,
+    _: Admin,
+    Path(job_id): Path<Uuid>,
+) -> Result<Json<JobDetails>, ApiError> {
+    jobs::inspect(&state.jobs, job_id)
+        .await
+        .ok_or_else(unknown_job)
+        .map(Json)
+}
+
+// Cancels a job, unlike clients even once it is running.
+pub async fn stop_job(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path(job_id): Path<Uuid>,
+) -> Result<Json<JobStatus>, ApiError> {
+    if !jobs::stop(&state.jobs, job_id).await {
+        return Err(match jobs::status(&state.jobs, job_id).await {
+            Some(_) => ApiError::new(ErrorCode::Conflict, "The job has already finished."),
+            None => unknown_job(),
+        });
+    }
+    // A remote worker running it is told to stop with its next heartbeat
+    state.remote_workers.abandon(job_id);
+    info!("{} cancelled job {job_id}", admin.name);
+
+    let status = jobs::status(&state.jobs, job_id)
+        .await
+        .ok_or_else(unknown_job)?;
+    state.metrics.job_finished(status.task, JobState::Cancelled);
+    Ok(Json(status))
+}
+
+fn queue_status(state: &AppState) -> Json<QueueStatus> {
+    let (queued, batch_queued) = state.work_queue.lane_lens();
+    Json(QueueStatus {
+        paused: state.work_queue.is_paused(),
+        queued,
+        batch_queued,
+    })
+}
+
+pub async fn get_queue(State(state): State<AppState>, _: Admin) -> Json<QueueStatus> {
+    queue_status(&state)
+}
+
+// Stops workers from picking up queued jobs. Jobs are still accepted, and the ones already
+// running finish.
+pub async fn pause_queue(State(state): State<AppState>, admin: Admin) -> Json<QueueStatus> {
+    info!("{} paused the queue", admin.name);
+    state.work_queue.set_paused(true);
+    queue_status(&state)
+}
+
+pub async fn resume_queue(State(state): State<AppState>, admin: Admin) -> Json<QueueStatus> {
+    info!("{} resumed the queue", admin.name);
+    state.work_queue.set_paused(false);
+    queue_status(&state)
+}
+
+pub async fn list_bans(State(state): State<AppState>, _: Admin) -> Json<Vec<Ban>> {
+    Json(state.bans.list())
+}
+
+// Bans a client, cancelling the jobs it still has queued.
+pub async fn ban_client(
+    State(state): State<AppState>,
+    admin: Admin,
+    Json(req): Json<Ban>,
+) -> Result<Json<Ban>, ApiError> {
+    if req.client.trim().is_empty() {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            "No client to ban was given.",
+        ));
+    }
+    let ban = with_db(&state.db, move |conn| bans::insert(conn, req))
+        .await
+        .map_err(database_error)?;
+    state.bans.add(ban.clone());
+
+    let queued = jobs::queued_by(&state.jobs, &ban.client).await;
+    let mut cancelled = 0;
+    for id in queued {
+        if jobs::cancel(&state.jobs, id).await
+            && let Some(status) = jobs::status(&state.jobs, id).await
+        {
+            state.metrics.job_finished(status.task, JobState::Cancelled);
+            cancelled += 1;
+        }
+    }
+    info!(
+        "{} banned {}, cancelling {cancelled} queued jobs",
+        admin.name, ban.client
+    );
+    Ok(Json(ban))
+}
+
+pub async fn unban_client(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path(client): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    if !state.bans.remove(&client) {
+        return Err(ApiError::not_found("This client isn't banned."));
+    }
+    info!("{} lifted the ban on {client}", admin.name);
+    with_db(&state.db, move |conn| bans::delete(conn, &client))
+        .await
+        .map_err(database_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_workers(State(state): State<AppState>, _: Admin) -> Json<WorkersStatus> {
+    let config = state.config.get();
+    let jobs = jobs::list(&state.jobs, None)
+        .await
+        .into_iter()
+        .filter(|job| job.status.node.is_none() && job.status.state != JobState::Queued)
+        .filter_map(|job| Some((job.status.worker?, job.status.id)))
+        .collect();
+
+    Json(WorkersStatus {
+        local: LocalWorkersStatus {
+            count: state.workers.count(),
+            min: config.workers.min,
+            max: config.workers.max,
+            busy: state.metrics.busy_workers.get().max(0) as usize,
+            jobs,
+        },
+        remote: state.remote_workers.list(),
+    })
+}
+
+pub async fn register_worker(
+    State(state): State<AppState>,
+    _: WorkerToken,
+    Json(req): Json<RegisterWorkerRequest>,
+) -> Result<Json<RegisterWorkerResponse>, ApiError> {
+    if req.slots == 0 {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            "A worker needs at least one slot.",
+        ));
+    }
+    let worker_id = state.remote_workers.register(req.name, req.slots);
+    Ok(Json(RegisterWorkerResponse {
+        worker_id,
+        lease_secs: state.config.get().remote.lease_secs,
+    }))
+}
+
+pub async fn deregister_worker(
+    State(state): State<AppState>,
+    _: WorkerToken,
+    Path(worker_id): Path<usize>,
+) -> Result<StatusCode, ApiError> {
+    remote::deregister(&state, worker_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn worker_heartbeat(
+    State(state): State<AppState>,
+    _: WorkerToken,
+    Path(worker_id): Path<usize>,
+    Json(req): Json<WorkerHeartbeat>,
+) -> Result<Json<HeartbeatResponse>, ApiError> {
+    let lease = state.config.get().remote.lease();
+    state
+        .remote_workers
+        .heartbeat(worker_id, &req.jobs, lease)
+        .map(|abandoned| Json(HeartbeatResponse { abandoned }))
+        .ok_or_else(|| ApiError::not_found("Unknown worker ID, register again."))
+}
+
+// Hands the worker the next job, waiting a while for one. Answers 204 if none came up.
+pub async fn pull_job(
+    State(state): State<AppState>,
+    _: WorkerToken,
+    Path(worker_id): Path<usize>,
+) -> Result<Response, ApiError> {
+    Ok(match remote::next_job(&state, worker_id).await? {
+        Some(job) => Json(job).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    })
+}
+
+pub async fn report_job_stage(
+    State(state): State<AppState>,
+    _: WorkerToken,
+    Path((worker_id, job_id)): Path<(usize, Uuid)>,
+    Json(req): Json<StageReport>,
+) -> Result<StatusCode, ApiError> {
+    remote::report_stage(&state, worker_id, job_id, req.state).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn submit_job_result(
+    State(state): State<AppState>,
+    _: WorkerToken,
+    Path((worker_id, job_id)): Path<(usize, Uuid)>,
+    Json(req): Json<RemoteResult>,
+) -> Result<StatusCode, ApiError> {
+    remote::finish_job(&state, worker_id, job_id, req).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Answers requests under /api that don't match any endpoint, which would otherwise go to the
+// static files.
+pub async fn unknown_endpoint() -> ApiError {
+    ApiError::not_found("No such API endpoint.")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{api_keys::Tier, config::Config};
+
+    // A server whose queue holds a single job.
+    fn state() -> AppState {
+        let mut config = Config::default();
+        config.queue.depth = 1;
+        AppState::for_tests(config)
+    }
+
+    fn client() -> Client {
+        Client {
+            key: "client".to_string(),
+            // Slow enough that nothing refills while the test runs
+            tier: Arc::new(Tier {
+                burst: 3,
+                requests_per_second: 0.001,
+                ..Tier::default()
+            }),
+        }
+    }
+
+    fn request() -> ExecuteRequest {
+        ExecuteRequest {
+            task: TaskType::Execute,
+            code: String::new(),
+        }
+    }
+
+    fn tokens_left(state: &AppState, client: &Client) -> u32 {
+        let status = state.rate_limiter.check(&client.key, &client.tier, 0.0);
+        status.unwrap().remaining
+    }
+
+    // Makes storing jobs fail, by taking away the durable queue's table.
+    fn break_store(state: &mut AppState) {
+        let db = crate::db::open(":memory:").unwrap();
+        db.lock()
+            .unwrap()
+            .execute("DROP TABLE queued_jobs", [])
+            .unwrap();
+        state.jobs = jobs::new(Some(db));
+    }
+
+    #[tokio::test]
+    async fn batches_that_are_turned_away_are_not_charged() {
+        let mut config = Config::default();
+        config.queue.batch_depth = 4;
+        let mut state = AppState::for_tests(config);
+        let submit = |state: &AppState, client: &Client| {
+            let batch = BatchRequest {
+                jobs: (0..4).map(|_| request()).collect(),
+            };
+            submit_batch(State(state.clone()), client.clone(), Json(batch))
+        };
+
+        // Four jobs cost more than the bucket holds, leaving the client in debt
+        let first = client();
+        submit(&state, &first).await.unwrap();
+        let limited = state.rate_limiter.check(&first.key, &first.tier, 0.0);
+        assert_eq!(limited.unwrap_err().status.remaining, 0);
+
+        let second = Client {
+            key: "second".to_string(),
+            ..client()
+        };
+        let full = submit(&state, &second).await;
+        let status = full.unwrap_err().into_response().status();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(tokens_left(&state, &second), 3);
+
+        break_store(&mut state);
+        let failed = submit(&state, &second).await;
+        let status = failed.unwrap_err().into_response().status();
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(tokens_left(&state, &second), 3);
+    }
+
+    #[tokio::test]
+    async fn jobs_that_are_turned_away_are_refunded() {
+        let mut state = state();
+        let client = client();
+        submit_job(&state, client.clone(), request()).await.unwrap();
+        assert_eq!(tokens_left(&state, &client), 2);
+
+        let full = submit_job(&state, client.clone(), request()).await;
+        let status = full.unwrap_err().into_response().status();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(tokens_left(&state, &client), 2);
+
+        break_store(&mut state);
+        let failed = submit_job(&state, client.clone(), request()).await;
+        let status = failed.unwrap_err().into_response().status();
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(tokens_left(&state, &client), 2);
+    }
+
+    fn watch_output(output: &watch::Sender<JobOutput>) -> WatchedJob {
+        let (_, status) = watch::channel(JobStatus {
+            id: Uuid::nil(),
+            task: TaskType::Execute,
+            state: JobState::Running,
+            worker: None,
+            node: None,
+            history: Vec::new(),
+        });
+        WatchedJob {
+            status,
+            queue_moved: watch::channel(()).1,
+            output: output.subscribe(),
+            sent: [0; 2],
+            offset: [0; 2],
+            deadline: Instant::now(),
+            last_state: None,
+        }
+    }
+
+    #[test]
+    fn output_events_hold_back_split_characters() {
+        let (output, _) = watch::channel(JobOutput::default());
+        let mut watched = watch_output(&output);
+
+        // The first byte of "é"
+        output.send_modify(|o| o.stdout.extend_from_slice(&[b'a', 0xc3]));
+        let event = output_event(&mut watched).unwrap();
+        assert_eq!((event.offset, event.data.as_str()), (0, "a"));
+        assert!(output_event(&mut watched).is_none());
+
+        output.send_modify(|o| {
+            o.stdout.push(0xa9);
+            o.stderr.extend_from_slice(b"oops");
+        });
+        let event = output_event(&mut watched).unwrap();
+        assert_eq!(event.stream, OutputStream::Stdout);
+        assert_eq!((event.offset, event.data.as_str()), (1, "é"));
+        let event = output_event(&mut watched).unwrap();
+        assert_eq!(event.stream, OutputStream::Stderr);
+        assert_eq!((event.offset, event.data.as_str()), (0, "oops"));
+        assert!(output_event(&mut watched).is_none());
+    }
+
+    #[test]
+    fn output_event_offsets_match_the_result_after_invalid_bytes() {
+        let (output, _) = watch::channel(JobOutput::default());
+        let mut watched = watch_output(&output);
+
+        // An invalid byte, then what could still become "€"
+        output.send_modify(|o| o.stdout.extend_from_slice(&[b'a', 0xff, b'b', 0xe2, 0x82]));
+        let event = output_event(&mut watched).unwrap();
+        assert_eq!((event.offset, event.data.as_str()), (0, "a\u{fffd}b"));
+        assert!(output_event(&mut watched).is_none());
+
+        // But doesn't
+        output.send_modify(|o| o.stdout.push(b'c'));
+        let event = output_event(&mut watched).unwrap();
+        assert_eq!((event.offset, event.data.as_str()), (5, "\u{fffd}c"));
+        assert!(output_event(&mut watched).is_none());
 
-    Json(serde_json::json!({ "version": version }))
+        // The job's result has the same text, and streaming it again sends it all at once
+        let result = String::from_utf8_lossy(&output.borrow().stdout).into_owned();
+        assert_eq!(result, "a\u{fffd}b\u{fffd}c");
+        assert_eq!(&result[event.offset..], event.data);
+        let event = output_event(&mut watch_output(&output)).unwrap();
+        assert_eq!((event.offset, event.data), (0, result));
+    }
 }
@@ -0,0 +1,204 @@
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::{
+    api_keys::Tier,
+    config::Config,
+    metrics::Metrics,
+    models::{Readiness, ReadinessCheck},
+    sandbox,
+};
+
+// How long a readiness result is reused before the checks run again. The canary compiles and
+// runs a program, which is too much to do on every probe.
+const READINESS_TTL: Duration = Duration::from_secs(15);
+
+const CANARY_CODE: &str = "fn main() -> i32 { return 0; }\n";
+
+pub type ReadinessCache = Arc<Mutex<Option<(Instant, Readiness)>>>;
+
+pub fn new_cache() -> ReadinessCache {
+    Arc::new(Mutex::new(None))
+}
+
+fn is_executable(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+}
+
+fn check(name: &'static str, result: Result<(), String>) -> ReadinessCheck {
+    ReadinessCheck {
+        name,
+        ok: result.is_ok(),
+        error: result.err(),
+    }
+}
+
+fn check_file(path: &str, executable: bool) -> Result<(), String> {
+    let path = Path::new(path);
+    if !path.is_file() {
+        return Err(format!("{} does not exist", path.display()));
+    }
+    if executable && !is_executable(path) {
+        return Err(format!("{} is not executable", path.display()));
+    }
+    Ok(())
+}
+
+fn check_command(name: &str) -> Result<(), String> {
+    find_in_path(name)
+        .map(|_| ())
+        .ok_or_else(|| format!("{name} was not found in PATH"))
+}
+
+async fn check_work_dir(work_dir: &str) -> Result<(), String> {
+    let probe = format!("{work_dir}/.ready-{}", Uuid::new_v4());
+    tokio::fs::create_dir_all(work_dir)
+        .await
+        .map_err(|e| format!("Failed to create work directory: {e}"))?;
+    tokio::fs::write(&probe, b"")
+        .await
+        .map_err(|e| format!("Work directory is not writable: {e}"))?;
+    let _ = tokio::fs::remove_file(&probe).await;
+    Ok(())
+}
+
+// Compiles, links and runs a trivial program in the sandbox, like an execute job would.
+async fn run_canary(config: &Config) -> Result<(), String> {
+    let work_dir = format!("{}/canary-{}", config.paths.work_dir, Uuid::new_v4());
+    let result = run_canary_in(config, &work_dir).await;
+    // Clean up the work directory after execution
+    let _ = tokio::fs::remove_dir_all(&work_dir).await;
+    result
+}
+
+async fn run_canary_in(config: &Config, work_dir: &str) -> Result<(), String> {
+    let source_path = format!("{work_dir}/main.zr");
+    let obj_path = format!("{work_dir}/main.o");
+    let main_path = format!("{work_dir}/main");
+    let tier = Tier::default();
+    let memory_limit = format!("--as={}", tier.memory_bytes);
+    let mut cpu_time = Duration::ZERO;
+
+    tokio::fs::create_dir_all(work_dir)
+        .await
+        .map_err(|e| format!("Failed to create work directory: {e}"))?;
+    tokio::fs::write(&source_path, CANARY_CODE)
+        .await
+        .map_err(|e| format!("Failed to write source file: {e}"))?;
+
+    if let Some(result) = sandbox::compile(
+        config,
+        &source_path,
+        &obj_path,
+        &memory_limit,
+        tier.max_output_bytes,
+        &mut cpu_time,
+    )
+    .await?
+    {
+        return Err(format!("Compilation failed: {}", result.stderr.trim()));
+    }
+    if let Some(result) = sandbox::link(
+        config,
+        &obj_path,
+        &main_path,
+        &memory_limit,
+        tier.max_output_bytes,
+        &mut cpu_time,
+    )
+    .await?
+    {
+        return Err(format!("Linking failed: {}", result.stderr.trim()));
+    }
+
+    let mut jail = sandbox::nsjail(config, work_dir, &tier, 5, &[])?;
+    match sandbox::run_with_limit(
+        Duration::from_secs(5),
+        &mut jail,
+        tier.max_output_bytes,
+        &sandbox::discard_output,
+        &mut cpu_time,
+    )
+    .await
+    {
+        Ok(Ok(output)) if output.status.success() => Ok(()),
+        Ok(Ok(output)) => Err(format!(
+            "Canary exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+        Ok(Err(e)) => Err(format!("Failed to spawn execution process: {e}")),
+        Err(_) => Err("Canary timed out after 5 seconds".to_string()),
+    }
+}
+
+async fn run_checks(config: &Config) -> Readiness {
+    let paths = &config.paths;
+    let mut checks = vec![
+        check("zrc", check_file(&paths.toolchain_bin("zrc"), true)),
+        check("zircop", check_file(&paths.toolchain_bin("zircop"), true)),
+        check("clang", check_command("clang")),
+        check("nsjail", check_command("nsjail")),
+        check("prlimit", check_command("prlimit")),
+        check("seccomp_policy", check_file(&paths.seccomp_policy, false)),
+        check("work_dir", check_work_dir(&paths.work_dir).await),
+    ];
+
+    // Without everything above, the canary can only fail
+    let canary = if checks.iter().all(|check| check.ok) {
+        run_canary(config).await
+    } else {
+        Err("Skipped because other checks failed".to_string())
+    };
+    checks.push(check("canary", canary));
+
+    let ready = checks.iter().all(|check| check.ok);
+    if !ready {
+        let failed: Vec<_> = checks.iter().filter(|check| !check.ok).collect();
+        warn!("Readiness checks failed: {failed:?}");
+    }
+
+    Readiness {
+        ready,
+        checks,
+        checked_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0),
+    }
+}
+
+// Returns the latest readiness result, running the checks again if it is stale. Concurrent
+// probes wait for a single run of the checks.
+pub async fn readiness(cache: &ReadinessCache, config: &Config, metrics: &Metrics) -> Readiness {
+    let mut cached = cache.lock().await;
+    if let Some((at, readiness)) = &*cached
+        && at.elapsed() < READINESS_TTL
+    {
+        metrics.cache_lookup("readiness", true);
+        return readiness.clone();
+    }
+    metrics.cache_lookup("readiness", false);
+
+    debug!("Running readiness checks");
+    let readiness = run_checks(config).await;
+    *cached = Some((Instant::now(), readiness.clone()));
+    readiness
+}
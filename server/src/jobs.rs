@@ -0,0 +1,596 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::{Mutex, watch};
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+use crate::{
+    batches,
+    db::{Db, with_db},
+    metrics::Metrics,
+    models::{
+        Batches, Job, JobDetails, JobEntry, JobLoad, JobOutput, JobResult, JobState, JobStatus,
+        Jobs, QueuePosition, StateTransition,
+    },
+    queue_store::{self, StoredJob},
+    scheduler::Scheduler,
+};
+
+// How long results are kept after a job finishes, and how long the job record is kept around
+// after that so clients can still see what happened to it.
+const RESULT_TTL: Duration = Duration::from_mins(5);
+const EXPIRED_TTL: Duration = Duration::from_mins(5);
+
+// Assumed job duration for queue estimates when no jobs have finished recently.
+const DEFAULT_JOB_DURATION_MS: u64 = 2000;
+
+// How many of the most recently finished jobs queue estimates are based on
+const RECENT_JOBS: usize = 100;
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn is_active(state: JobState) -> bool {
+    matches!(
+        state,
+        JobState::Compiling | JobState::Linking | JobState::Running
+    )
+}
+
+fn transition(jobs: &Jobs, entry: &JobEntry, state: JobState) {
+    let mut previous = state;
+    let mut processing_ms = None;
+    entry.status.send_modify(|status| {
+        previous = status.state;
+        status.state = state;
+        status.history.push(StateTransition {
+            state,
+            at: now_ms(),
+        });
+        processing_ms = processing_time_ms(status);
+    });
+
+    let mut load = jobs.load.lock().unwrap_or_else(|e| e.into_inner());
+    if is_active(state) && !is_active(previous) {
+        load.running += 1;
+    } else if is_active(previous) && !is_active(state) {
+        load.running = load.running.saturating_sub(1);
+    }
+    // Jobs failed before they ever ran say nothing about how long jobs take
+    if let Some(ms) = processing_ms.filter(|_| is_active(previous)) {
+        load.recent_ms.push_back(ms);
+        load.recent_total_ms += ms;
+        if load.recent_ms.len() > RECENT_JOBS {
+            let oldest = load.recent_ms.pop_front().unwrap_or_default();
+            load.recent_total_ms -= oldest;
+        }
+    }
+    drop(load);
+
+    if previous == JobState::Queued {
+        jobs.queue_moved.send_replace(());
+    }
+}
+
+// Creates the job store, persisting jobs to `store` if the queue is durable.
+pub fn new(store: Option<Db>) -> Jobs {
+    Jobs {
+        entries: Arc::new(Mutex::new(HashMap::new())),
+        queue_moved: watch::Sender::new(()),
+        load: Arc::new(std::sync::Mutex::new(JobLoad::default())),
+        store,
+    }
+}
+
+// Records that a job finished in the durable queue, if there is one.
+async fn persist_finished(jobs: &Jobs, id: Uuid, state: JobState) {
+    let Some(store) = &jobs.store else {
+        return;
+    };
+    if let Err(e) = with_db(store, move |conn| {
+        queue_store::mark_finished(conn, id, state)
+    })
+    .await
+    {
+        error!("Failed to record that job {id} is {state:?}: {e}");
+    }
+}
+
+// Adds a newly accepted job, writing it to the durable queue first if there is one.
+pub async fn insert(jobs: &Jobs, job: &Job) -> rusqlite::Result<()> {
+    if let Some(store) = &jobs.store {
+        let stored = job.clone();
+        with_db(store, move |conn| queue_store::insert(conn, &stored)).await?;
+    }
+    insert_entry(jobs, job).await;
+    Ok(())
+}
+
+async fn insert_entry(jobs: &Jobs, job: &Job) {
+    let status = JobStatus {
+        id: job.id,
+        task: job.task_type,
+        state: JobState::Queued,
+        worker: None,
+        node: None,
+        history: vec![StateTransition {
+            state: JobState::Queued,
+            at: now_ms(),
+        }],
+    };
+
+    jobs.entries.lock().await.insert(
+        job.id,
+        JobEntry {
+            status: watch::Sender::new(status),
+            result: None,
+            output: watch::Sender::new(JobOutput::default()),
+            client: job.client.clone(),
+            code: job.code.clone(),
+        },
+    );
+}
+
+pub async fn remove(jobs: &Jobs, id: Uuid) {
+    jobs.entries.lock().await.remove(&id);
+    if let Some(store) = &jobs.store
+        && let Err(e) = with_db(store, move |conn| queue_store::delete(conn, id)).await
+    {
+        error!("Failed to remove job {id} from the durable queue: {e}");
+    }
+}
+
+pub async fn status(jobs: &Jobs, id: Uuid) -> Option<JobStatus> {
+    jobs.entries
+        .lock()
+        .await
+        .get(&id)
+        .map(|entry| entry.status.borrow().clone())
+}
+
+pub async fn result(jobs: &Jobs, id: Uuid) -> Option<JobResult> {
+    jobs.entries.lock().await.get(&id)?.result.clone()
+}
+
+// Subscribes to a job's state changes, so callers don't need to poll the job map.
+pub async fn subscribe(jobs: &Jobs, id: Uuid) -> Option<watch::Receiver<JobStatus>> {
+    jobs.entries
+        .lock()
+        .await
+        .get(&id)
+        .map(|entry| entry.status.subscribe())
+}
+
+// Where a running job's output is published as it comes.
+pub async fn output(jobs: &Jobs, id: Uuid) -> Option<watch::Sender<JobOutput>> {
+    jobs.entries
+        .lock()
+        .await
+        .get(&id)
+        .map(|entry| entry.output.clone())
+}
+
+pub async fn subscribe_output(jobs: &Jobs, id: Uuid) -> Option<watch::Receiver<JobOutput>> {
+    jobs.entries
+        .lock()
+        .await
+        .get(&id)
+        .map(|entry| entry.output.subscribe())
+}
+
+// Number of jobs in the store, how many of them hold results, and the size of those results.
+pub async fn store_stats(jobs: &Jobs) -> (usize, usize, usize) {
+    let entries = jobs.entries.lock().await;
+    let results = entries.values().filter_map(|entry| entry.result.as_ref());
+    let (count, bytes) = results.fold((0, 0), |(count, bytes), result| {
+        (count + 1, bytes + result.stdout.len() + result.stderr.len())
+    });
+    (entries.len(), count, bytes)
+}
+
+// How long a job took from being picked up by a worker to finishing, if it has finished.
+fn processing_time_ms(status: &JobStatus) -> Option<u64> {
+    let started = status
+        .history
+        .iter()
+        .find(|t| t.state != JobState::Queued)?;
+    let finished = status
+        .history
+        .iter()
+        .find(|t| matches!(t.state, JobState::Completed | JobState::Failed))?;
+    Some(finished.at.saturating_sub(started.at))
+}
+
+// Number of running jobs, and the average processing time of the jobs that finished recently.
+fn load(jobs: &Jobs) -> (u64, u64) {
+    let load = jobs.load.lock().unwrap_or_else(|e| e.into_inner());
+    let average_ms = load
+        .recent_total_ms
+        .checked_div(load.recent_ms.len() as u64)
+        .unwrap_or(DEFAULT_JOB_DURATION_MS);
+    (load.running as u64, average_ms)
+}
+
+// Estimates how long until the queue has room again: with every worker busy, a slot opens
+// up each time one of them finishes a job.
+pub async fn drain_interval(jobs: &Jobs, workers: usize) -> Duration {
+    let (_, average_ms) = load(jobs);
+    Duration::from_millis(average_ms / workers.max(1) as u64)
+}
+
+// Works out where a queued job sits in the queue and roughly when it will start, based on
+// how long recently finished jobs took. Returns `None` if the job is not queued.
+pub async fn queue_position(
+    jobs: &Jobs,
+    scheduler: &Scheduler,
+    id: Uuid,
+    workers: usize,
+) -> Option<QueuePosition> {
+    if jobs.entries.lock().await.get(&id)?.status.borrow().state != JobState::Queued {
+        return None;
+    }
+    let ahead = scheduler.position(id)? as u64;
+    let (running, average_ms) = load(jobs);
+
+    // Rough estimate: every job ahead of us (queued or running) has to clear a worker first,
+    // and the workers chew through them in parallel.
+    let workers = workers.max(1) as u64;
+    let wait_ms = average_ms * (ahead + running + 1).saturating_sub(workers) / workers;
+
+    Some(QueuePosition {
+        position: ahead as usize,
+        estimated_start: now_ms() + wait_ms,
+    })
+}
+
+// Picks up where the durable queue left off before a restart: jobs that were still queued are
+// queued again, while jobs that had started are failed, since they may have run already.
+pub async fn resume(
+    jobs: &Jobs,
+    scheduler: &Scheduler,
+    batches: &Batches,
+    metrics: &Metrics,
+) -> rusqlite::Result<()> {
+    let Some(store) = &jobs.store else {
+        return Ok(());
+    };
+    let stored = with_db(store, queue_store::take_all).await?;
+
+    let mut queued = Vec::new();
+    let mut interrupted = 0;
+    // Every job of the batches that still had unfinished jobs, in order
+    let mut batch_jobs: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+    let mut resumed_batches = HashSet::new();
+    for StoredJob {
+        job,
+        state,
+        started,
+    } in stored
+    {
+        if let Some(batch) = job.batch {
+            batch_jobs.entry(batch).or_default().push(job.id);
+        }
+        if state.is_terminal() {
+            continue;
+        }
+        resumed_batches.extend(job.batch);
+
+        insert_entry(jobs, &job).await;
+        if started {
+            let result = JobResult {
+                stdout: "".to_string(),
+                stderr: "The server stopped while this job was running.".to_string(),
+                exit_code: -1,
+            };
+            finish(jobs, job.id, JobState::Failed, result).await;
+            metrics.job_finished(job.task_type, JobState::Failed);
+            interrupted += 1;
+        } else {
+            queued.push(job);
+        }
+    }
+
+    if !queued.is_empty() || interrupted > 0 {
+        info!(
+            "Resuming {} queued jobs, failed {interrupted} that were interrupted",
+            queued.len()
+        );
+    }
+    scheduler.restore(queued);
+    for (batch, ids) in batch_jobs {
+        if resumed_batches.contains(&batch) {
+            batches::insert(batches, jobs, batch, ids).await;
+        }
+    }
+    Ok(())
+}
+
+// Jobs that have not reached a final state yet.
+pub async fn unfinished(jobs: &Jobs) -> Vec<Uuid> {
+    jobs.entries
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, entry)| !entry.status.borrow().state.is_terminal())
+        .map(|(id, _)| *id)
+        .collect()
+}
+
+// Moves a job into a new state. Terminal states are final, so transitions out of them are
+// ignored; this returns whether the transition happened.
+pub async fn set_state(jobs: &Jobs, id: Uuid, state: JobState) -> bool {
+    let entries = jobs.entries.lock().await;
+    let Some(entry) = entries.get(&id) else {
+        return false;
+    };
+    if entry.status.borrow().state.is_terminal() {
+        return false;
+    }
+
+    debug!("Job {id} is now {state:?}");
+    transition(jobs, entry, state);
+    true
+}
+
+// Claims a queued job for a worker, local or on a remote node. Returns false if the job is no
+// longer queued (e.g. it was cancelled while waiting).
+//
+// With a durable queue, the claim is recorded before the job runs, so it is never run again
+// after a restart. A job whose claim can't be recorded is failed instead of run.
+pub async fn claim(jobs: &Jobs, id: Uuid, worker: usize, node: Option<&str>) -> bool {
+    let claimed = match jobs.entries.lock().await.get(&id) {
+        Some(entry) => entry.status.send_if_modified(|status| {
+            if status.state != JobState::Queued {
+                return false;
+            }
+            status.worker = Some(worker);
+            status.node = node.map(str::to_string);
+            true
+        }),
+        None => false,
+    };
+    let Some(store) = jobs.store.as_ref().filter(|_| claimed) else {
+        return claimed;
+    };
+
+    match with_db(store, move |conn| queue_store::mark_started(conn, id)).await {
+        Ok(true) => return true,
+        Ok(false) => error!("Job {id} was claimed but is missing from the durable queue"),
+        Err(e) => error!("Failed to record that job {id} started: {e}"),
+    }
+
+    // The job is off the scheduler already, so nothing else would ever finish it
+    let result = JobResult {
+        stdout: "".to_string(),
+        stderr: "Fatal execution error: the job could not be recorded as started".to_string(),
+        exit_code: -1,
+    };
+    finish(jobs, id, JobState::Failed, result).await;
+    false
+}
+
+// Puts a job back in the queue after the worker running it was lost, so another worker can
+// run it from the start. Returns false if it finished in the meantime.
+pub async fn requeue(jobs: &Jobs, scheduler: &Scheduler, job: Job) -> bool {
+    let id = job.id;
+    match jobs.entries.lock().await.get(&id) {
+        Some(entry) if !entry.status.borrow().state.is_terminal() => {
+            entry.status.send_modify(|status| {
+                status.worker = None;
+                status.node = None;
+            });
+            transition(jobs, entry, JobState::Queued);
+            entry.output.send_replace(JobOutput::default());
+        }
+        _ => return false,
+    }
+
+    if let Some(store) = &jobs.store
+        && let Err(e) = with_db(store, move |conn| queue_store::mark_requeued(conn, id)).await
+    {
+        error!("Failed to record that job {id} was queued again: {e}");
+    }
+    debug!("Job {id} is queued again");
+    // It was accepted already, so it goes back in even if the queue is full
+    scheduler.restore(vec![job]);
+    true
+}
+
+// Moves a job into a final state with its result. Returns false if it had already finished,
+// e.g. because it was cancelled while it ran.
+pub async fn finish(jobs: &Jobs, id: Uuid, state: JobState, result: JobResult) -> bool {
+    let finished = match jobs.entries.lock().await.get_mut(&id) {
+        Some(entry) if !entry.status.borrow().state.is_terminal() => {
+            debug!("Job {id} is now {state:?}");
+            // Store the result before announcing the new state, so watchers can pick it up
+            entry.result = Some(result);
+            // The result holds all of the output
+            entry.output.send_replace(JobOutput::default());
+            transition(jobs, entry, state);
+            true
+        }
+        _ => false,
+    };
+    if finished {
+        persist_finished(jobs, id, state).await;
+        schedule_cleanup(jobs.clone(), id);
+    }
+    finished
+}
+
+// Takes a job that has not started yet out of the queue, moving it to the given terminal
+// state. Returns false if it is unknown or no longer queued.
+async fn dequeue_as(jobs: &Jobs, id: Uuid, state: JobState) -> bool {
+    let dequeued = match jobs.entries.lock().await.get(&id) {
+        Some(entry) if entry.status.borrow().state == JobState::Queued => {
+            transition(jobs, entry, state);
+            true
+        }
+        _ => false,
+    };
+
+    if dequeued {
+        debug!("Job {id} is now {state:?}");
+        persist_finished(jobs, id, state).await;
+        schedule_cleanup(jobs.clone(), id);
+    }
+    dequeued
+}
+
+// Cancels a job that has not started yet. Returns false if it is unknown or already running.
+pub async fn cancel(jobs: &Jobs, id: Uuid) -> bool {
+    dequeue_as(jobs, id, JobState::Cancelled).await
+}
+
+// Cancels a job whether or not it has started. Whoever is running it notices and stops.
+// Returns false if it is unknown or already finished.
+pub async fn stop(jobs: &Jobs, id: Uuid) -> bool {
+    if cancel(jobs, id).await {
+        return true;
+    }
+    let result = JobResult {
+        stdout: "".to_string(),
+        stderr: "The job was cancelled by an operator.".to_string(),
+        exit_code: -1,
+    };
+    finish(jobs, id, JobState::Cancelled, result).await
+}
+
+// Resolves once a job has finished, or is gone.
+pub async fn finished(jobs: &Jobs, id: Uuid) {
+    if let Some(mut status) = subscribe(jobs, id).await {
+        let _ = status.wait_for(|status| status.state.is_terminal()).await;
+    }
+}
+
+fn details(entry: &JobEntry, full: bool) -> JobDetails {
+    JobDetails {
+        status: entry.status.borrow().clone(),
+        client: entry.client.clone(),
+        code: full.then(|| entry.code.clone()),
+        result: entry.result.clone().filter(|_| full),
+    }
+}
+
+// A job with its code and result, for operators.
+pub async fn inspect(jobs: &Jobs, id: Uuid) -> Option<JobDetails> {
+    jobs.entries
+        .lock()
+        .await
+        .get(&id)
+        .map(|entry| details(entry, true))
+}
+
+// The jobs in the given state, or the unfinished ones, oldest first.
+pub async fn list(jobs: &Jobs, state: Option<JobState>) -> Vec<JobDetails> {
+    let mut listed: Vec<JobDetails> = jobs
+        .entries
+        .lock()
+        .await
+        .values()
+        .filter(|entry| {
+            let current = entry.status.borrow().state;
+            state.map_or(!current.is_terminal(), |state| current == state)
+        })
+        .map(|entry| details(entry, false))
+        .collect();
+    listed.sort_by_key(|job| job.status.history.first().map(|first| first.at));
+    listed
+}
+
+// Unfinished jobs submitted by a client that have not started yet.
+pub async fn queued_by(jobs: &Jobs, client: &str) -> Vec<Uuid> {
+    jobs.entries
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, entry)| {
+            entry.client == client && entry.status.borrow().state == JobState::Queued
+        })
+        .map(|(id, _)| *id)
+        .collect()
+}
+
+// Expires a job that sat in the queue past its deadline.
+pub async fn expire(jobs: &Jobs, id: Uuid) -> bool {
+    dequeue_as(jobs, id, JobState::Expired).await
+}
+
+// Clean up finished jobs after some time to prevent memory bloat
+fn schedule_cleanup(jobs: Jobs, id: Uuid) {
+    tokio::spawn(async move {
+        tokio::time::sleep(RESULT_TTL).await;
+        debug!("Discarding results for job {id}");
+        // The job keeps its final state, clients asking for the result are told it's gone
+        if let Some(entry) = jobs.entries.lock().await.get_mut(&id) {
+            entry.result = None;
+        }
+
+        tokio::time::sleep(EXPIRED_TTL).await;
+        debug!("Removing job {id}");
+        remove(&jobs, id).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{api_keys::Tier, db, models::TaskType};
+
+    fn job() -> Job {
+        Job {
+            id: Uuid::new_v4(),
+            client: "client".to_string(),
+            tier: Arc::new(Tier::default()),
+            task_type: TaskType::Execute,
+            code: String::new(),
+            deadline: Instant::now() + Duration::from_secs(60),
+            batch: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn resume_requeues_only_jobs_that_never_started() {
+        let store = db::open(":memory:").unwrap();
+        let before = new(Some(store.clone()));
+        let (queued, started, finished) = (job(), job(), job());
+        for job in [&queued, &started, &finished] {
+            insert(&before, job).await.unwrap();
+        }
+        assert!(claim(&before, started.id, 0, None).await);
+        assert!(claim(&before, finished.id, 1, None).await);
+        finish(
+            &before,
+            finished.id,
+            JobState::Completed,
+            JobResult::default(),
+        )
+        .await;
+
+        // Restart with the same database
+        let jobs = new(Some(store));
+        let scheduler = Arc::new(Scheduler::new(8, 8));
+        let metrics = Metrics::new();
+        resume(&jobs, &scheduler, &batches::new(), &metrics)
+            .await
+            .unwrap();
+
+        assert_eq!(scheduler.len(), 1);
+        assert_eq!(scheduler.position(queued.id), Some(0));
+        let state = async |id| status(&jobs, id).await.map(|status| status.state);
+        assert_eq!(state(queued.id).await, Some(JobState::Queued));
+        // It may have run already, so it is failed rather than run again
+        assert_eq!(state(started.id).await, Some(JobState::Failed));
+        assert_eq!(state(finished.id).await, None);
+    }
+}
@@ -1,28 +1,74 @@
+mod api_keys;
+mod bans;
+mod batches;
+mod client;
 mod compilation_worker;
+mod config;
+mod db;
+mod error;
 mod handlers;
+mod health;
+mod jobs;
+mod metrics;
 mod metrics_worker;
 mod models;
+mod openapi;
+mod queue_store;
+mod rate_limit;
+mod reload;
+mod remote;
+mod remote_worker;
 mod sandbox;
+mod scheduler;
+mod session;
+mod shutdown;
+mod snippets;
 
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use axum::{
     Router,
-    routing::{get, post},
-};
-use models::Results;
-use tokio::sync::Mutex;
-use tower_governor::{
-    GovernorLayer, governor::GovernorConfigBuilder, key_extractor::SmartIpKeyExtractor,
+    extract::Request,
+    http::HeaderValue,
+    middleware,
+    routing::{any, delete, get, post},
 };
+use tokio::sync::Semaphore;
 use tower_http::{
-    cors::CorsLayer,
+    cors::{Any, CorsLayer},
     services::ServeDir,
     trace::{self, TraceLayer},
 };
 use tracing::{Level, info};
 
-use crate::models::{AppState, Job};
+use crate::{
+    api_keys::ApiKeys,
+    compilation_worker::Workers,
+    config::{Config, Reloadable},
+    error::RequestId,
+    metrics::Metrics,
+    models::AppState,
+    rate_limit::RateLimiter,
+    remote::RemoteWorkers,
+    scheduler::Scheduler,
+    shutdown::Shutdown,
+};
+
+// Allows any origin if the list contains `*`, or only the listed ones otherwise. The origins
+// have been validated when the configuration was loaded.
+fn cors_layer(origins: &[String]) -> CorsLayer {
+    if origins.iter().any(|origin| origin == "*") {
+        return CorsLayer::permissive();
+    }
+    let origins: Vec<HeaderValue> = origins
+        .iter()
+        .filter_map(|origin| HeaderValue::from_str(origin).ok())
+        .collect();
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(Any)
+        .allow_headers(Any)
+}
 
 #[tokio::main]
 async fn main() {
@@ -35,51 +81,110 @@ async fn main() {
         std::process::exit(1);
     }
 
+    let config = match Config::load(&config::path()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    // `worker` runs this process as a remote worker for another server instead
+    if std::env::args().nth(1).as_deref() == Some("worker") {
+        return remote_worker::main(config).await;
+    }
+
+    let db = db::open(&config.paths.database).expect("failed to open database");
+    let bans = db::with_db(&db, bans::Bans::load)
+        .await
+        .expect("failed to load bans");
+
     info!("Spawning workers...");
 
-    let num_workers = std::env::var("NUM_WORKERS")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(4);
+    let api_keys = Arc::new(Reloadable::new(
+        ApiKeys::load(&config.paths.api_keys, &config.anonymous).expect("failed to load API keys"),
+    ));
 
-    let (tx, rx) = async_channel::unbounded::<Job>();
-    let results: Results = Arc::new(Mutex::new(HashMap::new()));
+    let scheduler = Arc::new(Scheduler::new(config.queue.depth, config.queue.batch_depth));
+    let metrics = Arc::new(Metrics::new());
+    let jobs = jobs::new(config.queue.durable.then(|| db.clone()));
+    let batches = batches::new();
+    jobs::resume(&jobs, &scheduler, &batches, &metrics)
+        .await
+        .expect("failed to resume the durable queue");
 
-    for i in 0..num_workers {
-        let rx = rx.clone();
-        let results = results.clone();
+    let rate_limiter = Arc::new(RateLimiter::default());
+    {
+        let rate_limiter = rate_limiter.clone();
         tokio::spawn(async move {
-            compilation_worker::worker(i, rx, results).await;
+            loop {
+                tokio::time::sleep(Duration::from_mins(1)).await;
+                rate_limiter.prune();
+            }
         });
     }
 
+    // Everything below reads the configuration through `shared_config`, so it sees reloads
+    let workers_count = config.workers.min;
+    let sessions = Arc::new(Semaphore::new(config.sessions.max));
+    let port = config.server.port;
+    let cors = cors_layer(&config.server.cors_origins);
+    let static_dir = ServeDir::new(&config.server.static_dir);
+    let shared_config = Arc::new(Reloadable::new(config));
+
+    let workers = Workers::new(
+        scheduler.clone(),
+        jobs.clone(),
+        metrics.clone(),
+        rate_limiter.clone(),
+        shared_config.clone(),
+    );
+    workers.scale(workers_count);
+    tokio::spawn(compilation_worker::autoscale(workers.clone()));
+
     {
-        let rx = rx.clone();
-        let results = results.clone();
+        let scheduler = scheduler.clone();
+        let jobs = jobs.clone();
+        let metrics = metrics.clone();
+        let config = shared_config.clone();
         tokio::spawn(async move {
-            metrics_worker::main(rx, results).await;
+            metrics_worker::main(scheduler, jobs, metrics, config).await;
         });
     }
 
     let state = AppState {
-        work_queue: tx,
-        results,
+        work_queue: scheduler,
+        jobs,
+        batches,
+        workers,
+        remote_workers: Arc::new(RemoteWorkers::default()),
+        bans: Arc::new(bans),
+        config: shared_config,
+        sessions,
+        api_keys,
+        rate_limiter,
+        metrics,
+        readiness: health::new_cache(),
+        shutdown: Shutdown::new(),
+        db,
     };
 
-    let governor_conf = GovernorConfigBuilder::default()
-        .key_extractor(SmartIpKeyExtractor)
-        .per_second(10)
-        .burst_size(10)
-        .finish()
-        .expect("failed to build rate limit config");
-
-    let execute_router = Router::new()
-        .route("/api/v1/execute", post(crate::handlers::execute_code))
-        .with_state(state.clone())
-        .layer(GovernorLayer::new(governor_conf));
+    tokio::spawn(reload::on_sighup(state.clone()));
+    tokio::spawn(remote::reap(state.clone()));
 
     let app = Router::new()
-        .merge(execute_router)
+        .route("/metrics", get(crate::handlers::get_metrics))
+        .route("/healthz", get(crate::handlers::healthz))
+        .route("/readyz", get(crate::handlers::readyz))
+        .route("/api/v1/execute", post(crate::handlers::execute_code))
+        .route("/api/v1/run", post(crate::handlers::run_code))
+        .route("/api/v1/session", get(crate::handlers::interactive_session))
+        .route("/api/v1/batch", post(crate::handlers::submit_batch))
+        .route("/api/v1/batch/{batch_id}", get(crate::handlers::get_batch))
+        .route(
+            "/api/v1/batch/{batch_id}/stream",
+            get(crate::handlers::stream_batch),
+        )
         .route(
             "/api/v1/stream/{job_id}",
             get(crate::handlers::stream_results),
@@ -88,21 +193,92 @@ async fn main() {
             "/api/v1/results/{job_id}",
             get(crate::handlers::get_results),
         )
+        .route(
+            "/api/v1/jobs/{job_id}",
+            get(crate::handlers::get_job).delete(crate::handlers::cancel_job),
+        )
         .route("/api/v1/version", get(crate::handlers::get_version))
-        .with_state(state)
+        .route("/api/v1/openapi.json", get(crate::handlers::get_openapi))
+        .merge(openapi::viewer("/api/v1/docs"))
+        .route("/api/v1/admin/reload", post(crate::handlers::reload_config))
+        .route("/api/v1/admin/jobs", get(crate::handlers::list_jobs))
+        .route(
+            "/api/v1/admin/jobs/{job_id}",
+            get(crate::handlers::inspect_job).delete(crate::handlers::stop_job),
+        )
+        .route("/api/v1/admin/queue", get(crate::handlers::get_queue))
+        .route(
+            "/api/v1/admin/queue/pause",
+            post(crate::handlers::pause_queue),
+        )
+        .route(
+            "/api/v1/admin/queue/resume",
+            post(crate::handlers::resume_queue),
+        )
+        .route(
+            "/api/v1/admin/bans",
+            get(crate::handlers::list_bans).post(crate::handlers::ban_client),
+        )
+        .route(
+            "/api/v1/admin/bans/{client}",
+            delete(crate::handlers::unban_client),
+        )
+        .route("/api/v1/admin/workers", get(crate::handlers::get_workers))
+        .route("/api/v1/workers", post(crate::handlers::register_worker))
+        .route(
+            "/api/v1/workers/{worker_id}",
+            delete(crate::handlers::deregister_worker),
+        )
+        .route(
+            "/api/v1/workers/{worker_id}/heartbeat",
+            post(crate::handlers::worker_heartbeat),
+        )
+        .route(
+            "/api/v1/workers/{worker_id}/jobs",
+            post(crate::handlers::pull_job),
+        )
+        .route(
+            "/api/v1/workers/{worker_id}/jobs/{job_id}/state",
+            post(crate::handlers::report_job_stage),
+        )
+        .route(
+            "/api/v1/workers/{worker_id}/jobs/{job_id}/result",
+            post(crate::handlers::submit_job_result),
+        )
+        .route("/api/v1/snippets", post(crate::handlers::create_snippet))
+        .route("/api/v1/snippets/{id}", get(crate::handlers::get_snippet))
+        .route(
+            "/api/v1/snippets/{id}/revisions",
+            get(crate::handlers::list_snippet_revisions)
+                .post(crate::handlers::save_snippet_revision),
+        )
+        .route(
+            "/api/v1/snippets/{id}/diff",
+            get(crate::handlers::diff_snippet_revisions),
+        )
+        .route(
+            "/api/v1/snippets/{id}/fork",
+            post(crate::handlers::fork_snippet),
+        )
+        .route("/api/{*path}", any(crate::handlers::unknown_endpoint))
+        .with_state(state.clone())
         .layer(
             TraceLayer::new_for_http()
-                .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
+                .make_span_with(|req: &Request| {
+                    let id = req.extensions().get::<RequestId>().map(|id| id.0.as_str());
+                    tracing::info_span!(
+                        "request",
+                        method = %req.method(),
+                        uri = %req.uri(),
+                        id = %id.unwrap_or_default(),
+                    )
+                })
                 .on_response(trace::DefaultOnResponse::new().level(Level::INFO))
                 .on_failure(trace::DefaultOnFailure::new().level(Level::ERROR)),
         )
-        .layer(CorsLayer::permissive())
-        .fallback_service(ServeDir::new("../web/public"));
-
-    let port = std::env::var("PORT")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(3000);
+        .layer(middleware::from_fn(error::handle_errors))
+        .layer(cors)
+        .fallback_service(static_dir);
 
     let listener = tokio::net::TcpListener::bind(format!("[::]:{port}"))
         .await
@@ -110,5 +286,12 @@ async fn main() {
 
     info!("Listening on port {port} (IPv4 + IPv6)");
     let app = app.into_make_service_with_connect_info::<SocketAddr>();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown::drain_on_signal(state.clone()))
+        .await
+        .unwrap();
+
+    // Every connection is closed by now, so nothing is using the work directory anymore
+    shutdown::clean_work_dir(&state.config.get().paths.work_dir).await;
+    info!("Shut down");
 }
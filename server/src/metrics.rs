@@ -0,0 +1,227 @@
+use std::fmt::Write;
+
+use prometheus_client::{
+    encoding::{EncodeLabelSet, EncodeLabelValue, LabelValueEncoder},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
+    registry::Registry,
+};
+
+use crate::models::{JobState, JobStatus, TaskType};
+
+impl EncodeLabelValue for TaskType {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        encoder.write_str(self.as_str())
+    }
+}
+
+impl EncodeLabelValue for JobState {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        encoder.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub struct JobLabels {
+    pub task: TaskType,
+    pub outcome: JobState,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub struct StageLabels {
+    pub task: TaskType,
+    pub stage: JobState,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub struct LaneLabels {
+    pub lane: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub struct RejectionLabels {
+    pub reason: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub struct CacheLabels {
+    pub cache: &'static str,
+    // `hit` or `miss`
+    pub result: &'static str,
+}
+
+fn stage_histogram() -> Histogram {
+    // 10ms up to about 80s
+    Histogram::new(exponential_buckets(0.01, 2.0, 14))
+}
+
+// Metrics exported in Prometheus format on `/metrics`. Gauges describing the queue and the job
+// store are filled in when the metrics are scraped.
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    pub jobs: Family<JobLabels, Counter>,
+    pub stage_duration: Family<StageLabels, Histogram, fn() -> Histogram>,
+    pub rejections: Family<RejectionLabels, Counter>,
+    pub cache_lookups: Family<CacheLabels, Counter>,
+    pub workers: Gauge,
+    pub busy_workers: Gauge,
+    pub worker_panics: Counter,
+    pub remote_workers: Gauge,
+    pub remote_jobs: Gauge,
+    pub queued_jobs: Family<LaneLabels, Gauge>,
+    pub stored_jobs: Gauge,
+    pub stored_results: Gauge,
+    pub stored_result_bytes: Gauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut metrics = Metrics {
+            registry: Registry::with_prefix("zplay"),
+            jobs: Family::default(),
+            stage_duration: Family::new_with_constructor(stage_histogram),
+            rejections: Family::default(),
+            cache_lookups: Family::default(),
+            workers: Gauge::default(),
+            busy_workers: Gauge::default(),
+            worker_panics: Counter::default(),
+            remote_workers: Gauge::default(),
+            remote_jobs: Gauge::default(),
+            queued_jobs: Family::default(),
+            stored_jobs: Gauge::default(),
+            stored_results: Gauge::default(),
+            stored_result_bytes: Gauge::default(),
+        };
+
+        let registry = &mut metrics.registry;
+        registry.register(
+            "jobs",
+            "Jobs that reached a final state, by task type and outcome",
+            metrics.jobs.clone(),
+        );
+        registry.register(
+            "job_stage_duration_seconds",
+            "Time jobs spent in each stage, from queued to running",
+            metrics.stage_duration.clone(),
+        );
+        registry.register(
+            "rejected_requests",
+            "Requests turned away by rate limiting or a full queue",
+            metrics.rejections.clone(),
+        );
+        registry.register(
+            "cache_lookups",
+            "Lookups in the toolchain version and readiness caches, by cache and whether they hit",
+            metrics.cache_lookups.clone(),
+        );
+        registry.register(
+            "workers",
+            "Number of compilation workers",
+            metrics.workers.clone(),
+        );
+        registry.register(
+            "busy_workers",
+            "Number of compilation workers currently processing a job",
+            metrics.busy_workers.clone(),
+        );
+        registry.register(
+            "worker_panics",
+            "Times a compilation worker panicked and was restarted",
+            metrics.worker_panics.clone(),
+        );
+        registry.register(
+            "remote_workers",
+            "Number of remote workers registered with this server",
+            metrics.remote_workers.clone(),
+        );
+        registry.register(
+            "remote_jobs",
+            "Jobs currently running on remote workers",
+            metrics.remote_jobs.clone(),
+        );
+        registry.register(
+            "queued_jobs",
+            "Jobs waiting in the queue, by lane",
+            metrics.queued_jobs.clone(),
+        );
+        registry.register(
+            "stored_jobs",
+            "Jobs kept in the job store, including finished ones",
+            metrics.stored_jobs.clone(),
+        );
+        registry.register(
+            "stored_results",
+            "Job results kept in the job store",
+            metrics.stored_results.clone(),
+        );
+        registry.register(
+            "stored_result_bytes",
+            "Size of the output held by stored job results",
+            metrics.stored_result_bytes.clone(),
+        );
+
+        metrics
+    }
+
+    pub fn encode(&self) -> Result<String, std::fmt::Error> {
+        let mut out = String::new();
+        prometheus_client::encoding::text::encode(&mut out, &self.registry)?;
+        Ok(out)
+    }
+
+    pub fn job_finished(&self, task: TaskType, outcome: JobState) {
+        self.jobs.get_or_create(&JobLabels { task, outcome }).inc();
+    }
+
+    pub fn rejected(&self, reason: &'static str) {
+        self.rejections
+            .get_or_create(&RejectionLabels { reason })
+            .inc();
+    }
+
+    pub fn cache_lookup(&self, cache: &'static str, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.cache_lookups
+            .get_or_create(&CacheLabels { cache, result })
+            .inc();
+    }
+
+    // Records how long a finished job spent in each of the stages it went through.
+    pub fn observe_stages(&self, status: &JobStatus) {
+        for pair in status.history.windows(2) {
+            let stage = pair[0].state;
+            let seconds = pair[1].at.saturating_sub(pair[0].at) as f64 / 1000.0;
+            self.stage_duration
+                .get_or_create(&StageLabels {
+                    task: status.task,
+                    stage,
+                })
+                .observe(seconds);
+        }
+    }
+
+    // Number of jobs that expired in the queue, across all task types.
+    pub fn expired_jobs(&self) -> u64 {
+        [
+            TaskType::Execute,
+            TaskType::Lint,
+            TaskType::Tast,
+            TaskType::Llvm,
+        ]
+        .into_iter()
+        .map(|task| {
+            self.jobs
+                .get_or_create(&JobLabels {
+                    task,
+                    outcome: JobState::Expired,
+                })
+                .get()
+        })
+        .sum()
+    }
+}
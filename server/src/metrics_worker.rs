@@ -1,20 +1,37 @@
+use std::sync::Arc;
+
 use tracing::{debug, error, info};
 
-use crate::models::{Job, Results};
+use crate::{
+    config::{Config, Reloadable},
+    metrics::Metrics,
+    models::Jobs,
+    scheduler::Scheduler,
+};
 
-pub async fn main(rx: async_channel::Receiver<Job>, _results: Results) {
+pub async fn main(
+    scheduler: Arc<Scheduler>,
+    _jobs: Jobs,
+    metrics: Arc<Metrics>,
+    config: Arc<Reloadable<Config>>,
+) {
     info!("Metrics worker started");
     loop {
-        // We write the metrics (number of pending jobs) to the file ./metrics.json every 30 seconds
+        // We write the metrics (number of pending and expired jobs) to the configured metrics
+        // file every few seconds. The full set of metrics is served in Prometheus format on
+        // /metrics.
+        let config = config.get();
 
-        let pending_jobs = rx.len();
+        let pending_jobs = scheduler.len();
+        let expired_jobs = metrics.expired_jobs();
         let metrics = serde_json::json!({
             "pending_jobs": pending_jobs,
+            "expired_jobs": expired_jobs,
         });
         debug!("Metrics file written: {pending_jobs} pending jobs");
 
         if let Err(e) = tokio::fs::write(
-            "./metrics.json",
+            &config.paths.metrics_file,
             serde_json::to_string_pretty(&metrics).unwrap(),
         )
         .await
@@ -22,6 +39,9 @@ pub async fn main(rx: async_channel::Receiver<Job>, _results: Results) {
             error!("Failed to write metrics file: {e}");
         }
 
-        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        tokio::time::sleep(std::time::Duration::from_secs(
+            config.workers.metrics_interval_secs,
+        ))
+        .await;
     }
 }
@@ -1,9 +1,30 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    sync::Arc,
+    time::Instant,
+};
 
 use serde::{Deserialize, Serialize};
+use tokio::sync::{Semaphore, watch};
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+use crate::{
+    api_keys::{ApiKeys, Tier},
+    bans::Bans,
+    compilation_worker::Workers,
+    config::{Config, Reloadable},
+    db::Db,
+    health::ReadinessCache,
+    metrics::Metrics,
+    rate_limit::RateLimiter,
+    remote::RemoteWorkers,
+    scheduler::Scheduler,
+    shutdown::Shutdown,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum TaskType {
     Execute,
@@ -12,38 +33,587 @@ pub enum TaskType {
     Llvm,
 }
 
-#[derive(Debug)]
+impl TaskType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TaskType::Execute => "execute",
+            TaskType::Lint => "lint",
+            TaskType::Tast => "tast",
+            TaskType::Llvm => "llvm",
+        }
+    }
+}
+
+impl FromStr for TaskType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "execute" => Ok(TaskType::Execute),
+            "lint" => Ok(TaskType::Lint),
+            "tast" => Ok(TaskType::Tast),
+            "llvm" => Ok(TaskType::Llvm),
+            _ => Err(format!("unknown task type: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Job {
     pub id: Uuid,
+    // Who submitted the job, for fair scheduling, and the limits that apply to them
+    pub client: String,
+    pub tier: Arc<Tier>,
     pub task_type: TaskType,
     pub code: String,
+    // Jobs still queued after this are dropped, since nobody is waiting for them anymore
+    pub deadline: Instant,
+    // The batch the job was submitted in, if any
+    pub batch: Option<Uuid>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+// What it takes to run a job, wherever it runs. This is what remote workers are sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobSpec {
+    pub id: Uuid,
+    pub task: TaskType,
+    pub code: String,
+    pub tier: Tier,
+}
+
+impl From<Job> for JobSpec {
+    fn from(job: Job) -> Self {
+        JobSpec {
+            id: job.id,
+            task: job.task_type,
+            code: job.code,
+            tier: (*job.tier).clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
 pub struct JobResult {
     pub stdout: String,
     pub stderr: String,
     pub exit_code: i32,
 }
 
-pub type Results = Arc<tokio::sync::Mutex<HashMap<Uuid, JobResult>>>;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Compiling,
+    Linking,
+    Running,
+    // The job produced a result (which may still be a compile error or non-zero exit)
+    Completed,
+    // The server could not produce a result for the job
+    Failed,
+    Cancelled,
+    // The job waited in the queue past its deadline
+    Expired,
+}
 
-#[derive(Debug, Deserialize)]
+impl JobState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Compiling => "compiling",
+            JobState::Linking => "linking",
+            JobState::Running => "running",
+            JobState::Completed => "completed",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+            JobState::Expired => "expired",
+        }
+    }
+
+    pub fn is_terminal(self) -> bool {
+        matches!(
+            self,
+            JobState::Completed | JobState::Failed | JobState::Cancelled | JobState::Expired
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct StateTransition {
+    pub state: JobState,
+    // Unix timestamp in milliseconds
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct JobStatus {
+    pub id: Uuid,
+    pub task: TaskType,
+    pub state: JobState,
+    pub worker: Option<usize>,
+    // The remote worker node running the job, if it isn't running on this server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    pub history: Vec<StateTransition>,
+}
+
+#[derive(Debug)]
+pub struct JobEntry {
+    // Publishes every change to the job's status to whoever is watching it
+    pub status: watch::Sender<JobStatus>,
+    pub result: Option<JobResult>,
+    // What the job printed so far while it runs, published to streams
+    pub output: watch::Sender<JobOutput>,
+    // Kept for operators inspecting the job
+    pub client: String,
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+// A running job's output, until it finishes and its result takes over.
+#[derive(Debug, Clone, Default)]
+pub struct JobOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl JobOutput {
+    pub fn get(&self, stream: OutputStream) -> &[u8] {
+        match stream {
+            OutputStream::Stdout => &self.stdout,
+            OutputStream::Stderr => &self.stderr,
+        }
+    }
+
+    pub fn get_mut(&mut self, stream: OutputStream) -> &mut Vec<u8> {
+        match stream {
+            OutputStream::Stdout => &mut self.stdout,
+            OutputStream::Stderr => &mut self.stderr,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct OutputEvent {
+    pub stream: OutputStream,
+    // Where `data` starts in the stream, in bytes, so clients that stream a job again can
+    // skip what they have already seen. Invalid UTF-8 counts as the replacement character it is
+    // sent as, like in the job's result.
+    pub offset: usize,
+    pub data: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueuePosition {
+    // Number of queued jobs ahead of this one
+    pub position: usize,
+    // Unix timestamp in milliseconds
+    pub estimated_start: u64,
+}
+
+// The job's place in the queue, which is left out if it is just leaving the queue.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PendingEvent {
+    pub state: JobState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_start: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Jobs {
+    pub entries: Arc<tokio::sync::Mutex<HashMap<Uuid, JobEntry>>>,
+    // Signalled whenever a job leaves the queue, so queued jobs can refresh their position
+    pub queue_moved: watch::Sender<()>,
+    // Kept up to date as jobs change state, for queue estimates
+    pub load: Arc<std::sync::Mutex<JobLoad>>,
+    // Where jobs are persisted if the queue is durable
+    pub store: Option<Db>,
+}
+
+#[derive(Debug, Default)]
+pub struct JobLoad {
+    // Jobs that are compiling, linking or running
+    pub running: usize,
+    // How long the most recently finished jobs took from being picked up to finishing, in
+    // milliseconds, and their sum
+    pub recent_ms: VecDeque<u64>,
+    pub recent_total_ms: u64,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct ExecuteRequest {
     pub task: TaskType,
     pub code: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RunRequest {
+    #[serde(flatten)]
+    pub job: ExecuteRequest,
+    // How long to wait for the job to finish, in seconds
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct ExecuteResponse {
     #[serde(rename = "jobId")]
     pub job_id: Uuid,
 }
 
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct BatchRequest {
+    pub jobs: Vec<ExecuteRequest>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BatchResponse {
+    #[serde(rename = "batchId")]
+    pub batch_id: Uuid,
+    #[serde(rename = "jobIds")]
+    pub job_ids: Vec<Uuid>,
+}
+
+// How many of a batch's jobs have finished, and how.
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct BatchProgress {
+    pub total: usize,
+    pub completed: usize,
+    pub failed: usize,
+    pub cancelled: usize,
+    pub expired: usize,
+    // Indices of the finished jobs in the order they finished, so streams can tell which
+    // ones they haven't reported yet
+    #[serde(skip)]
+    pub finished: Vec<usize>,
+}
+
+impl BatchProgress {
+    pub fn is_done(&self) -> bool {
+        self.finished.len() == self.total
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BatchItem {
+    pub index: usize,
+    pub id: Uuid,
+    pub state: JobState,
+    pub result: Option<JobResult>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BatchStatus {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub progress: BatchProgress,
+    pub items: Vec<BatchItem>,
+}
+
+#[derive(Debug)]
+pub struct BatchEntry {
+    pub jobs: Vec<Uuid>,
+    pub progress: watch::Sender<BatchProgress>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Batches {
+    pub entries: Arc<tokio::sync::Mutex<HashMap<Uuid, BatchEntry>>>,
+}
+
+// Messages clients send over an interactive session's WebSocket. Raw input can also be sent
+// as binary messages.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SessionRequest {
+    // Must be the first message of a session
+    Start { code: String, cols: u16, rows: u16 },
+    Input { data: String },
+    Resize { cols: u16, rows: u16 },
+}
+
+// Messages the server sends over an interactive session's WebSocket, besides the program's
+// terminal output, which is sent as binary messages.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionEvent {
+    State { state: JobState },
+    BuildFailed { result: JobResult },
+    Error { error: String },
+    Exit { exit_code: i32 },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadinessCheck {
+    pub name: &'static str,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<ReadinessCheck>,
+    // Unix timestamp in milliseconds
+    pub checked_at: u64,
+}
+
+fn default_toolchain() -> String {
+    "nightly".to_string()
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateSnippetRequest {
+    pub task: TaskType,
+    pub code: String,
+    #[serde(default = "default_toolchain")]
+    pub toolchain: String,
+    #[serde(default)]
+    pub flags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SnippetRef {
+    pub id: String,
+    pub revision: i64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Snippet {
+    pub id: String,
+    pub revision: i64,
+    pub parent_revision: Option<i64>,
+    pub forked_from: Option<SnippetRef>,
+    pub task: TaskType,
+    pub toolchain: String,
+    pub flags: Vec<String>,
+    pub code: String,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SaveRevisionRequest {
+    #[serde(flatten)]
+    pub snippet: CreateSnippetRequest,
+    // The revision this one is based on; defaults to the latest revision.
+    pub parent: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RevisionSummary {
+    pub revision: i64,
+    pub parent_revision: Option<i64>,
+    pub task: TaskType,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RevisionQuery {
+    pub revision: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DiffQuery {
+    pub from: i64,
+    pub to: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SnippetDiff {
+    pub from: i64,
+    pub to: i64,
+    pub diff: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct VersionResponse {
+    pub version: String,
+}
+
+// Sent as the data of the events that end a stream early, like `not_found` and `timeout`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StreamError {
+    pub error: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReloadResponse {
+    // Changed settings that only take effect after a restart
+    #[serde(rename = "restartRequired")]
+    pub restart_required: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegisterWorkerRequest {
+    pub name: String,
+    // How many jobs the worker runs at once
+    pub slots: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegisterWorkerResponse {
+    #[serde(rename = "workerId")]
+    pub worker_id: usize,
+    // Jobs the worker doesn't send a heartbeat for within this long are failed
+    #[serde(rename = "leaseSecs")]
+    pub lease_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WorkerHeartbeat {
+    // The jobs the worker is still running, whose leases are renewed
+    pub jobs: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StageReport {
+    pub state: JobState,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RemoteResult {
+    // Completed or failed
+    pub state: JobState,
+    pub result: JobResult,
+    #[serde(rename = "cpuSeconds")]
+    pub cpu_seconds: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HeartbeatResponse {
+    // Jobs the worker listed that it should stop running, because they were cancelled or
+    // their lease ran out
+    pub abandoned: Vec<Uuid>,
+}
+
+// A job as operators see it. The code and result are only included when looking at a single
+// job.
+#[derive(Debug, Serialize)]
+pub struct JobDetails {
+    #[serde(flatten)]
+    pub status: JobStatus,
+    pub client: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<JobResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JobsQuery {
+    // Only list jobs in this state; unfinished jobs are listed by default
+    pub state: Option<JobState>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueStatus {
+    pub paused: bool,
+    pub queued: usize,
+    #[serde(rename = "batchQueued")]
+    pub batch_queued: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LocalWorkersStatus {
+    pub count: usize,
+    pub min: usize,
+    pub max: usize,
+    pub busy: usize,
+    // The job each busy worker is running, by worker index
+    pub jobs: HashMap<usize, Uuid>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RemoteWorkerStatus {
+    pub id: usize,
+    pub name: String,
+    pub slots: usize,
+    #[serde(rename = "lastSeenSecs")]
+    pub last_seen_secs: u64,
+    pub jobs: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkersStatus {
+    pub local: LocalWorkersStatus,
+    pub remote: Vec<RemoteWorkerStatus>,
+}
+
+// A client that is refused service. Clients are identified like in job listings: `key:<name>`
+// for API keys, or an IP address.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub client: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+    // Unix timestamp in seconds
+    #[serde(default, rename = "createdAt")]
+    pub created_at: i64,
+}
+
 /////
 
 #[derive(Clone)]
 pub struct AppState {
-    pub work_queue: async_channel::Sender<Job>,
-    pub results: Results,
+    pub work_queue: Arc<Scheduler>,
+    pub jobs: Jobs,
+    pub batches: Batches,
+    pub workers: Arc<Workers>,
+    pub remote_workers: Arc<RemoteWorkers>,
+    pub bans: Arc<Bans>,
+    pub config: Arc<Reloadable<Config>>,
+    // Limits the number of interactive sessions running at once
+    pub sessions: Arc<Semaphore>,
+    pub api_keys: Arc<Reloadable<ApiKeys>>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub metrics: Arc<Metrics>,
+    pub readiness: ReadinessCache,
+    pub shutdown: Shutdown,
+    pub db: Db,
+}
+
+#[cfg(test)]
+impl AppState {
+    // A server with the given configuration, an in-memory database, no API keys and no
+    // workers, for handler tests.
+    pub fn for_tests(config: Config) -> Self {
+        let db = crate::db::open(":memory:").unwrap();
+        let scheduler = Arc::new(Scheduler::new(config.queue.depth, config.queue.batch_depth));
+        let jobs = crate::jobs::new(None);
+        let metrics = Arc::new(Metrics::new());
+        let rate_limiter = Arc::new(RateLimiter::default());
+        let api_keys = ApiKeys::load("/nonexistent", &config.anonymous).unwrap();
+        let config = Arc::new(Reloadable::new(config));
+        AppState {
+            workers: Workers::new(
+                scheduler.clone(),
+                jobs.clone(),
+                metrics.clone(),
+                rate_limiter.clone(),
+                config.clone(),
+            ),
+            work_queue: scheduler,
+            jobs,
+            batches: crate::batches::new(),
+            remote_workers: Arc::new(RemoteWorkers::default()),
+            bans: Arc::new(Bans::default()),
+            sessions: Arc::new(Semaphore::new(config.get().sessions.max)),
+            config,
+            api_keys: Arc::new(Reloadable::new(api_keys)),
+            rate_limiter,
+            metrics,
+            readiness: crate::health::new_cache(),
+            shutdown: Shutdown::new(),
+            db,
+        }
+    }
 }
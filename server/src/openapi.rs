@@ -0,0 +1,102 @@
+use axum::Router;
+use once_cell::sync::Lazy;
+use utoipa::{
+    Modify, OpenApi,
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+};
+use utoipa_scalar::{Scalar, Servable};
+
+use crate::{
+    error::{ErrorCode, ErrorResponse},
+    handlers,
+    models::{
+        BatchItem, BatchProgress, JobResult, JobStatus, OutputEvent, OutputStream, PendingEvent,
+        SessionEvent, SessionRequest, StreamError,
+    },
+};
+
+// The OpenAPI description of the public API, generated from the handlers and models. The
+// admin and worker APIs are left out, since they aren't meant for clients.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Zirco Playground API",
+        description = "Compiles and runs Zirco code in a sandbox.\n\n\
+                       Clients may identify themselves with an API key for higher limits, \
+                       otherwise they are limited by IP address. Every failed request is \
+                       answered with an `ErrorResponse`, whose `code` tells what went wrong, \
+                       and every response carries an `X-Request-Id` header."
+    ),
+    paths(
+        handlers::execute_code,
+        handlers::run_code,
+        handlers::get_job,
+        handlers::cancel_job,
+        handlers::get_results,
+        handlers::stream_results,
+        handlers::submit_batch,
+        handlers::get_batch,
+        handlers::stream_batch,
+        handlers::interactive_session,
+        handlers::create_snippet,
+        handlers::get_snippet,
+        handlers::save_snippet_revision,
+        handlers::list_snippet_revisions,
+        handlers::diff_snippet_revisions,
+        handlers::fork_snippet,
+        handlers::get_version,
+    ),
+    // Sent in event streams and WebSocket messages, which the paths can't refer to
+    components(schemas(
+        JobStatus,
+        JobResult,
+        PendingEvent,
+        OutputEvent,
+        OutputStream,
+        BatchItem,
+        BatchProgress,
+        StreamError,
+        SessionRequest,
+        SessionEvent,
+        ErrorResponse,
+        ErrorCode,
+    )),
+    modifiers(&ApiKeys),
+    security((), ("bearer" = []), ("apiKey" = [])),
+    tags(
+        (name = "jobs", description = "Running code"),
+        (name = "batches", description = "Running many programs at once"),
+        (name = "sessions", description = "Running programs interactively"),
+        (name = "snippets", description = "Sharing code"),
+        (name = "toolchain"),
+    )
+)]
+struct ApiDoc;
+
+// API keys can be sent either way, see `client::Client`.
+struct ApiKeys;
+
+impl Modify for ApiKeys {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        components.add_security_scheme(
+            "apiKey",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Api-Key"))),
+        );
+    }
+}
+
+static SPEC: Lazy<utoipa::openapi::OpenApi> = Lazy::new(ApiDoc::openapi);
+
+pub fn spec() -> &'static utoipa::openapi::OpenApi {
+    &SPEC
+}
+
+// Serves an interactive viewer for the specification at `path`.
+pub fn viewer<S: Clone + Send + Sync + 'static>(path: &'static str) -> Router<S> {
+    Scalar::with_url(path, spec().clone()).into()
+}
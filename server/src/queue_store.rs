@@ -0,0 +1,227 @@
+use std::{
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{Connection, params};
+use uuid::Uuid;
+
+use crate::{
+    api_keys::Tier,
+    models::{Job, JobState, TaskType},
+};
+
+// The on-disk copy of the job queue, kept when `queue.durable` is set. Every accepted job is
+// written here before it is queued, and marked as started before a worker runs it. Jobs that
+// were still queued when the server stopped are queued again on the next start, while jobs
+// that had started are never run a second time.
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+// A job as it was stored, and what became of it.
+#[derive(Debug)]
+pub struct StoredJob {
+    pub job: Job,
+    pub state: JobState,
+    pub started: bool,
+}
+
+pub fn insert(conn: &Connection, job: &Job) -> rusqlite::Result<()> {
+    // Deadlines are kept as wall clock time, which is what survives a restart
+    let deadline = now_ms()
+        + job
+            .deadline
+            .saturating_duration_since(Instant::now())
+            .as_millis() as i64;
+    conn.execute(
+        "INSERT INTO queued_jobs (id, client, tier, task, code, batch_id, deadline, state)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            job.id.to_string(),
+            job.client,
+            serde_json::to_string(&*job.tier).expect("tiers are always serializable"),
+            job.task_type.as_str(),
+            job.code,
+            job.batch.map(|batch| batch.to_string()),
+            deadline,
+            JobState::Queued.as_str(),
+        ],
+    )?;
+    Ok(())
+}
+
+// Records that a worker is about to run a job. Returns false if it isn't a queued job.
+pub fn mark_started(conn: &Connection, id: Uuid) -> rusqlite::Result<bool> {
+    let changed = conn.execute(
+        "UPDATE queued_jobs SET started_at = ?2 WHERE id = ?1 AND started_at IS NULL",
+        params![id.to_string(), now_ms()],
+    )?;
+    Ok(changed == 1)
+}
+
+// Forgets that a job started, once it is back in the queue to be run again.
+pub fn mark_requeued(conn: &Connection, id: Uuid) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE queued_jobs SET started_at = NULL WHERE id = ?1",
+        params![id.to_string()],
+    )?;
+    Ok(())
+}
+
+pub fn mark_finished(conn: &Connection, id: Uuid, state: JobState) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE queued_jobs SET state = ?2 WHERE id = ?1",
+        params![id.to_string(), state.as_str()],
+    )?;
+    Ok(())
+}
+
+pub fn delete(conn: &Connection, id: Uuid) -> rusqlite::Result<()> {
+    conn.execute(
+        "DELETE FROM queued_jobs WHERE id = ?1",
+        params![id.to_string()],
+    )?;
+    Ok(())
+}
+
+fn parse_state(state: &str) -> JobState {
+    match state {
+        "queued" => JobState::Queued,
+        "completed" => JobState::Completed,
+        "failed" => JobState::Failed,
+        "cancelled" => JobState::Cancelled,
+        // Nothing else is ever stored
+        _ => JobState::Expired,
+    }
+}
+
+fn parse_uuid(column: usize, value: &str) -> rusqlite::Result<Uuid> {
+    Uuid::parse_str(value).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text, e.into())
+    })
+}
+
+// Loads every stored job, in the order they were accepted, and forgets the ones that had
+// already finished.
+pub fn take_all(conn: &mut Connection) -> rusqlite::Result<Vec<StoredJob>> {
+    let tx = conn.transaction()?;
+    let jobs = {
+        let mut stmt = tx.prepare(
+            "SELECT id, client, tier, task, code, batch_id, deadline, started_at, state
+             FROM queued_jobs ORDER BY rowid",
+        )?;
+        stmt.query_map([], |row| {
+            let id: String = row.get(0)?;
+            let tier: String = row.get(2)?;
+            let task: String = row.get(3)?;
+            let batch: Option<String> = row.get(5)?;
+            let deadline: i64 = row.get(6)?;
+            let started_at: Option<i64> = row.get(7)?;
+            let state: String = row.get(8)?;
+
+            let tier: Tier = serde_json::from_str(&tier).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, e.into())
+            })?;
+            let task_type = TaskType::from_str(&task).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, e.into())
+            })?;
+            let remaining = Duration::from_millis(deadline.saturating_sub(now_ms()).max(0) as u64);
+
+            Ok(StoredJob {
+                job: Job {
+                    id: parse_uuid(0, &id)?,
+                    client: row.get(1)?,
+                    tier: tier.into(),
+                    task_type,
+                    code: row.get(4)?,
+                    deadline: Instant::now() + remaining,
+                    batch: batch.map(|batch| parse_uuid(5, &batch)).transpose()?,
+                },
+                state: parse_state(&state),
+                started: started_at.is_some(),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?
+    };
+    tx.execute(
+        "DELETE FROM queued_jobs WHERE state != ?1",
+        params![JobState::Queued.as_str()],
+    )?;
+    tx.commit()?;
+    Ok(jobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn job(batch: Option<Uuid>) -> Job {
+        Job {
+            id: Uuid::new_v4(),
+            client: "client".to_string(),
+            tier: Tier {
+                priority: 3,
+                ..Tier::default()
+            }
+            .into(),
+            task_type: TaskType::Llvm,
+            code: "fn main() -> i32 { return 0; }".to_string(),
+            deadline: Instant::now() + Duration::from_secs(60),
+            batch,
+        }
+    }
+
+    #[test]
+    fn jobs_round_trip() {
+        let db = db::open(":memory:").unwrap();
+        let mut conn = db.lock().unwrap();
+        let batch = Some(Uuid::new_v4());
+        let (queued, started, finished) = (job(None), job(batch), job(None));
+        for job in [&queued, &started, &finished] {
+            insert(&conn, job).unwrap();
+        }
+
+        assert!(mark_started(&conn, started.id).unwrap());
+        assert!(!mark_started(&conn, started.id).unwrap());
+        assert!(!mark_started(&conn, Uuid::new_v4()).unwrap());
+        mark_started(&conn, finished.id).unwrap();
+        mark_finished(&conn, finished.id, JobState::Completed).unwrap();
+
+        let stored = take_all(&mut conn).unwrap();
+        let summary: Vec<_> = stored
+            .iter()
+            .map(|stored| (stored.job.id, stored.state, stored.started))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (queued.id, JobState::Queued, false),
+                (started.id, JobState::Queued, true),
+                (finished.id, JobState::Completed, true),
+            ]
+        );
+
+        let restored = &stored[1].job;
+        assert_eq!(restored.client, started.client);
+        assert_eq!(restored.tier.priority, 3);
+        assert_eq!(restored.task_type, TaskType::Llvm);
+        assert_eq!(restored.code, started.code);
+        assert_eq!(restored.batch, batch);
+        let remaining = restored.deadline.saturating_duration_since(Instant::now());
+        assert!(remaining > Duration::from_secs(55), "{remaining:?}");
+
+        // Finished jobs are forgotten once they have been taken
+        let ids: Vec<Uuid> = take_all(&mut conn)
+            .unwrap()
+            .into_iter()
+            .map(|stored| stored.job.id)
+            .collect();
+        assert_eq!(ids, [queued.id, started.id]);
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::api_keys::Tier;
+
+// Token-bucket rate limiter keyed by client, where each client's bucket size and refill
+// rate come from its tier.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    capacity: f64,
+    refill_per_second: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.updated = now;
+    }
+
+    fn status(&self) -> RateLimitStatus {
+        RateLimitStatus {
+            limit: self.capacity as u32,
+            remaining: self.tokens.max(0.0) as u32,
+            reset: secs_f64((self.capacity - self.tokens) / self.refill_per_second),
+        }
+    }
+}
+
+// A client's standing with the rate limiter, as reported in `X-RateLimit-*` headers.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitStatus {
+    pub limit: u32,
+    pub remaining: u32,
+    // Time until the bucket is full again
+    pub reset: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimited {
+    pub status: RateLimitStatus,
+    pub retry_after: Duration,
+}
+
+fn secs_f64(secs: f64) -> Duration {
+    Duration::try_from_secs_f64(secs.max(0.0)).unwrap_or(Duration::MAX)
+}
+
+impl RateLimiter {
+    fn with_bucket<T>(&self, client: &str, tier: &Tier, f: impl FnOnce(&mut Bucket) -> T) -> T {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let bucket = buckets.entry(client.to_string()).or_insert_with(|| Bucket {
+            tokens: tier.burst as f64,
+            capacity: tier.burst as f64,
+            refill_per_second: tier.requests_per_second,
+            updated: now,
+        });
+
+        bucket.capacity = tier.burst as f64;
+        bucket.refill_per_second = tier.requests_per_second;
+        bucket.refill(now);
+        f(bucket)
+    }
+
+    // Takes `cost` tokens from the client's bucket, or reports how long until enough tokens
+    // are available.
+    pub fn check(
+        &self,
+        client: &str,
+        tier: &Tier,
+        cost: f64,
+    ) -> Result<RateLimitStatus, RateLimited> {
+        self.with_bucket(client, tier, |bucket| {
+            if bucket.tokens >= cost {
+                bucket.tokens -= cost;
+                return Ok(bucket.status());
+            }
+
+            Err(RateLimited {
+                status: bucket.status(),
+                retry_after: secs_f64((cost - bucket.tokens) / bucket.refill_per_second),
+            })
+        })
+    }
+
+    // Charges the client for resources used after the fact. This can leave the bucket in
+    // debt, which the client has to wait out before its next request.
+    pub fn debit(&self, client: &str, tier: &Tier, cost: f64) {
+        self.with_bucket(client, tier, |bucket| bucket.tokens -= cost);
+    }
+
+    // Gives back tokens taken for work that was turned away after all, up to a full bucket.
+    pub fn refund(&self, client: &str, tier: &Tier, cost: f64) {
+        self.with_bucket(client, tier, |bucket| {
+            bucket.tokens = (bucket.tokens + cost).min(bucket.capacity);
+        });
+    }
+
+    // Forgets clients whose buckets have refilled completely, since a full bucket is the same
+    // as no bucket at all.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.buckets
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|_, bucket| {
+                bucket.refill(now);
+                bucket.tokens < bucket.capacity
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tier(burst: u32, requests_per_second: f64) -> Tier {
+        Tier {
+            burst,
+            requests_per_second,
+            ..Tier::default()
+        }
+    }
+
+    #[test]
+    fn buckets_refill_up_to_their_capacity() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: -1.0,
+            capacity: 4.0,
+            refill_per_second: 2.0,
+            updated: start,
+        };
+
+        bucket.refill(start + Duration::from_secs(1));
+        assert_eq!(bucket.tokens, 1.0);
+        let status = bucket.status();
+        assert_eq!((status.limit, status.remaining), (4, 1));
+        assert_eq!(status.reset, Duration::from_millis(1500));
+
+        bucket.refill(start + Duration::from_secs(10));
+        assert_eq!(bucket.tokens, 4.0);
+    }
+
+    #[test]
+    fn check_takes_tokens_until_the_bucket_is_empty() {
+        let limiter = RateLimiter::default();
+        // Slow enough that nothing refills while the test runs
+        let tier = tier(2, 0.001);
+
+        assert_eq!(limiter.check("a", &tier, 1.5).unwrap().remaining, 0);
+        let limited = limiter.check("a", &tier, 1.0).unwrap_err();
+        assert_eq!(limited.status.remaining, 0);
+        // Half a token short, at a thousandth of a token per second
+        let retry_after = limited.retry_after.as_secs_f64();
+        assert!((499.0..=500.0).contains(&retry_after), "{retry_after}");
+
+        // Every client has a bucket of its own
+        assert!(limiter.check("b", &tier, 2.0).is_ok());
+    }
+
+    #[test]
+    fn debits_leave_the_bucket_in_debt() {
+        let limiter = RateLimiter::default();
+        let tier = tier(2, 1.0);
+
+        limiter.debit("a", &tier, 5.0);
+        let limited = limiter.check("a", &tier, 1.0).unwrap_err();
+        // Three tokens of debt plus the one we asked for
+        let retry_after = limited.retry_after.as_secs_f64();
+        assert!((3.9..=4.0).contains(&retry_after), "{retry_after}");
+    }
+
+    #[test]
+    fn refunds_stop_at_a_full_bucket() {
+        let limiter = RateLimiter::default();
+        let tier = tier(2, 0.001);
+
+        limiter.check("a", &tier, 2.0).unwrap();
+        limiter.refund("a", &tier, 1.5);
+        assert_eq!(limiter.check("a", &tier, 1.5).unwrap().remaining, 0);
+        limiter.refund("a", &tier, 10.0);
+        assert_eq!(limiter.check("a", &tier, 0.0).unwrap().remaining, 2);
+    }
+
+    #[test]
+    fn prune_forgets_full_buckets() {
+        let limiter = RateLimiter::default();
+        limiter.check("refilled", &tier(2, 1e12), 1.0).unwrap();
+        limiter.check("empty", &tier(2, 0.001), 1.0).unwrap();
+
+        limiter.prune();
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(!buckets.contains_key("refilled"));
+        assert!(buckets.contains_key("empty"));
+    }
+}
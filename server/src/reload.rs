@@ -0,0 +1,59 @@
+use tokio::signal::unix::{SignalKind, signal};
+use tracing::{error, info, warn};
+
+use crate::{
+    api_keys::ApiKeys,
+    config::{self, Config},
+    models::AppState,
+};
+
+// Reloads the configuration file and the API keys it points to, and applies them to new
+// jobs. Nothing changes unless both load successfully. Returns the settings that changed but
+// only take effect after a restart.
+pub async fn reload(state: &AppState) -> Result<Vec<&'static str>, String> {
+    // Reading the files blocks, so it happens on the blocking thread pool
+    let (config, api_keys) = tokio::task::spawn_blocking(|| {
+        let config = Config::load(&config::path())?;
+        let api_keys = ApiKeys::load(&config.paths.api_keys, &config.anonymous)?;
+        Ok::<_, String>((config, api_keys))
+    })
+    .await
+    .expect("reload task panicked")?;
+
+    let restart_required = state.config.get().restart_required(&config);
+    if !restart_required.is_empty() {
+        warn!(
+            "Changes to {} take effect after a restart",
+            restart_required.join(", ")
+        );
+    }
+
+    // The autoscaler takes it from there
+    let count = state
+        .workers
+        .count()
+        .clamp(config.workers.min, config.workers.max);
+    state.api_keys.set(api_keys);
+    state.config.set(config);
+    state.workers.scale(count);
+
+    info!("Configuration reloaded");
+    Ok(restart_required)
+}
+
+pub async fn on_sighup(state: AppState) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP: {e}");
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading configuration");
+        if let Err(e) = reload(&state).await {
+            error!("Failed to reload configuration: {e}");
+        }
+    }
+}
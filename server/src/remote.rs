@@ -0,0 +1,584 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    error::{ApiError, ErrorCode},
+    jobs,
+    models::{AppState, Job, JobResult, JobSpec, JobState, RemoteResult, RemoteWorkerStatus},
+    sandbox,
+    scheduler::RunningSlot,
+};
+
+// How long a worker's request for a job is held open at most when nothing is queued
+const MAX_POLL: Duration = Duration::from_secs(20);
+
+// How often expired leases are looked for
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Worker processes that pull jobs from this server over HTTP (see `remote_worker` for the
+// other end). Every job handed to one is leased to it: the worker renews its leases with
+// heartbeats while it runs the jobs, and a job whose lease runs out is queued again for
+// another worker. A worker that isn't heard from for as long is forgotten.
+#[derive(Default)]
+pub struct RemoteWorkers {
+    nodes: Mutex<Nodes>,
+    // Notified whenever a lease ends
+    released: Notify,
+}
+
+#[derive(Default)]
+struct Nodes {
+    next_id: usize,
+    nodes: HashMap<usize, Node>,
+}
+
+struct Node {
+    name: String,
+    slots: usize,
+    last_seen: Instant,
+    leases: HashMap<Uuid, Lease>,
+}
+
+// A job running on a remote worker, kept to account for it once it finishes or to queue it
+// again if the worker is lost.
+pub struct Lease {
+    expires: Instant,
+    pub job: Job,
+    // Counts the job against its client's running limit until the lease ends
+    _slot: RunningSlot,
+}
+
+impl RemoteWorkers {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Nodes> {
+        self.nodes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn register(&self, name: String, slots: usize) -> usize {
+        let mut nodes = self.lock();
+        nodes.next_id += 1;
+        let id = nodes.next_id;
+        info!("Remote worker {id} ({name}) registered with {slots} slots");
+        nodes.nodes.insert(
+            id,
+            Node {
+                name,
+                slots,
+                last_seen: Instant::now(),
+                leases: HashMap::new(),
+            },
+        );
+        id
+    }
+
+    // Forgets a worker, returning the leases it still held.
+    pub fn deregister(&self, id: usize) -> Option<Vec<(Uuid, Lease)>> {
+        let node = self.lock().nodes.remove(&id)?;
+        info!("Remote worker {id} ({}) deregistered", node.name);
+        self.released.notify_waiters();
+        Some(node.leases.into_iter().collect())
+    }
+
+    // Records that a worker is alive, renewing its leases on the given jobs. Returns the jobs
+    // it should stop running, since it no longer holds their lease, or None if the worker is
+    // unknown, in which case it should register again.
+    pub fn heartbeat(&self, id: usize, running: &[Uuid], lease: Duration) -> Option<Vec<Uuid>> {
+        let mut nodes = self.lock();
+        let node = nodes.nodes.get_mut(&id)?;
+        let now = Instant::now();
+        node.last_seen = now;
+        // Leases missing from the list are left to run out, since the worker may simply not
+        // have received the job yet
+        let mut abandoned = Vec::new();
+        for job in running {
+            match node.leases.get_mut(job) {
+                Some(lease_entry) => lease_entry.expires = now + lease,
+                None => abandoned.push(*job),
+            }
+        }
+        Some(abandoned)
+    }
+
+    // Leases a job to a worker. Returns the worker's name, or None if it is unknown.
+    fn lease(&self, id: usize, job: &Job, slot: RunningSlot, lease: Duration) -> Option<String> {
+        let mut nodes = self.lock();
+        let node = nodes.nodes.get_mut(&id)?;
+        node.leases.insert(
+            job.id,
+            Lease {
+                expires: Instant::now() + lease,
+                job: job.clone(),
+                _slot: slot,
+            },
+        );
+        Some(node.name.clone())
+    }
+
+    // Whether a worker holds the lease on a job, renewing it if it does.
+    fn renew(&self, id: usize, job: Uuid, lease: Duration) -> bool {
+        let mut nodes = self.lock();
+        let Some(node) = nodes.nodes.get_mut(&id) else {
+            return false;
+        };
+        let now = Instant::now();
+        node.last_seen = now;
+        match node.leases.get_mut(&job) {
+            Some(lease_entry) => {
+                lease_entry.expires = now + lease;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Ends a worker's lease on a job, returning it if the worker held it.
+    fn release(&self, id: usize, job: Uuid) -> Option<Lease> {
+        let lease = self.lock().nodes.get_mut(&id)?.leases.remove(&job)?;
+        self.released.notify_waiters();
+        Some(lease)
+    }
+
+    // Ends the lease on a job that was cancelled, whichever worker holds it. The worker is
+    // told to stop the job with its next heartbeat.
+    pub fn abandon(&self, job: Uuid) -> bool {
+        let released = self
+            .lock()
+            .nodes
+            .values_mut()
+            .any(|node| node.leases.remove(&job).is_some());
+        if released {
+            self.released.notify_waiters();
+        }
+        released
+    }
+
+    // Ends every lease, for when the jobs have been failed some other way.
+    pub fn release_all(&self) {
+        for node in self.lock().nodes.values_mut() {
+            node.leases.clear();
+        }
+        self.released.notify_waiters();
+    }
+
+    // Takes out the leases that ran out, and those of workers that haven't been heard from
+    // for a whole lease, forgetting those workers.
+    fn expired(&self, lease: Duration) -> Vec<(Uuid, Lease)> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        let mut nodes = self.lock();
+        nodes.nodes.retain(|id, node| {
+            let alive = now.duration_since(node.last_seen) < lease;
+            if !alive {
+                warn!("Lost remote worker {id} ({})", node.name);
+            }
+            let leases = std::mem::take(&mut node.leases);
+            for (job, lease_entry) in leases {
+                if alive && lease_entry.expires > now {
+                    node.leases.insert(job, lease_entry);
+                } else {
+                    expired.push((job, lease_entry));
+                }
+            }
+            alive
+        });
+        drop(nodes);
+
+        if !expired.is_empty() {
+            self.released.notify_waiters();
+        }
+        expired
+    }
+
+    // Number of registered workers, and how many jobs they can run at once.
+    pub fn capacity(&self) -> (usize, usize) {
+        let nodes = self.lock();
+        let slots = nodes.nodes.values().map(|node| node.slots).sum();
+        (nodes.nodes.len(), slots)
+    }
+
+    pub fn list(&self) -> Vec<RemoteWorkerStatus> {
+        let now = Instant::now();
+        let mut workers: Vec<_> = self
+            .lock()
+            .nodes
+            .iter()
+            .map(|(&id, node)| RemoteWorkerStatus {
+                id,
+                name: node.name.clone(),
+                slots: node.slots,
+                last_seen_secs: now.duration_since(node.last_seen).as_secs(),
+                jobs: node.leases.keys().copied().collect(),
+            })
+            .collect();
+        workers.sort_by_key(|worker| worker.id);
+        workers
+    }
+
+    pub fn leased(&self) -> usize {
+        self.lock()
+            .nodes
+            .values()
+            .map(|node| node.leases.len())
+            .sum()
+    }
+
+    // Waits until no job is leased to a remote worker.
+    pub async fn idle(&self) {
+        loop {
+            // Register for wakeups before checking, so we can't miss a lease ending
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            if self.leased() == 0 {
+                return;
+            }
+            released.await;
+        }
+    }
+}
+
+fn failed_result(message: &str) -> JobResult {
+    JobResult {
+        stdout: "".to_string(),
+        stderr: message.to_string(),
+        exit_code: -1,
+    }
+}
+
+// Queues the jobs of leases that ended without a result again, or fails them with `message`
+// if the server is shutting down.
+async fn requeue_leases(state: &AppState, leases: Vec<(Uuid, Lease)>, message: &str) {
+    for (id, lease) in leases {
+        let task = lease.job.task_type;
+        if !state.shutdown.is_started() {
+            if jobs::requeue(&state.jobs, &state.work_queue, lease.job).await {
+                warn!("Queued job {id} again: {message}");
+            }
+            continue;
+        }
+        warn!("Failing job {id}: {message}");
+        if jobs::finish(&state.jobs, id, JobState::Failed, failed_result(message)).await {
+            state.metrics.job_finished(task, JobState::Failed);
+        }
+    }
+}
+
+// Periodically queues the jobs whose lease ran out again.
+pub async fn reap(state: AppState) {
+    loop {
+        tokio::time::sleep(REAP_INTERVAL).await;
+        let expired = state
+            .remote_workers
+            .expired(state.config.get().remote.lease());
+        requeue_leases(
+            &state,
+            expired,
+            "The remote worker running this job stopped responding.",
+        )
+        .await;
+    }
+}
+
+fn unknown_worker() -> ApiError {
+    ApiError::not_found("Unknown worker ID, register again.")
+}
+
+fn shutting_down() -> ApiError {
+    ApiError::new(ErrorCode::ShuttingDown, "The server is shutting down.")
+}
+
+pub async fn deregister(state: &AppState, worker: usize) -> Result<(), ApiError> {
+    let leases = state
+        .remote_workers
+        .deregister(worker)
+        .ok_or_else(unknown_worker)?;
+    requeue_leases(
+        state,
+        leases,
+        "The remote worker running this job shut down.",
+    )
+    .await;
+    Ok(())
+}
+
+// Leases a job that was just taken from the queue to a worker, unless it waited past its
+// deadline or is no longer queued.
+async fn lease_job(
+    state: &AppState,
+    worker: usize,
+    job: Job,
+    slot: RunningSlot,
+) -> Option<JobSpec> {
+    let id = job.id;
+    let task = job.task_type;
+    if job.deadline < Instant::now() {
+        debug!("Remote worker {worker} dropping job {id}, which waited past its deadline");
+        if jobs::expire(&state.jobs, id).await {
+            state.metrics.job_finished(task, JobState::Expired);
+        }
+        return None;
+    }
+
+    let lease = state.config.get().remote.lease();
+    let Some(name) = state.remote_workers.lease(worker, &job, slot, lease) else {
+        // The worker was forgotten while it waited for a job
+        let message = "The remote worker this job was sent to disappeared.";
+        if jobs::finish(&state.jobs, id, JobState::Failed, failed_result(message)).await {
+            state.metrics.job_finished(task, JobState::Failed);
+        }
+        return None;
+    };
+
+    if !jobs::claim(&state.jobs, id, worker, Some(&name)).await {
+        debug!("Remote worker {worker} skipping job {id}, which is no longer queued");
+        state.remote_workers.release(worker, id);
+        return None;
+    }
+
+    debug!("Leased job {id} to remote worker {worker} ({name})");
+    Some(job.into())
+}
+
+// Waits for the next job for a worker, for a while. Returns None if there was none.
+pub async fn next_job(state: &AppState, worker: usize) -> Result<Option<JobSpec>, ApiError> {
+    let lease = state.config.get().remote.lease();
+    if state.remote_workers.heartbeat(worker, &[], lease).is_none() {
+        return Err(unknown_worker());
+    }
+    if state.shutdown.is_started() {
+        return Err(shutting_down());
+    }
+
+    // Answer well within the lease, so a worker waiting for jobs isn't taken for dead
+    let timeout = tokio::time::sleep(MAX_POLL.min(lease / 2));
+    tokio::pin!(timeout);
+    loop {
+        let (job, slot) = tokio::select! {
+            next = state.work_queue.pop() => next,
+            () = &mut timeout => return Ok(None),
+            () = state.shutdown.started() => return Err(shutting_down()),
+        };
+
+        // The job is out of the queue now, so it has to be leased even if the worker hangs up
+        let leased = tokio::spawn({
+            let state = state.clone();
+            async move { lease_job(&state, worker, job, slot).await }
+        })
+        .await
+        .map_err(|_| ApiError::internal())?;
+
+        if leased.is_some() {
+            return Ok(leased);
+        }
+    }
+}
+
+fn not_leased() -> ApiError {
+    ApiError::not_found("This worker doesn't hold the lease on that job.")
+}
+
+// Moves a leased job to the next stage, as reported by its worker.
+pub async fn report_stage(
+    state: &AppState,
+    worker: usize,
+    id: Uuid,
+    stage: JobState,
+) -> Result<(), ApiError> {
+    if !matches!(
+        stage,
+        JobState::Compiling | JobState::Linking | JobState::Running
+    ) {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            "Only the compiling, linking and running stages can be reported.",
+        ));
+    }
+    let lease = state.config.get().remote.lease();
+    if !state.remote_workers.renew(worker, id, lease) {
+        return Err(not_leased());
+    }
+    jobs::set_state(&state.jobs, id, stage).await;
+    Ok(())
+}
+
+// Records the result of a leased job, like a local worker does once it is done with one.
+pub async fn finish_job(
+    state: &AppState,
+    worker: usize,
+    id: Uuid,
+    result: RemoteResult,
+) -> Result<(), ApiError> {
+    if !matches!(result.state, JobState::Completed | JobState::Failed) {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            "A job can only finish as completed or failed.",
+        ));
+    }
+    let lease = state
+        .remote_workers
+        .release(worker, id)
+        .ok_or_else(not_leased)?;
+
+    // Charge the client for the CPU time the job actually used
+    let cpu_seconds = result.cpu_seconds.max(0.0);
+    let job = &lease.job;
+    state.rate_limiter.debit(
+        &job.client,
+        &job.tier,
+        cpu_seconds * job.tier.cpu_second_cost,
+    );
+    debug!("Job {id} used {cpu_seconds}s of CPU time on remote worker {worker}");
+
+    let output = sandbox::truncate_output(result.result, job.tier.max_output_bytes);
+    if !jobs::finish(&state.jobs, id, result.state, output).await {
+        return Ok(());
+    }
+
+    state.metrics.job_finished(job.task_type, result.state);
+    if let Some(status) = jobs::status(&state.jobs, id).await {
+        state.metrics.observe_stages(&status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{http::StatusCode, response::IntoResponse};
+
+    use super::*;
+    use crate::{api_keys::Tier, config::Config, models::TaskType};
+
+    fn state() -> AppState {
+        let mut config = Config::default();
+        config.remote.token = "secret".to_string();
+        AppState::for_tests(config)
+    }
+
+    async fn submit(state: &AppState) -> Uuid {
+        let job = Job {
+            id: Uuid::new_v4(),
+            client: "client".to_string(),
+            tier: Arc::new(Tier::default()),
+            task_type: TaskType::Execute,
+            code: "fn main() {}".to_string(),
+            deadline: Instant::now() + Duration::from_secs(60),
+            batch: None,
+        };
+        let id = job.id;
+        jobs::insert(&state.jobs, &job).await.unwrap();
+        state.work_queue.try_push(job).unwrap();
+        id
+    }
+
+    fn completed() -> RemoteResult {
+        RemoteResult {
+            state: JobState::Completed,
+            result: JobResult {
+                stdout: "done\n".to_string(),
+                ..JobResult::default()
+            },
+            cpu_seconds: 0.5,
+        }
+    }
+
+    async fn state_of(state: &AppState, id: Uuid) -> JobState {
+        jobs::status(&state.jobs, id).await.unwrap().state
+    }
+
+    fn status_code(error: ApiError) -> StatusCode {
+        error.into_response().status()
+    }
+
+    #[tokio::test]
+    async fn lost_workers_jobs_are_queued_again() {
+        let state = state();
+        let id = submit(&state).await;
+        let lost = state.remote_workers.register("lost".to_string(), 1);
+
+        let spec = next_job(&state, lost).await.unwrap().unwrap();
+        assert_eq!(spec.id, id);
+        report_stage(&state, lost, id, JobState::Running)
+            .await
+            .unwrap();
+        assert_eq!(state.remote_workers.leased(), 1);
+
+        // The worker stops sending heartbeats, and the reaper finds its lease run out
+        let expired = state.remote_workers.expired(Duration::ZERO);
+        requeue_leases(&state, expired, "lost").await;
+        let status = jobs::status(&state.jobs, id).await.unwrap();
+        assert_eq!(status.state, JobState::Queued);
+        assert_eq!((status.worker, status.node), (None, None));
+        assert_eq!(state.work_queue.position(id), Some(0));
+        assert!(
+            state
+                .remote_workers
+                .heartbeat(lost, &[id], Duration::MAX)
+                .is_none()
+        );
+
+        // Whatever the lost worker sends later is turned away
+        let late = finish_job(&state, lost, id, completed()).await.unwrap_err();
+        assert_eq!(status_code(late), StatusCode::NOT_FOUND);
+        assert_eq!(state_of(&state, id).await, JobState::Queued);
+        assert!(jobs::result(&state.jobs, id).await.is_none());
+
+        let other = state.remote_workers.register("other".to_string(), 1);
+        assert_eq!(next_job(&state, other).await.unwrap().unwrap().id, id);
+        finish_job(&state, other, id, completed()).await.unwrap();
+        assert_eq!(state_of(&state, id).await, JobState::Completed);
+        assert_eq!(
+            jobs::result(&state.jobs, id).await.unwrap().stdout,
+            "done\n"
+        );
+        assert_eq!(state.remote_workers.leased(), 0);
+    }
+
+    #[tokio::test]
+    async fn heartbeats_renew_only_the_leases_a_worker_holds() {
+        let state = state();
+        let id = submit(&state).await;
+        let worker = state.remote_workers.register("node".to_string(), 2);
+        next_job(&state, worker).await.unwrap().unwrap();
+        let status = jobs::status(&state.jobs, id).await.unwrap();
+        assert_eq!(status.node.as_deref(), Some("node"));
+
+        // Jobs the worker doesn't hold a lease on are to be stopped
+        let stray = Uuid::new_v4();
+        let lease = Duration::from_secs(30);
+        let stop = state.remote_workers.heartbeat(worker, &[id, stray], lease);
+        assert_eq!(stop, Some(vec![stray]));
+        assert!(state.remote_workers.expired(lease).is_empty());
+
+        let not_held = report_stage(&state, worker, stray, JobState::Running).await;
+        assert_eq!(status_code(not_held.unwrap_err()), StatusCode::NOT_FOUND);
+        let invalid = report_stage(&state, worker, id, JobState::Completed).await;
+        assert_eq!(
+            status_code(invalid.unwrap_err()),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        let unfinished = RemoteResult {
+            state: JobState::Running,
+            ..completed()
+        };
+        let invalid = finish_job(&state, worker, id, unfinished).await;
+        assert_eq!(
+            status_code(invalid.unwrap_err()),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        // A worker that shuts down hands its jobs back
+        deregister(&state, worker).await.unwrap();
+        assert_eq!(state_of(&state, id).await, JobState::Queued);
+        let unknown = next_job(&state, worker).await.unwrap_err();
+        assert_eq!(status_code(unknown), StatusCode::NOT_FOUND);
+    }
+}
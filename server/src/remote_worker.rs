@@ -0,0 +1,309 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use reqwest::StatusCode;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::{sync::Notify, task::JoinSet};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    config::Config,
+    models::{
+        HeartbeatResponse, JobResult, JobSpec, JobState, RegisterWorkerRequest,
+        RegisterWorkerResponse, RemoteResult, StageReport, WorkerHeartbeat,
+    },
+    sandbox, shutdown,
+};
+
+// How long to wait before trying again when the server can't be reached
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+// How many times sending a job's result is attempted before giving up on it
+const RESULT_ATTEMPTS: u32 = 5;
+
+// Runs this process as a remote worker: it registers with the API server in
+// `remote.server_url`, pulls jobs from it and runs them in the local sandbox, reporting each
+// stage and the result back. On SIGTERM or SIGINT it stops pulling jobs, finishes the ones
+// it is running and deregisters.
+pub async fn main(config: Config) {
+    let name = if config.remote.name.is_empty() {
+        std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|name| name.trim().to_string())
+            .unwrap_or_else(|_| "worker".to_string())
+    } else {
+        config.remote.name.clone()
+    };
+
+    let node = Arc::new(Node {
+        http: reqwest::Client::new(),
+        base: format!(
+            "{}/api/v1/workers",
+            config.remote.server_url.trim_end_matches('/')
+        ),
+        name,
+        id: AtomicUsize::new(0),
+        lease: Mutex::new(Duration::from_secs(config.remote.lease_secs)),
+        running: Mutex::new(HashMap::new()),
+        stopping: AtomicBool::new(false),
+        config,
+    });
+
+    node.register(0).await;
+    tokio::spawn(heartbeat(node.clone()));
+
+    let mut slots = JoinSet::new();
+    for slot in 0..node.config.remote.slots {
+        slots.spawn(run_slot(node.clone(), slot));
+    }
+
+    shutdown::terminate().await;
+    node.stopping.store(true, Ordering::SeqCst);
+    info!("Finishing running jobs");
+    slots.join_all().await;
+
+    let id = node.id.load(Ordering::SeqCst);
+    if let Err(e) = node
+        .http
+        .delete(format!("{}/{id}", node.base))
+        .bearer_auth(node.token())
+        .send()
+        .await
+    {
+        warn!("Failed to deregister: {e}");
+    }
+    info!("Shut down");
+}
+
+struct Node {
+    http: reqwest::Client,
+    // The worker API on the server
+    base: String,
+    name: String,
+    // The id the server knows us by, 0 until registered
+    id: AtomicUsize,
+    lease: Mutex<Duration>,
+    // Jobs currently running, whose leases the heartbeat renews, and how to stop each of them
+    running: Mutex<HashMap<Uuid, Arc<Notify>>>,
+    stopping: AtomicBool,
+    config: Config,
+}
+
+// Why a request to the server failed.
+enum RequestError {
+    // The server doesn't know this worker (anymore), or the job it was about
+    NotFound,
+    Other(String),
+}
+
+impl Node {
+    fn token(&self) -> &str {
+        &self.config.remote.token
+    }
+
+    fn lease(&self) -> Duration {
+        *self.lease.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn running(&self) -> std::sync::MutexGuard<'_, HashMap<Uuid, Arc<Notify>>> {
+        self.running.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Posts `body` to `path` under the worker API, returning the response body if there is
+    // one.
+    async fn post<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &impl Serialize,
+    ) -> Result<Option<T>, RequestError> {
+        let response = self
+            .http
+            .post(format!("{}{path}", self.base))
+            .bearer_auth(self.token())
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| RequestError::Other(e.to_string()))?;
+
+        match response.status() {
+            StatusCode::NO_CONTENT => Ok(None),
+            StatusCode::NOT_FOUND => Err(RequestError::NotFound),
+            status if status.is_success() => response
+                .json()
+                .await
+                .map(Some)
+                .map_err(|e| RequestError::Other(e.to_string())),
+            status => Err(RequestError::Other(format!("server answered {status}"))),
+        }
+    }
+
+    // Registers with the server, unless someone already registered again since `stale`
+    // stopped being known to it. Retries until it succeeds.
+    async fn register(&self, stale: usize) {
+        // Only the first of the slots that noticed does it
+        if self.id.load(Ordering::SeqCst) != stale {
+            return;
+        }
+
+        let request = RegisterWorkerRequest {
+            name: self.name.clone(),
+            slots: self.config.remote.slots,
+        };
+        loop {
+            match self.post::<RegisterWorkerResponse>("", &request).await {
+                Ok(Some(registered)) => {
+                    if self
+                        .id
+                        .compare_exchange(
+                            stale,
+                            registered.worker_id,
+                            Ordering::SeqCst,
+                            Ordering::SeqCst,
+                        )
+                        .is_ok()
+                    {
+                        info!(
+                            "Registered with {} as worker {} ({})",
+                            self.config.remote.server_url, registered.worker_id, self.name
+                        );
+                        *self.lease.lock().unwrap_or_else(|e| e.into_inner()) =
+                            Duration::from_secs(registered.lease_secs);
+                    }
+                    return;
+                }
+                Ok(None) | Err(RequestError::NotFound) => {
+                    error!("The server doesn't accept remote workers");
+                }
+                Err(RequestError::Other(e)) => warn!("Failed to register: {e}"),
+            }
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+
+    async fn report_stage(&self, worker: usize, job: Uuid, state: JobState) {
+        let path = format!("/{worker}/jobs/{job}/state");
+        if let Err(RequestError::Other(e)) = self.post::<()>(&path, &StageReport { state }).await {
+            warn!("Failed to report job {job} as {state:?}: {e}");
+        }
+    }
+
+    async fn send_result(&self, worker: usize, job: Uuid, result: RemoteResult) {
+        let path = format!("/{worker}/jobs/{job}/result");
+        for attempt in 1..=RESULT_ATTEMPTS {
+            match self.post::<()>(&path, &result).await {
+                Ok(_) => return,
+                Err(RequestError::NotFound) => {
+                    return warn!("The server gave up on job {job} before it finished");
+                }
+                Err(RequestError::Other(e)) => {
+                    warn!("Failed to send the result of job {job} (attempt {attempt}): {e}");
+                }
+            }
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+        error!("Dropping the result of job {job}");
+    }
+}
+
+// Keeps the worker and the leases on its running jobs alive, and stops the jobs the server
+// gave up on.
+async fn heartbeat(node: Arc<Node>) {
+    loop {
+        tokio::time::sleep(node.lease() / 3).await;
+
+        let id = node.id.load(Ordering::SeqCst);
+        let jobs = node.running().keys().copied().collect();
+        match node
+            .post::<HeartbeatResponse>(&format!("/{id}/heartbeat"), &WorkerHeartbeat { jobs })
+            .await
+        {
+            Ok(response) => {
+                let running = node.running();
+                for job in response.into_iter().flat_map(|r| r.abandoned) {
+                    if let Some(stop) = running.get(&job) {
+                        stop.notify_one();
+                    }
+                }
+            }
+            Err(RequestError::NotFound) => {
+                warn!("The server forgot about this worker, registering again");
+                node.register(id).await;
+            }
+            Err(RequestError::Other(e)) => warn!("Failed to send heartbeat: {e}"),
+        }
+    }
+}
+
+// Pulls jobs and runs them one at a time, until the worker stops.
+async fn run_slot(node: Arc<Node>, slot: usize) {
+    while !node.stopping.load(Ordering::SeqCst) {
+        let id = node.id.load(Ordering::SeqCst);
+        match node.post::<JobSpec>(&format!("/{id}/jobs"), &()).await {
+            Ok(Some(job)) => run_job(&node, id, slot, job).await,
+            Ok(None) => {}
+            Err(RequestError::NotFound) => node.register(id).await,
+            Err(RequestError::Other(e)) => {
+                warn!("Failed to pull a job: {e}");
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn run_job(node: &Node, worker: usize, slot: usize, job: JobSpec) {
+    let id = job.id;
+    let max_output_bytes = job.tier.max_output_bytes;
+    debug!("Slot {slot} received job {id}");
+    let stop = Arc::new(Notify::new());
+    node.running().insert(id, stop.clone());
+
+    let mut cpu_time = Duration::ZERO;
+    let report = |state| node.report_stage(worker, id, state);
+    // The server abandons jobs that were cancelled, which kills their processes
+    let ran = tokio::select! {
+        ran = sandbox::sandboxed_execution(
+            job,
+            &node.config,
+            &mut cpu_time,
+            report,
+            &sandbox::discard_output,
+        ) => ran,
+        () = stop.notified() => {
+            info!("Slot {slot} stopped job {id}, which the server abandoned");
+            let work_dir = format!("{}/{id}", node.config.paths.work_dir);
+            let _ = tokio::fs::remove_dir_all(work_dir).await;
+            node.running().remove(&id);
+            return;
+        }
+    };
+    let (state, result) = match ran {
+        Ok(res) => (JobState::Completed, res),
+        Err(e) => {
+            error!("Slot {slot} failed to execute job {id}: {e}");
+            (
+                JobState::Failed,
+                JobResult {
+                    stdout: "".to_string(),
+                    stderr: format!("Fatal execution error: {e}"),
+                    exit_code: -1,
+                },
+            )
+        }
+    };
+
+    // The server truncates the output too, this just saves sending it
+    let result = RemoteResult {
+        state,
+        result: sandbox::truncate_output(result, max_output_bytes),
+        cpu_seconds: cpu_time.as_secs_f64(),
+    };
+    node.send_result(worker, id, result).await;
+    node.running().remove(&id);
+    debug!("Slot {slot} completed job {id}");
+}
@@ -1,15 +1,376 @@
-use std::{process::Stdio, time::Duration};
+use std::{
+    process::{Output, Stdio},
+    time::Duration,
+};
 
-use tokio::{process::Command, time::timeout};
+use nix::{
+    sys::wait::{Id, WaitPidFlag, waitid},
+    unistd::{Pid, SysconfVar, sysconf},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Command,
+    time::{error::Elapsed, timeout},
+};
 use tracing::debug;
 
-use crate::models::{Job, JobResult};
+use crate::{
+    api_keys::Tier,
+    config::{Config, StepLimits},
+    models::{JobResult, JobSpec, JobState, OutputStream, TaskType},
+};
 
-pub async fn sandboxed_execution(job: Job) -> Result<JobResult, String> {
-    let work_dir = format!("./work/{}", job.id);
-    let source_path = format!("./work/{}/main.zr", job.id);
-    let obj_path = format!("./work/{}/main.o", job.id);
-    let main_path = format!("./work/{}/main", job.id);
+// Runs a command to completion within `limit`, like `timeout(limit, cmd.output())`, adding
+// the CPU time used by the process and the children it reaped to `cpu_time`. A process that
+// times out is killed and charged the whole limit. Only the first `max_output` bytes (plus
+// one, to tell that there was more) of stdout and stderr are kept, and passed to `on_output`
+// as they come.
+pub async fn run_with_limit(
+    limit: Duration,
+    cmd: &mut Command,
+    max_output: usize,
+    on_output: OnOutput<'_>,
+    cpu_time: &mut Duration,
+) -> Result<std::io::Result<Output>, Elapsed> {
+    let mut child = match cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => return Ok(Err(e)),
+    };
+    let pid = child.id().map(|id| Pid::from_raw(id as i32));
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+
+    let run = async {
+        let (out, err, _) = tokio::join!(
+            read_limited(&mut stdout, max_output, OutputStream::Stdout, on_output),
+            read_limited(&mut stderr, max_output, OutputStream::Stderr, on_output),
+            wait_for_exit(pid),
+        );
+        let (out, err) = (out?, err?);
+
+        // The process has exited but has not been reaped yet, so its accounting is still
+        // available
+        let used = pid.and_then(process_cpu_time).unwrap_or_default();
+        let status = child.wait().await?;
+
+        Ok((
+            Output {
+                status,
+                stdout: out,
+                stderr: err,
+            },
+            used,
+        ))
+    };
+
+    match timeout(limit, run).await {
+        Ok(Ok((output, used))) => {
+            *cpu_time += used;
+            Ok(Ok(output))
+        }
+        Ok(Err(e)) => Ok(Err(e)),
+        Err(elapsed) => {
+            *cpu_time += limit;
+            let _ = child.kill().await;
+            Err(elapsed)
+        }
+    }
+}
+
+// Receives a process's output as it is read.
+pub type OnOutput<'a> = &'a (dyn Fn(OutputStream, &[u8]) + Sync);
+
+pub fn discard_output(_: OutputStream, _: &[u8]) {}
+
+// Reads up to `max_bytes` + 1 bytes from a pipe, discarding the rest so the process writing
+// to it doesn't block.
+async fn read_limited(
+    pipe: &mut (impl AsyncRead + Unpin),
+    max_bytes: usize,
+    stream: OutputStream,
+    on_output: OnOutput<'_>,
+) -> std::io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut buf = [0; 8192];
+    while output.len() <= max_bytes {
+        let wanted = (max_bytes + 1 - output.len()).min(buf.len());
+        let read = pipe.read(&mut buf[..wanted]).await?;
+        if read == 0 {
+            return Ok(output);
+        }
+        // The byte past the limit only tells that the output was truncated
+        let shown = read.min(max_bytes.saturating_sub(output.len()));
+        if shown > 0 {
+            on_output(stream, &buf[..shown]);
+        }
+        output.extend_from_slice(&buf[..read]);
+    }
+    tokio::io::copy(pipe, &mut tokio::io::sink()).await?;
+    Ok(output)
+}
+
+// Waits for a process to exit without reaping it.
+pub async fn wait_for_exit(pid: Option<Pid>) {
+    let Some(pid) = pid else {
+        return;
+    };
+    let _ = tokio::task::spawn_blocking(move || {
+        waitid(Id::Pid(pid), WaitPidFlag::WEXITED | WaitPidFlag::WNOWAIT)
+    })
+    .await;
+}
+
+// Reads the user + system CPU time of a process and its reaped children from procfs.
+pub fn process_cpu_time(pid: Pid) -> Option<Duration> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // Skip past the command name, which may itself contain spaces and parentheses. The
+    // remaining fields start at field 3 (state), so utime..cstime (fields 14-17) are 11..15.
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let ticks: u64 = fields
+        .get(11..15)?
+        .iter()
+        .map(|f| f.parse::<u64>().ok())
+        .sum::<Option<u64>>()?;
+
+    let ticks_per_second = sysconf(SysconfVar::CLK_TCK).ok()??.max(1) as u64;
+    Some(Duration::from_millis(ticks * 1000 / ticks_per_second))
+}
+
+// Outcome of a build step: `Ok(None)` if it succeeded, or the output to report if it failed.
+type StepResult = Result<Option<JobResult>, String>;
+
+// Starts a `prlimit` command applying a toolchain step's limits, to which the step's own
+// command line is appended.
+fn prlimit(limits: &StepLimits, memory_limit: &str) -> Command {
+    let mut cmd = Command::new("prlimit");
+    cmd.args([
+        memory_limit,
+        &format!("--cpu={}", limits.cpu_seconds),
+        &format!("--fsize={}", limits.max_file_bytes),
+        "--",
+    ]);
+    cmd
+}
+
+// Include paths and flags every toolchain invocation gets
+fn toolchain_args(config: &Config) -> [String; 5] {
+    let toolchain = &config.paths.toolchain;
+    [
+        "-I".to_string(),
+        format!("{toolchain}/include"),
+        "-I".to_string(),
+        format!("{toolchain}/libzr/include"),
+        "--forbid-unlisted-includes".to_string(),
+    ]
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+// Runs a toolchain step whose output is the job's result, like linting or emitting TAST.
+async fn run_tool(
+    label: &str,
+    limits: &StepLimits,
+    memory_limit: &str,
+    max_output: usize,
+    args: &[String],
+    cpu_time: &mut Duration,
+) -> Result<JobResult, String> {
+    let result = run_with_limit(
+        limits.timeout(),
+        prlimit(limits, memory_limit).args(args),
+        max_output,
+        &discard_output,
+        cpu_time,
+    )
+    .await;
+
+    match result {
+        Ok(Ok(output)) => Ok(JobResult {
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+            exit_code: output.status.code().unwrap_or(-1),
+        }),
+        Ok(Err(e)) => Err(format!("Failed to spawn {label} process: {e}")),
+        Err(_) => Ok(JobResult {
+            stdout: "".to_string(),
+            stderr: format!(
+                "{} timed out after {} seconds",
+                capitalize(label),
+                limits.timeout_secs
+            ),
+            exit_code: -1,
+        }),
+    }
+}
+
+// Runs a build step, which only produces output worth reporting if it fails.
+async fn run_build_step(
+    label: &str,
+    limits: &StepLimits,
+    memory_limit: &str,
+    max_output: usize,
+    args: &[String],
+    cpu_time: &mut Duration,
+) -> StepResult {
+    let result = run_with_limit(
+        limits.timeout(),
+        prlimit(limits, memory_limit).args(args),
+        max_output,
+        &discard_output,
+        cpu_time,
+    )
+    .await;
+
+    match result {
+        Ok(Ok(output)) if output.status.success() => Ok(None),
+        Ok(Ok(output)) => Ok(Some(JobResult {
+            stdout: "".to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+            exit_code: output.status.code().unwrap_or(-1),
+        })),
+        Ok(Err(e)) => Err(format!("Failed to spawn {label} process: {e}")),
+        Err(_) => Ok(Some(JobResult {
+            stdout: "".to_string(),
+            stderr: format!(
+                "{} timed out after {} seconds",
+                capitalize(label),
+                limits.timeout_secs
+            ),
+            exit_code: -1,
+        })),
+    }
+}
+
+// Compiles a source file to an object file.
+pub async fn compile(
+    config: &Config,
+    source_path: &str,
+    obj_path: &str,
+    memory_limit: &str,
+    max_output: usize,
+    cpu_time: &mut Duration,
+) -> StepResult {
+    let mut args = vec![config.paths.toolchain_bin("zrc")];
+    args.extend(toolchain_args(config));
+    args.extend([
+        "--emit".to_string(),
+        "object".to_string(),
+        "-o".to_string(),
+        obj_path.to_string(),
+        source_path.to_string(),
+    ]);
+
+    run_build_step(
+        "compilation",
+        &config.limits.compile,
+        memory_limit,
+        max_output,
+        &args,
+        cpu_time,
+    )
+    .await
+}
+
+// Links an object file against libzr into a static executable.
+pub async fn link(
+    config: &Config,
+    obj_path: &str,
+    main_path: &str,
+    memory_limit: &str,
+    max_output: usize,
+    cpu_time: &mut Duration,
+) -> StepResult {
+    // Now run clang -lc -lzr -o main main.o
+    let args = [
+        "clang".to_string(),
+        obj_path.to_string(),
+        "-o".to_string(),
+        main_path.to_string(),
+        format!("{}/libzr/lib/libzr.a", config.paths.toolchain),
+        "-lc".to_string(),
+        "-static".to_string(),
+    ];
+
+    run_build_step(
+        "linking",
+        &config.limits.link,
+        memory_limit,
+        max_output,
+        &args,
+        cpu_time,
+    )
+    .await
+}
+
+// Builds the nsjail command that runs `main` from a work directory.
+// --bindmount the workdir to /work
+// --time_limit (wall clock) as given, --rlimit_as (in MB) and --rlimit_cpu from the client's tier
+// --seccomp_policy from the configuration
+pub fn nsjail(
+    config: &Config,
+    work_dir: &str,
+    tier: &Tier,
+    time_limit: u64,
+    extra_args: &[&str],
+) -> Result<Command, String> {
+    let normalized_work_dir = std::fs::canonicalize(work_dir)
+        .map_err(|e| format!("Failed to canonicalize work directory: {e}"))?
+        .to_str()
+        .ok_or_else(|| "Failed to convert work directory path to string".to_string())?
+        .to_string();
+
+    let mut jail = Command::new("nsjail");
+    jail.args([
+        "--quiet",
+        "--bindmount",
+        &format!("{}:/work", normalized_work_dir),
+        "--time_limit",
+        &time_limit.to_string(),
+        "--rlimit_as",
+        &(tier.memory_bytes / (1024 * 1024)).to_string(),
+        "--rlimit_cpu",
+        &tier.cpu_seconds.to_string(),
+        "--rlimit_nofile",
+        &config.limits.max_open_files.to_string(),
+        "--seccomp_policy",
+        &config.paths.seccomp_policy,
+        "--user",
+        "9999",
+        "--group",
+        "9999",
+    ])
+    .args(extra_args)
+    .args(["--", "/work/main"]);
+    Ok(jail)
+}
+
+// Runs a job in the sandbox, calling `report` as it moves from one stage to the next, and
+// `on_output` with what the program prints while it runs.
+pub async fn sandboxed_execution<F: Future<Output = ()>>(
+    job: JobSpec,
+    config: &Config,
+    cpu_time: &mut Duration,
+    report: impl Fn(JobState) -> F,
+    on_output: OnOutput<'_>,
+) -> Result<JobResult, String> {
+    let work_dir = format!("{}/{}", config.paths.work_dir, job.id);
+    let source_path = format!("{work_dir}/main.zr");
+    let obj_path = format!("{work_dir}/main.o");
+    let main_path = format!("{work_dir}/main");
+    let memory_limit = format!("--as={}", job.tier.memory_bytes);
+    let max_output = job.tier.max_output_bytes;
     tokio::fs::create_dir_all(work_dir.clone())
         .await
         .map_err(|e| format!("Failed to create work directory: {e}"))?;
@@ -18,326 +379,93 @@ pub async fn sandboxed_execution(job: Job) -> Result<JobResult, String> {
         .await
         .map_err(|e| format!("Failed to write source file: {e}"))?;
 
-    if job.task_type == crate::models::TaskType::Lint {
-        // For linting, we invoke zircop instead of compiling and executing.
-
-        debug!("Starting linting for job {}", job.id);
-
-        let lint_result = timeout(
-            Duration::from_secs(10),
-            Command::new("prlimit")
-                .args([
-                    "--as=536870912",    // 512 MB
-                    "--cpu=10",          // 10 seconds of CPU time
-                    "--fsize=104857600", // 100 MB file size
-                    "--",
-                    "./zrc-nightly/bin/zircop",
-                    "-I",
-                    "./zrc-nightly/include",
-                    "-I",
-                    "./zrc-nightly/libzr/include",
-                    "--forbid-unlisted-includes",
-                    &source_path,
-                ])
-                .output(),
-        )
-        .await;
-
-        let lint_result = match lint_result {
-            Ok(Ok(output)) => output,
-            Ok(Err(e)) => {
-                // Clean up the work directory after execution
-                let _ = tokio::fs::remove_dir_all(work_dir).await;
-                return Err(format!("Failed to spawn linting process: {e}"));
-            }
-            Err(_) => {
-                // Clean up the work directory after execution
-                let _ = tokio::fs::remove_dir_all(work_dir).await;
-                return Ok(JobResult {
-                    stdout: "".to_string(),
-                    stderr: "Linting timed out after 10 seconds".to_string(),
-                    exit_code: -1,
-                });
-            }
-        };
+    report(JobState::Compiling).await;
 
-        // Clean up the work directory after execution
-        let _ = tokio::fs::remove_dir_all(work_dir).await;
-
-        return Ok(JobResult {
-            stdout: String::from_utf8_lossy(&lint_result.stdout).to_string(),
-            stderr: String::from_utf8_lossy(&lint_result.stderr).to_string(),
-            exit_code: lint_result.status.code().unwrap_or(-1),
-        });
-    }
-
-    debug!("Starting compilation for job {}", job.id);
-
-    if job.task_type == crate::models::TaskType::Tast {
-        // For TAST, we invoke zrc with --emit tast and return the output without linking or executing.
-
-        let tast_result = timeout(
-            Duration::from_secs(10),
-            Command::new("prlimit")
-                .args([
-                    "--as=536870912",    // 512 MB
-                    "--cpu=10",          // 10 seconds of CPU time
-                    "--fsize=104857600", // 100 MB file size
-                    "--",
-                    "./zrc-nightly/bin/zrc",
-                    "-I",
-                    "./zrc-nightly/include",
-                    "-I",
-                    "./zrc-nightly/libzr/include",
-                    "--emit",
-                    "tast",
-                    "--forbid-unlisted-includes",
-                    &source_path,
-                ])
-                .output(),
-        )
-        .await;
-
-        let tast_result = match tast_result {
-            Ok(Ok(output)) => output,
-            Ok(Err(e)) => {
-                // Clean up the work directory after execution
-                let _ = tokio::fs::remove_dir_all(work_dir).await;
-                return Err(format!("Failed to spawn TAST generation process: {e}"));
-            }
-            Err(_) => {
-                // Clean up the work directory after execution
-                let _ = tokio::fs::remove_dir_all(work_dir).await;
-                return Ok(JobResult {
-                    stdout: "".to_string(),
-                    stderr: "TAST generation timed out after 10 seconds".to_string(),
-                    exit_code: -1,
-                });
-            }
-        };
+    // Linting (with zircop) and emitting TAST or LLVM IR (with zrc) produce the result
+    // directly, without linking or executing anything.
+    let tool = match job.task {
+        TaskType::Lint => Some(("linting", &config.limits.lint, "zircop", None)),
+        TaskType::Tast => Some(("TAST generation", &config.limits.tast, "zrc", Some("tast"))),
+        TaskType::Llvm => Some((
+            "LLVM IR generation",
+            &config.limits.llvm,
+            "zrc",
+            Some("llvm"),
+        )),
+        TaskType::Execute => None,
+    };
+    if let Some((label, limits, binary, emit)) = tool {
+        debug!("Starting {label} for job {}", job.id);
 
-        // Clean up the work directory after execution
-        let _ = tokio::fs::remove_dir_all(work_dir).await;
+        let mut args = vec![config.paths.toolchain_bin(binary)];
+        args.extend(toolchain_args(config));
+        if let Some(emit) = emit {
+            args.extend(["--emit".to_string(), emit.to_string()]);
+        }
+        args.push(source_path);
 
-        return Ok(JobResult {
-            stdout: String::from_utf8_lossy(&tast_result.stdout).to_string(),
-            stderr: String::from_utf8_lossy(&tast_result.stderr).to_string(),
-            exit_code: tast_result.status.code().unwrap_or(-1),
-        });
-    } else if job.task_type == crate::models::TaskType::Llvm {
-        // For LLVM IR, we invoke zrc with --emit llvm and return the output without linking or executing.
-
-        let llvm_result = timeout(
-            Duration::from_secs(10),
-            Command::new("prlimit")
-                .args([
-                    "--as=536870912",    // 512 MB
-                    "--cpu=10",          // 10 seconds of CPU time
-                    "--fsize=104857600", // 100 MB file size
-                    "--",
-                    "./zrc-nightly/bin/zrc",
-                    "-I",
-                    "./zrc-nightly/include",
-                    "-I",
-                    "./zrc-nightly/libzr/include",
-                    "--emit",
-                    "llvm",
-                    "--forbid-unlisted-includes",
-                    &source_path,
-                ])
-                .output(),
-        )
-        .await;
-
-        let llvm_result = match llvm_result {
-            Ok(Ok(output)) => output,
-            Ok(Err(e)) => {
-                // Clean up the work directory after execution
-                let _ = tokio::fs::remove_dir_all(work_dir).await;
-                return Err(format!("Failed to spawn LLVM IR generation process: {e}"));
-            }
-            Err(_) => {
-                // Clean up the work directory after execution
-                let _ = tokio::fs::remove_dir_all(work_dir).await;
-                return Ok(JobResult {
-                    stdout: "".to_string(),
-                    stderr: "LLVM IR generation timed out after 10 seconds".to_string(),
-                    exit_code: -1,
-                });
-            }
-        };
+        let result = run_tool(label, limits, &memory_limit, max_output, &args, cpu_time).await;
 
         // Clean up the work directory after execution
         let _ = tokio::fs::remove_dir_all(work_dir).await;
-
-        return Ok(JobResult {
-            stdout: String::from_utf8_lossy(&llvm_result.stdout).to_string(),
-            stderr: String::from_utf8_lossy(&llvm_result.stderr).to_string(),
-            exit_code: llvm_result.status.code().unwrap_or(-1),
-        });
+        return result;
     }
 
-    let compile_result = timeout(
-        Duration::from_secs(10),
-        Command::new("prlimit")
-            .args([
-                "--as=536870912",    // 512 MB
-                "--cpu=10",          // 10 seconds of CPU time
-                "--fsize=104857600", // 100 MB file size
-                "--",
-                "./zrc-nightly/bin/zrc",
-                "-I",
-                "./zrc-nightly/include",
-                "-I",
-                "./zrc-nightly/libzr/include",
-                "--emit",
-                "object",
-                "-o",
-                &obj_path,
-                "--forbid-unlisted-includes",
-                &source_path,
-            ])
-            .stdout(Stdio::null())
-            .output(),
+    debug!("Starting compilation for job {}", job.id);
+
+    let compiled = compile(
+        config,
+        &source_path,
+        &obj_path,
+        &memory_limit,
+        max_output,
+        cpu_time,
     )
     .await;
-
-    match compile_result {
-        Ok(Ok(output)) => {
-            if output.status.success() {
-                debug!("Compilation succeeded for job {}", job.id);
-                // compilation completed successfully, proceed to execution
-            } else {
-                debug!(
-                    "Compilation failed for job {} with exit code {:?}",
-                    job.id,
-                    output.status.code()
-                );
-                let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-                // Clean up the work directory after execution
-                let _ = tokio::fs::remove_dir_all(work_dir).await;
-                return Ok(JobResult {
-                    stdout: "".to_string(),
-                    stderr,
-                    exit_code: output.status.code().unwrap_or(-1),
-                });
-            }
-        }
-        Ok(Err(e)) => {
-            // Clean up the work directory after execution
-            let _ = tokio::fs::remove_dir_all(work_dir).await;
-            return Err(format!("Failed to spawn compilation process: {e}"));
-        }
-        Err(_) => {
-            // Clean up the work directory after execution
-            let _ = tokio::fs::remove_dir_all(work_dir).await;
-            return Ok(JobResult {
-                stdout: "".to_string(),
-                stderr: "Compilation timed out after 10 seconds".to_string(),
-                exit_code: -1,
-            });
-        }
+    if !matches!(compiled, Ok(None)) {
+        debug!("Compilation failed for job {}", job.id);
+        // Clean up the work directory after execution
+        let _ = tokio::fs::remove_dir_all(work_dir).await;
+        return compiled.map(Option::unwrap_or_default);
     }
 
     debug!("Starting linking for job {}", job.id);
+    report(JobState::Linking).await;
 
-    // Now run clang -lc -lzr -o main main.o
-    let link_result = timeout(
-        Duration::from_secs(10),
-        Command::new("prlimit")
-            .args([
-                "--as=536870912",    // 512 MB
-                "--cpu=10",          // 10 seconds of CPU time
-                "--fsize=104857600", // 100 MB file size
-                "--",
-                "clang",
-                &obj_path,
-                "-o",
-                &main_path,
-                "./zrc-nightly/libzr/lib/libzr.a",
-                "-lc",
-                "-static",
-            ])
-            .stdout(Stdio::null())
-            .output(),
+    let linked = link(
+        config,
+        &obj_path,
+        &main_path,
+        &memory_limit,
+        max_output,
+        cpu_time,
     )
     .await;
+    if !matches!(linked, Ok(None)) {
+        debug!("Linking failed for job {}", job.id);
+        // Clean up the work directory after execution
+        let _ = tokio::fs::remove_dir_all(work_dir).await;
+        return linked.map(Option::unwrap_or_default);
+    }
 
-    match link_result {
-        Ok(Ok(output)) => {
-            if output.status.success() {
-                debug!("Linking succeeded for job {}", job.id);
-                // linking completed successfully, proceed to execution
-            } else {
-                // Clean up the work directory after execution
-                let _ = tokio::fs::remove_dir_all(work_dir).await;
-                debug!(
-                    "Linking failed for job {} with exit code {:?}",
-                    job.id,
-                    output.status.code()
-                );
-                let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-                return Ok(JobResult {
-                    stdout: "".to_string(),
-                    stderr,
-                    exit_code: output.status.code().unwrap_or(-1),
-                });
-            }
-        }
-        Ok(Err(e)) => {
+    let cpu_seconds = job.tier.cpu_seconds;
+    let mut jail = match nsjail(config, &work_dir, &job.tier, cpu_seconds, &[]) {
+        Ok(jail) => jail,
+        Err(e) => {
             // Clean up the work directory after execution
             let _ = tokio::fs::remove_dir_all(work_dir).await;
-            return Err(format!("Failed to spawn linking process: {e}"));
+            return Err(e);
         }
-        Err(_) => {
-            // Clean up the work directory after execution
-            let _ = tokio::fs::remove_dir_all(work_dir).await;
-            return Ok(JobResult {
-                stdout: "".to_string(),
-                stderr: "Linking timed out after 10 seconds".to_string(),
-                exit_code: -1,
-            });
-        }
-    }
-
-    // Now we spawn the process within nsjail.
-    // --chroot to its workdir
-    // --time-limit 30
-    // --rlimit_as 512M
-    // --rlimit_cpu 30
-    // --seccomp_file ./seccomp.bpf
-    let normalized_work_dir = std::fs::canonicalize(&work_dir)
-        .map_err(|e| format!("Failed to canonicalize work directory: {e}"))?
-        .to_str()
-        .ok_or_else(|| "Failed to convert work directory path to string".to_string())?
-        .to_string();
+    };
 
     debug!("Starting execution for job {}", job.id);
-    let exec_result = timeout(
-        Duration::from_secs(30),
-        Command::new("nsjail")
-            .args([
-                "--quiet",
-                "--bindmount",
-                &format!("{}:/work", normalized_work_dir),
-                "--time_limit",
-                "30",
-                "--rlimit_as",
-                "536870912",
-                "--rlimit_cpu",
-                "30",
-                "--rlimit_nofile",
-                "20",
-                "--seccomp_policy",
-                "./seccomp.policy",
-                "--user",
-                "9999",
-                "--group",
-                "9999",
-                "--",
-                "/work/main",
-            ])
-            .output(),
+    report(JobState::Running).await;
+    let exec_result = run_with_limit(
+        Duration::from_secs(cpu_seconds),
+        &mut jail,
+        max_output,
+        on_output,
+        cpu_time,
     )
     .await;
 
@@ -353,7 +481,7 @@ pub async fn sandboxed_execution(job: Job) -> Result<JobResult, String> {
             let _ = tokio::fs::remove_dir_all(work_dir).await;
             return Ok(JobResult {
                 stdout: "".to_string(),
-                stderr: "Execution timed out after 30 seconds".to_string(),
+                stderr: format!("Execution timed out after {cpu_seconds} seconds"),
                 exit_code: -1,
             });
         }
@@ -368,3 +496,24 @@ pub async fn sandboxed_execution(job: Job) -> Result<JobResult, String> {
         exit_code: exec_result.status.code().unwrap_or(-1),
     })
 }
+
+// Cuts stdout and stderr down to the client's output limit.
+pub fn truncate_output(result: JobResult, max_bytes: usize) -> JobResult {
+    fn truncate(mut output: String, max_bytes: usize) -> String {
+        if output.len() > max_bytes {
+            let mut end = max_bytes;
+            while !output.is_char_boundary(end) {
+                end -= 1;
+            }
+            output.truncate(end);
+            output.push_str("\n[output truncated]\n");
+        }
+        output
+    }
+
+    JobResult {
+        stdout: truncate(result.stdout, max_bytes),
+        stderr: truncate(result.stderr, max_bytes),
+        ..result
+    }
+}
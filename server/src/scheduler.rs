@@ -0,0 +1,454 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::models::Job;
+
+// A bounded job queue that hands work out round-robin across clients, so one client
+// submitting a burst of jobs can't monopolize the workers. Clients in higher priority tiers
+// are served before lower priority ones, and each client can only have as many jobs running
+// as its tier allows.
+//
+// Jobs from batches wait in a separate, larger queue and are only handed out when no
+// interactive job is ready to run, so a big batch can't hold up people using the playground.
+pub struct Scheduler {
+    inner: Mutex<Inner>,
+    // Wakes idle workers whenever there might be new work for them
+    changed: Notify,
+    capacity: usize,
+    batch_capacity: usize,
+}
+
+// A client's interactive or batch jobs. Each is scheduled as if it were a separate client,
+// but both count towards the client's running jobs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Lane {
+    client: String,
+    batch: bool,
+}
+
+impl Lane {
+    fn of(job: &Job) -> Self {
+        Lane {
+            client: job.client.clone(),
+            batch: job.batch.is_some(),
+        }
+    }
+}
+
+// Interactive jobs go before batch jobs, then higher priority tiers before lower ones.
+fn rank(job: &Job) -> (bool, i32) {
+    (job.batch.is_none(), job.tier.priority)
+}
+
+#[derive(Default)]
+struct Inner {
+    queues: HashMap<Lane, VecDeque<Job>>,
+    // Lanes with queued jobs, in the order they will next be served
+    ring: VecDeque<Lane>,
+    running: HashMap<String, usize>,
+    len: usize,
+    batch_len: usize,
+    // Set when the server shuts down, after which no more jobs are accepted
+    closed: bool,
+    // Set by operators to stop handing out jobs, while still accepting them
+    paused: bool,
+}
+
+impl Inner {
+    fn push(&mut self, job: Job) {
+        let lane = Lane::of(&job);
+        if job.batch.is_some() {
+            self.batch_len += 1;
+        } else {
+            self.len += 1;
+        }
+
+        let queue = self.queues.entry(lane.clone()).or_default();
+        queue.push_back(job);
+        if queue.len() == 1 {
+            self.ring.push_back(lane);
+        }
+    }
+}
+
+// Marks a job as running for its client until dropped.
+pub struct RunningSlot {
+    scheduler: Arc<Scheduler>,
+    client: String,
+}
+
+impl Scheduler {
+    pub fn new(capacity: usize, batch_capacity: usize) -> Self {
+        Scheduler {
+            inner: Mutex::new(Inner::default()),
+            changed: Notify::new(),
+            capacity,
+            batch_capacity,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Number of queued jobs, interactive and batch
+    pub fn len(&self) -> usize {
+        let inner = self.lock();
+        inner.len + inner.batch_len
+    }
+
+    // Number of queued interactive and batch jobs
+    pub fn lane_lens(&self) -> (usize, usize) {
+        let inner = self.lock();
+        (inner.len, inner.batch_len)
+    }
+
+    // Number of queued jobs that could start right away given enough workers, without going
+    // over their clients' limits of running jobs.
+    pub fn runnable_len(&self) -> usize {
+        let inner = self.lock();
+        if inner.paused {
+            return 0;
+        }
+        let mut clients: HashMap<&str, (usize, usize)> = HashMap::new();
+        for (lane, queue) in &inner.queues {
+            let Some(job) = queue.front() else {
+                continue;
+            };
+            let (queued, max_running) = clients.entry(&lane.client).or_default();
+            *queued += queue.len();
+            *max_running = (*max_running).max(job.tier.max_running);
+        }
+
+        clients
+            .into_iter()
+            .map(|(client, (queued, max_running))| {
+                let running = inner.running.get(client).copied().unwrap_or(0);
+                queued.min(max_running.saturating_sub(running))
+            })
+            .sum()
+    }
+
+    // Queues a job, handing it back if the queue is full.
+    pub fn try_push(&self, job: Job) -> Result<(), Job> {
+        {
+            let mut inner = self.lock();
+            if inner.closed || inner.len >= self.capacity {
+                return Err(job);
+            }
+            inner.push(job);
+        }
+
+        self.changed.notify_waiters();
+        Ok(())
+    }
+
+    // Queues all jobs of a batch, or none of them if they don't fit in the batch queue.
+    pub fn try_push_batch(&self, jobs: Vec<Job>) -> Result<(), Vec<Job>> {
+        {
+            let mut inner = self.lock();
+            if inner.closed || inner.batch_len + jobs.len() > self.batch_capacity {
+                return Err(jobs);
+            }
+            for job in jobs {
+                inner.push(job);
+            }
+        }
+
+        self.changed.notify_waiters();
+        Ok(())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.lock().paused
+    }
+
+    // Stops or resumes handing out jobs. Jobs that are already running carry on.
+    pub fn set_paused(&self, paused: bool) {
+        self.lock().paused = paused;
+        self.changed.notify_waiters();
+    }
+
+    // Queues jobs that were accepted before a restart. They are queued even if they don't fit
+    // anymore, since their clients were told they would run.
+    pub fn restore(&self, jobs: Vec<Job>) {
+        {
+            let mut inner = self.lock();
+            for job in jobs {
+                inner.push(job);
+            }
+        }
+
+        self.changed.notify_waiters();
+    }
+
+    // Stops accepting jobs, and takes every job that is still queued out of the queue.
+    pub fn close(&self) -> Vec<Job> {
+        let mut inner = self.lock();
+        inner.closed = true;
+        inner.ring.clear();
+        inner.len = 0;
+        inner.batch_len = 0;
+        inner.queues.drain().flat_map(|(_, queue)| queue).collect()
+    }
+
+    // Takes the next job from the first lane in the rotation whose client is below its limit of
+    // running jobs (preferring higher ranked lanes), and moves that lane to the back of the
+    // rotation.
+    fn try_pop(self: &Arc<Self>) -> Option<(Job, RunningSlot)> {
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        if inner.paused {
+            return None;
+        }
+
+        let (index, _) = inner
+            .ring
+            .iter()
+            .enumerate()
+            .filter_map(|(index, lane)| {
+                let job = inner.queues.get(lane)?.front()?;
+                let running = inner.running.get(&lane.client).copied().unwrap_or(0);
+                (running < job.tier.max_running).then_some((index, rank(job)))
+            })
+            // `max_by_key` picks the last maximum, so reverse to keep rotation order
+            .rev()
+            .max_by_key(|&(_, rank)| rank)?;
+        let lane = inner.ring.remove(index)?;
+
+        let queue = inner.queues.get_mut(&lane)?;
+        let job = queue.pop_front()?;
+        if queue.is_empty() {
+            inner.queues.remove(&lane);
+        } else {
+            inner.ring.push_back(lane.clone());
+        }
+
+        if lane.batch {
+            inner.batch_len -= 1;
+        } else {
+            inner.len -= 1;
+        }
+        let client = lane.client;
+        *inner.running.entry(client.clone()).or_default() += 1;
+
+        Some((
+            job,
+            RunningSlot {
+                scheduler: self.clone(),
+                client,
+            },
+        ))
+    }
+
+    pub async fn pop(self: &Arc<Self>) -> (Job, RunningSlot) {
+        loop {
+            // Register for wakeups before checking, so we can't miss a push in between
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            if let Some(next) = self.try_pop() {
+                return next;
+            }
+
+            changed.await;
+        }
+    }
+
+    // Number of jobs that will be handed out before the given one, assuming every lane keeps
+    // being served in turn.
+    pub fn position(&self, id: Uuid) -> Option<usize> {
+        let inner = self.lock();
+
+        let (turn, index, rank) = inner.ring.iter().enumerate().find_map(|(turn, lane)| {
+            let queue = inner.queues.get(lane)?;
+            let index = queue.iter().position(|j| j.id == id)?;
+            Some((turn, index, self::rank(&queue[index])))
+        })?;
+
+        // Higher ranked lanes go first. Among lanes of the same rank, every lane gets `index`
+        // full rounds before our job comes up, and those ahead of us in the rotation get one
+        // more.
+        Some(
+            inner
+                .ring
+                .iter()
+                .enumerate()
+                .filter_map(|(other_turn, lane)| {
+                    let queue = inner.queues.get(lane)?;
+                    let other_rank = self::rank(queue.front()?);
+                    Some(if other_rank > rank {
+                        queue.len()
+                    } else if other_rank < rank {
+                        0
+                    } else if other_turn < turn {
+                        queue.len().min(index + 1)
+                    } else {
+                        queue.len().min(index)
+                    })
+                })
+                .sum(),
+        )
+    }
+}
+
+impl Drop for RunningSlot {
+    fn drop(&mut self) {
+        {
+            let mut inner = self.scheduler.lock();
+            if let Some(running) = inner.running.get_mut(&self.client) {
+                *running -= 1;
+                if *running == 0 {
+                    inner.running.remove(&self.client);
+                }
+            }
+        }
+
+        // The client may have been at its limit, so its next job can run now
+        self.scheduler.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::{api_keys::Tier, models::TaskType};
+
+    fn tier(max_running: usize, priority: i32) -> Arc<Tier> {
+        Arc::new(Tier {
+            max_running,
+            priority,
+            ..Tier::default()
+        })
+    }
+
+    fn job(client: &str, tier: &Arc<Tier>, batch: Option<Uuid>) -> Job {
+        Job {
+            id: Uuid::new_v4(),
+            client: client.to_string(),
+            tier: tier.clone(),
+            task_type: TaskType::Execute,
+            code: String::new(),
+            deadline: Instant::now() + Duration::from_secs(60),
+            batch,
+        }
+    }
+
+    // Pops every runnable job, releasing each slot straight away, and returns their clients.
+    fn drain(scheduler: &Arc<Scheduler>) -> Vec<String> {
+        std::iter::from_fn(|| scheduler.try_pop().map(|(job, _)| job.client)).collect()
+    }
+
+    #[test]
+    fn serves_clients_in_turn() {
+        let scheduler = Arc::new(Scheduler::new(16, 16));
+        let tier = tier(8, 0);
+        for client in ["a", "a", "a", "b", "c", "b"] {
+            scheduler.try_push(job(client, &tier, None)).unwrap();
+        }
+
+        assert_eq!(drain(&scheduler), ["a", "b", "c", "a", "b", "a"]);
+        assert_eq!(scheduler.len(), 0);
+    }
+
+    #[test]
+    fn serves_higher_priority_and_interactive_jobs_first() {
+        let scheduler = Arc::new(Scheduler::new(16, 16));
+        let low = tier(8, 0);
+        let high = tier(8, 1);
+        let batch = Some(Uuid::new_v4());
+        scheduler
+            .try_push_batch(vec![job("batch", &high, batch)])
+            .unwrap();
+        scheduler.try_push(job("low", &low, None)).unwrap();
+        scheduler.try_push(job("high", &high, None)).unwrap();
+
+        assert_eq!(drain(&scheduler), ["high", "low", "batch"]);
+    }
+
+    #[test]
+    fn holds_back_clients_at_their_running_limit() {
+        let scheduler = Arc::new(Scheduler::new(16, 16));
+        let tier = tier(1, 0);
+        for client in ["a", "a", "b"] {
+            scheduler.try_push(job(client, &tier, None)).unwrap();
+        }
+
+        let (first, slot) = scheduler.try_pop().unwrap();
+        assert_eq!(first.client, "a");
+        assert_eq!(scheduler.runnable_len(), 1);
+        let (second, _b) = scheduler.try_pop().unwrap();
+        assert_eq!(second.client, "b");
+        // a's second job waits until its first one is done
+        assert!(scheduler.try_pop().is_none());
+        assert_eq!(scheduler.runnable_len(), 0);
+
+        drop(slot);
+        let (third, _) = scheduler.try_pop().unwrap();
+        assert_eq!(third.client, "a");
+    }
+
+    #[test]
+    fn positions_match_the_order_jobs_are_handed_out() {
+        let scheduler = Arc::new(Scheduler::new(16, 16));
+        let tier = tier(8, 0);
+        let mut ids = Vec::new();
+        for client in ["a", "a", "a", "b", "c"] {
+            let job = job(client, &tier, None);
+            ids.push(job.id);
+            scheduler.try_push(job).unwrap();
+        }
+
+        let mut positions: Vec<(usize, Uuid)> = ids
+            .into_iter()
+            .map(|id| (scheduler.position(id).unwrap(), id))
+            .collect();
+        positions.sort();
+        let order: Vec<Uuid> =
+            std::iter::from_fn(|| scheduler.try_pop().map(|(job, _)| job.id)).collect();
+
+        assert_eq!(
+            positions.into_iter().map(|(_, id)| id).collect::<Vec<_>>(),
+            order
+        );
+        assert_eq!(scheduler.position(order[0]), None);
+    }
+
+    #[test]
+    fn rejects_jobs_that_do_not_fit() {
+        let scheduler = Arc::new(Scheduler::new(1, 2));
+        let tier = tier(8, 0);
+        scheduler.try_push(job("a", &tier, None)).unwrap();
+        assert!(scheduler.try_push(job("b", &tier, None)).is_err());
+
+        // Batches are queued whole or not at all
+        let batch = Some(Uuid::new_v4());
+        let jobs = (0..3).map(|_| job("a", &tier, batch)).collect();
+        assert_eq!(scheduler.try_push_batch(jobs).unwrap_err().len(), 3);
+        assert_eq!(scheduler.lane_lens(), (1, 0));
+
+        assert_eq!(scheduler.close().len(), 1);
+        assert!(scheduler.try_push(job("a", &tier, None)).is_err());
+    }
+
+    #[test]
+    fn hands_out_nothing_while_paused() {
+        let scheduler = Arc::new(Scheduler::new(16, 16));
+        scheduler.try_push(job("a", &tier(8, 0), None)).unwrap();
+
+        scheduler.set_paused(true);
+        assert!(scheduler.try_pop().is_none());
+        assert_eq!(scheduler.runnable_len(), 0);
+
+        scheduler.set_paused(false);
+        assert!(scheduler.try_pop().is_some());
+    }
+}
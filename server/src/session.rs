@@ -0,0 +1,410 @@
+use std::{
+    os::fd::{AsRawFd, OwnedFd},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::extract::ws::{Message, WebSocket};
+use futures::{SinkExt, StreamExt, stream::SplitSink};
+use nix::{
+    fcntl::{FcntlArg, OFlag, fcntl},
+    libc,
+    pty::{Winsize, openpty},
+    unistd::Pid,
+};
+use tokio::{
+    io::unix::AsyncFd,
+    sync::{OwnedSemaphorePermit, mpsc},
+    time::Instant,
+};
+use tracing::{debug, error};
+use uuid::Uuid;
+
+use crate::{
+    client::Client,
+    config::Config,
+    models::{AppState, JobState, SessionEvent, SessionRequest},
+    sandbox,
+    shutdown::Shutdown,
+};
+
+type SessionSender = SplitSink<WebSocket, Message>;
+
+// Why a session's program stopped being attached to the socket
+enum SessionEnd {
+    Exited,
+    Disconnected,
+    Idle,
+    OutputLimit,
+    Shutdown,
+}
+
+async fn send(tx: &mut SessionSender, event: SessionEvent) -> bool {
+    match serde_json::to_string(&event) {
+        Ok(text) => tx.send(Message::Text(text.into())).await.is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn winsize(cols: u16, rows: u16) -> Winsize {
+    Winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+fn resize(master: &AsyncFd<OwnedFd>, cols: u16, rows: u16) {
+    let size = winsize(cols, rows);
+    // SAFETY: TIOCSWINSZ only reads the winsize struct, which outlives the call
+    unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &size) };
+}
+
+async fn read_pty(master: &AsyncFd<OwnedFd>, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+        let mut guard = master.readable().await?;
+        if let Ok(result) =
+            guard.try_io(|fd| nix::unistd::read(fd.get_ref(), buf).map_err(Into::into))
+        {
+            return result;
+        }
+    }
+}
+
+async fn write_pty(master: &AsyncFd<OwnedFd>, mut data: &[u8]) -> std::io::Result<()> {
+    while !data.is_empty() {
+        let mut guard = master.writable().await?;
+        if let Ok(written) =
+            guard.try_io(|fd| nix::unistd::write(fd.get_ref(), data).map_err(Into::into))
+        {
+            data = &data[written?..];
+        }
+    }
+    Ok(())
+}
+
+// Runs an interactive session: waits for the client to send its code, builds it like an
+// execute job, then runs it in the jail attached to a terminal whose input and output are
+// relayed over the socket.
+pub async fn run(
+    socket: WebSocket,
+    state: AppState,
+    client: Client,
+    _permit: OwnedSemaphorePermit,
+) {
+    let id = Uuid::new_v4();
+    let (mut tx, mut rx) = socket.split();
+
+    // The session runs under the configuration it started with, even if it is reloaded
+    let config = state.config.get();
+    let start = tokio::select! {
+        start = tokio::time::timeout(config.sessions.idle_timeout(), rx.next()) => start,
+        () = state.shutdown.started() => return,
+    };
+    let Ok(Some(Ok(Message::Text(text)))) = start else {
+        debug!("Session {id} closed before it started");
+        return;
+    };
+    let Ok(SessionRequest::Start { code, cols, rows }) = serde_json::from_str(&text) else {
+        send(
+            &mut tx,
+            SessionEvent::Error {
+                error: "The first message must start the session.".to_string(),
+            },
+        )
+        .await;
+        return;
+    };
+
+    debug!("Starting session {id} for {}", client.key);
+
+    let work_dir = format!("{}/{id}", config.paths.work_dir);
+    let mut cpu_time = Duration::ZERO;
+    let session = Session {
+        id,
+        work_dir: &work_dir,
+        config: &config,
+        client: &client,
+        shutdown: &state.shutdown,
+    };
+    if let Err(e) = session
+        .run(code, winsize(cols, rows), &mut tx, &mut rx, &mut cpu_time)
+        .await
+    {
+        error!("Session {id} failed: {e}");
+        send(
+            &mut tx,
+            SessionEvent::Error {
+                error: format!("Fatal execution error: {e}"),
+            },
+        )
+        .await;
+    }
+    let _ = tx.close().await;
+
+    // Charge the client for the CPU time the session actually used
+    state.rate_limiter.debit(
+        &client.key,
+        &client.tier,
+        cpu_time.as_secs_f64() * client.tier.cpu_second_cost,
+    );
+    debug!("Session {id} used {cpu_time:?} of CPU time");
+
+    // Clean up the work directory after execution
+    let _ = tokio::fs::remove_dir_all(work_dir).await;
+}
+
+struct Session<'a> {
+    id: Uuid,
+    work_dir: &'a str,
+    config: &'a Config,
+    client: &'a Client,
+    shutdown: &'a Shutdown,
+}
+
+impl Session<'_> {
+    async fn run(
+        &self,
+        code: String,
+        size: Winsize,
+        tx: &mut SessionSender,
+        rx: &mut futures::stream::SplitStream<WebSocket>,
+        cpu_time: &mut Duration,
+    ) -> Result<(), String> {
+        let config = self.config;
+        let source_path = format!("{}/main.zr", self.work_dir);
+        let obj_path = format!("{}/main.o", self.work_dir);
+        let main_path = format!("{}/main", self.work_dir);
+        let memory_limit = format!("--as={}", self.client.tier.memory_bytes);
+        let max_output = self.client.tier.max_output_bytes;
+        tokio::fs::create_dir_all(self.work_dir)
+            .await
+            .map_err(|e| format!("Failed to create work directory: {e}"))?;
+        tokio::fs::write(&source_path, code)
+            .await
+            .map_err(|e| format!("Failed to write source file: {e}"))?;
+
+        send(
+            tx,
+            SessionEvent::State {
+                state: JobState::Compiling,
+            },
+        )
+        .await;
+        if let Some(result) = sandbox::compile(
+            config,
+            &source_path,
+            &obj_path,
+            &memory_limit,
+            max_output,
+            cpu_time,
+        )
+        .await?
+        {
+            send(tx, SessionEvent::BuildFailed { result }).await;
+            return Ok(());
+        }
+
+        send(
+            tx,
+            SessionEvent::State {
+                state: JobState::Linking,
+            },
+        )
+        .await;
+        if let Some(result) = sandbox::link(
+            config,
+            &obj_path,
+            &main_path,
+            &memory_limit,
+            max_output,
+            cpu_time,
+        )
+        .await?
+        {
+            send(tx, SessionEvent::BuildFailed { result }).await;
+            return Ok(());
+        }
+
+        let pty = openpty(&size, None).map_err(|e| format!("Failed to open terminal: {e}"))?;
+        fcntl(&pty.master, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+            .map_err(|e| format!("Failed to configure terminal: {e}"))?;
+
+        // Keep the program in the jail's session (rather than nsjail starting a new one), so
+        // the terminal stays its controlling terminal and it gets resize signals
+        let mut jail = sandbox::nsjail(
+            config,
+            self.work_dir,
+            &self.client.tier,
+            config.sessions.time_limit_secs,
+            &["--skip_setsid"],
+        )?;
+        let stdio = |fd: &OwnedFd| -> Result<Stdio, String> {
+            fd.try_clone()
+                .map(Stdio::from)
+                .map_err(|e| format!("Failed to attach terminal: {e}"))
+        };
+        jail.stdin(stdio(&pty.slave)?)
+            .stdout(stdio(&pty.slave)?)
+            .stderr(stdio(&pty.slave)?)
+            .kill_on_drop(true);
+        // SAFETY: only async-signal-safe calls are made between fork and exec
+        unsafe {
+            jail.pre_exec(|| {
+                nix::unistd::setsid()?;
+                if libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        let mut child = jail
+            .spawn()
+            .map_err(|e| format!("Failed to spawn execution process: {e}"))?;
+        // Our copies of the terminal have to go, or reads won't see the program exit
+        drop(jail);
+        drop(pty.slave);
+        let pid = child.id().map(|id| Pid::from_raw(id as i32));
+
+        debug!("Session {} is running", self.id);
+        send(
+            tx,
+            SessionEvent::State {
+                state: JobState::Running,
+            },
+        )
+        .await;
+
+        let master = Arc::new(
+            AsyncFd::new(pty.master).map_err(|e| format!("Failed to watch terminal: {e}"))?,
+        );
+
+        let (output_tx, mut output) = mpsc::channel::<Vec<u8>>(16);
+        let reader = tokio::spawn({
+            let master = master.clone();
+            async move {
+                let mut buf = vec![0; 4096];
+                // Reads fail with EIO once the program and everything it started are gone
+                while let Ok(n @ 1..) = read_pty(&master, &mut buf).await {
+                    if output_tx.send(buf[..n].to_vec()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        let (input, mut input_rx) = mpsc::channel::<Vec<u8>>(16);
+        let writer = tokio::spawn({
+            let master = master.clone();
+            async move {
+                while let Some(data) = input_rx.recv().await {
+                    if write_pty(&master, &data).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        let exited = sandbox::wait_for_exit(pid);
+        tokio::pin!(exited);
+        let mut has_exited = false;
+        let mut output_done = false;
+        let mut output_bytes = 0;
+        let mut idle_deadline = Instant::now() + self.config.sessions.idle_timeout();
+
+        let end = loop {
+            if has_exited && output_done {
+                break SessionEnd::Exited;
+            }
+
+            tokio::select! {
+                () = &mut exited, if !has_exited => {
+                    has_exited = true;
+                    // Give the terminal a moment to drain, in case something still holds it
+                    idle_deadline = Instant::now() + Duration::from_secs(1);
+                }
+                chunk = output.recv(), if !output_done => {
+                    let Some(chunk) = chunk else {
+                        output_done = true;
+                        continue;
+                    };
+                    output_bytes += chunk.len();
+                    if output_bytes > self.client.tier.max_output_bytes {
+                        break SessionEnd::OutputLimit;
+                    }
+                    if tx.send(Message::Binary(chunk.into())).await.is_err() {
+                        break SessionEnd::Disconnected;
+                    }
+                }
+                message = rx.next() => {
+                    let data = match message {
+                        Some(Ok(Message::Binary(data))) => data.to_vec(),
+                        Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                            Ok(SessionRequest::Input { data }) => data.into_bytes(),
+                            Ok(SessionRequest::Resize { cols, rows }) => {
+                                resize(&master, cols, rows);
+                                continue;
+                            }
+                            _ => continue,
+                        },
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                            break SessionEnd::Disconnected;
+                        }
+                        Some(Ok(_)) => continue,
+                    };
+
+                    idle_deadline = Instant::now() + self.config.sessions.idle_timeout();
+                    // If the program isn't reading its input, there's no point holding on to more
+                    let _ = input.try_send(data);
+                }
+                () = self.shutdown.started(), if !has_exited => break SessionEnd::Shutdown,
+                () = tokio::time::sleep_until(idle_deadline) => {
+                    break if has_exited { SessionEnd::Exited } else { SessionEnd::Idle };
+                }
+            }
+        };
+
+        reader.abort();
+        writer.abort();
+
+        if !has_exited {
+            let _ = child.start_kill();
+            exited.await;
+        }
+        // The process has exited but has not been reaped yet, so its accounting is still
+        // available
+        *cpu_time += pid.and_then(sandbox::process_cpu_time).unwrap_or_default();
+        let status = child
+            .wait()
+            .await
+            .map_err(|e| format!("Failed to wait for execution process: {e}"))?;
+
+        debug!("Session {} ended with {status}", self.id);
+
+        let error = match end {
+            SessionEnd::Exited => None,
+            SessionEnd::Disconnected => return Ok(()),
+            SessionEnd::Idle => Some(format!(
+                "Session closed after {} seconds without input.",
+                self.config.sessions.idle_timeout_secs
+            )),
+            SessionEnd::OutputLimit => Some("Output limit reached.".to_string()),
+            SessionEnd::Shutdown => Some("The server is shutting down.".to_string()),
+        };
+        if let Some(error) = error {
+            send(tx, SessionEvent::Error { error }).await;
+        }
+        send(
+            tx,
+            SessionEvent::Exit {
+                exit_code: status.code().unwrap_or(-1),
+            },
+        )
+        .await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use std::{collections::HashSet, sync::Arc};
+
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::watch,
+};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    jobs,
+    models::{AppState, JobResult, JobState},
+};
+
+// Whether the server is shutting down. Once it is, no new jobs or sessions are accepted.
+#[derive(Debug, Clone)]
+pub struct Shutdown(Arc<watch::Sender<bool>>);
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown(Arc::new(watch::Sender::new(false)))
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    fn start(&self) {
+        self.0.send_replace(true);
+    }
+
+    // Resolves once the server starts shutting down.
+    pub async fn started(&self) {
+        let mut started = self.0.subscribe();
+        let _ = started.wait_for(|started| *started).await;
+    }
+}
+
+// Resolves on the first SIGTERM or SIGINT.
+pub async fn terminate() {
+    let (Ok(mut sigterm), Ok(mut sigint)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) else {
+        error!("Failed to listen for shutdown signals");
+        return std::future::pending().await;
+    };
+
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+        _ = sigint.recv() => info!("Received SIGINT, shutting down"),
+    }
+}
+
+fn shutdown_result(message: &str) -> JobResult {
+    JobResult {
+        stdout: "".to_string(),
+        stderr: message.to_string(),
+        exit_code: -1,
+    }
+}
+
+// Waits for a shutdown signal, then drains the server: new jobs are turned away, queued jobs
+// are rejected (or kept for the next start, if the queue is durable), and running jobs get
+// until the shutdown timeout to finish before they are killed. Resolves once no job is left
+// running, at which point the server should stop accepting connections.
+pub async fn drain_on_signal(state: AppState) {
+    terminate().await;
+    state.shutdown.start();
+
+    let queued = state.work_queue.close();
+    // A durable queue keeps them for the next start instead
+    let kept: HashSet<Uuid> = if state.jobs.store.is_some() {
+        queued.iter().map(|job| job.id).collect()
+    } else {
+        HashSet::new()
+    };
+    if !kept.is_empty() {
+        info!("Keeping {} queued jobs for the next start", kept.len());
+    } else if !queued.is_empty() {
+        info!("Rejecting {} queued jobs", queued.len());
+    }
+    for job in queued.into_iter().filter(|job| !kept.contains(&job.id)) {
+        jobs::finish(
+            &state.jobs,
+            job.id,
+            JobState::Failed,
+            shutdown_result("The server shut down before this job could run."),
+        )
+        .await;
+        state.metrics.job_finished(job.task_type, JobState::Failed);
+    }
+
+    // Workers stop once they are done with their current job, and remote workers are sent
+    // no more jobs
+    let timeout = state.config.get().workers.shutdown_timeout();
+    state.workers.stop();
+    let finished = async {
+        state.workers.stopped().await;
+        state.remote_workers.idle().await;
+    };
+    tokio::select! {
+        () = finished => return info!("All running jobs finished"),
+        () = tokio::time::sleep(timeout) => {
+            warn!("Running jobs did not finish within {timeout:?}, killing them");
+        }
+        () = terminate() => warn!("Received a second signal, killing running jobs"),
+    }
+
+    let aborted = state.workers.abort();
+    info!("Stopped {aborted} workers");
+    // Results remote workers send from now on are turned away
+    state.remote_workers.release_all();
+    let unfinished = jobs::unfinished(&state.jobs).await;
+    for id in unfinished.into_iter().filter(|id| !kept.contains(id)) {
+        let Some(status) = jobs::status(&state.jobs, id).await else {
+            continue;
+        };
+        if jobs::finish(
+            &state.jobs,
+            id,
+            JobState::Failed,
+            shutdown_result("The server shut down while this job was running."),
+        )
+        .await
+        {
+            state.metrics.job_finished(status.task, JobState::Failed);
+        }
+    }
+}
+
+// Removes whatever jobs and sessions left behind in the work directory.
+pub async fn clean_work_dir(work_dir: &str) {
+    let mut entries = match tokio::fs::read_dir(work_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => return error!("Failed to clean work directory: {e}"),
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        let removed = if entry.file_type().await.is_ok_and(|kind| kind.is_dir()) {
+            tokio::fs::remove_dir_all(&path).await
+        } else {
+            tokio::fs::remove_file(&path).await
+        };
+        if let Err(e) = removed {
+            error!("Failed to remove {}: {e}", path.display());
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{Connection, OptionalExtension, params, types::Type};
+
+use crate::models::{CreateSnippetRequest, RevisionSummary, Snippet, SnippetRef};
+
+// Limits for stored snippets, so the database can't be used as free file hosting.
+pub const MAX_CODE_BYTES: usize = 64 * 1024;
+pub const MAX_FLAGS: usize = 16;
+pub const MAX_FLAG_BYTES: usize = 64;
+pub const MAX_TOOLCHAIN_BYTES: usize = 64;
+
+const ID_LENGTH: usize = 10;
+const ID_ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+// IDs to try before giving up, in case they collide with existing snippets
+const ID_ATTEMPTS: usize = 5;
+
+// Generates a short, URL-safe random ID from the bits of a v4 UUID.
+fn generate_id() -> String {
+    let mut bits = uuid::Uuid::new_v4().as_u128();
+    (0..ID_LENGTH)
+        .map(|_| {
+            let c = ID_ALPHABET[(bits % ID_ALPHABET.len() as u128) as usize];
+            bits /= ID_ALPHABET.len() as u128;
+            c as char
+        })
+        .collect()
+}
+
+// A column that holds something the server could never have written.
+fn corrupt(
+    column: usize,
+    e: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(column, Type::Text, e.into())
+}
+
+fn is_constraint_violation(e: &rusqlite::Error) -> bool {
+    e.sqlite_error_code() == Some(rusqlite::ErrorCode::ConstraintViolation)
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// Checks a snippet against the size limits, returning a description of the first violation.
+pub fn validate(req: &CreateSnippetRequest) -> Result<(), String> {
+    if req.code.len() > MAX_CODE_BYTES {
+        return Err(format!("Code exceeds {MAX_CODE_BYTES} bytes"));
+    }
+    if req.toolchain.len() > MAX_TOOLCHAIN_BYTES {
+        return Err(format!("Toolchain exceeds {MAX_TOOLCHAIN_BYTES} bytes"));
+    }
+    if req.flags.len() > MAX_FLAGS {
+        return Err(format!("More than {MAX_FLAGS} flags"));
+    }
+    if req.flags.iter().any(|f| f.len() > MAX_FLAG_BYTES) {
+        return Err(format!("Flag exceeds {MAX_FLAG_BYTES} bytes"));
+    }
+    Ok(())
+}
+
+fn insert_revision(
+    conn: &Connection,
+    id: &str,
+    revision: i64,
+    parent_revision: Option<i64>,
+    req: CreateSnippetRequest,
+) -> rusqlite::Result<Snippet> {
+    let created_at = now();
+    conn.execute(
+        "INSERT INTO snippet_revisions
+            (snippet_id, revision, parent_revision, task, toolchain, flags, code, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            id,
+            revision,
+            parent_revision,
+            req.task.as_str(),
+            req.toolchain,
+            serde_json::to_string(&req.flags).expect("flags are always serializable"),
+            req.code,
+            created_at,
+        ],
+    )?;
+
+    Ok(Snippet {
+        id: id.to_string(),
+        revision,
+        parent_revision,
+        forked_from: None,
+        task: req.task,
+        toolchain: req.toolchain,
+        flags: req.flags,
+        code: req.code,
+        created_at,
+    })
+}
+
+// Creates a new snippet lineage whose first revision holds the given code.
+fn insert_lineage(
+    conn: &Connection,
+    forked_from: Option<SnippetRef>,
+    req: CreateSnippetRequest,
+) -> rusqlite::Result<Snippet> {
+    let mut attempt = 1;
+    let id = loop {
+        let id = generate_id();
+        match conn.execute(
+            "INSERT INTO snippets (id, created_at, forked_from_id, forked_from_revision)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                id,
+                now(),
+                forked_from.as_ref().map(|f| &f.id),
+                forked_from.as_ref().map(|f| f.revision),
+            ],
+        ) {
+            Ok(_) => break id,
+            Err(e) if is_constraint_violation(&e) && attempt < ID_ATTEMPTS => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    };
+
+    let snippet = insert_revision(conn, &id, 1, None, req)?;
+    Ok(Snippet {
+        forked_from,
+        ..snippet
+    })
+}
+
+pub fn insert(conn: &mut Connection, req: CreateSnippetRequest) -> rusqlite::Result<Snippet> {
+    let tx = conn.transaction()?;
+    let snippet = insert_lineage(&tx, None, req)?;
+    tx.commit()?;
+    Ok(snippet)
+}
+
+// Fetches a revision of a snippet, or the latest one if no revision is given.
+pub fn get(
+    conn: &Connection,
+    id: &str,
+    revision: Option<i64>,
+) -> rusqlite::Result<Option<Snippet>> {
+    conn.query_row(
+        "SELECT r.snippet_id, r.revision, r.parent_revision, s.forked_from_id,
+                s.forked_from_revision, r.task, r.toolchain, r.flags, r.code, r.created_at
+         FROM snippet_revisions r JOIN snippets s ON s.id = r.snippet_id
+         WHERE r.snippet_id = ?1 AND (?2 IS NULL OR r.revision = ?2)
+         ORDER BY r.revision DESC LIMIT 1",
+        params![id, revision],
+        |row| {
+            let forked_from_id: Option<String> = row.get(3)?;
+            let forked_from_revision: Option<i64> = row.get(4)?;
+            let task: String = row.get(5)?;
+            let flags: String = row.get(7)?;
+            Ok(Snippet {
+                id: row.get(0)?,
+                revision: row.get(1)?,
+                parent_revision: row.get(2)?,
+                forked_from: forked_from_id
+                    .zip(forked_from_revision)
+                    .map(|(id, revision)| SnippetRef { id, revision }),
+                task: task.parse().map_err(|e: String| corrupt(5, e))?,
+                toolchain: row.get(6)?,
+                flags: serde_json::from_str(&flags).map_err(|e| corrupt(7, e))?,
+                code: row.get(8)?,
+                created_at: row.get(9)?,
+            })
+        },
+    )
+    .optional()
+}
+
+// Saves a new revision on top of `parent` (or the latest revision). Returns `None` if the
+// snippet or the parent revision does not exist.
+pub fn save_revision(
+    conn: &mut Connection,
+    id: &str,
+    parent: Option<i64>,
+    req: CreateSnippetRequest,
+) -> rusqlite::Result<Option<Snippet>> {
+    let tx = conn.transaction()?;
+
+    let Some(parent) = get(&tx, id, parent)? else {
+        return Ok(None);
+    };
+    let latest: i64 = tx.query_row(
+        "SELECT MAX(revision) FROM snippet_revisions WHERE snippet_id = ?1",
+        params![id],
+        |row| row.get(0),
+    )?;
+
+    let snippet = insert_revision(&tx, id, latest + 1, Some(parent.revision), req)?;
+    tx.commit()?;
+
+    Ok(Some(Snippet {
+        forked_from: parent.forked_from,
+        ..snippet
+    }))
+}
+
+pub fn list_revisions(
+    conn: &Connection,
+    id: &str,
+) -> rusqlite::Result<Option<Vec<RevisionSummary>>> {
+    let mut stmt = conn.prepare(
+        "SELECT revision, parent_revision, task, created_at FROM snippet_revisions
+         WHERE snippet_id = ?1 ORDER BY revision",
+    )?;
+    let revisions = stmt
+        .query_map(params![id], |row| {
+            let task: String = row.get(2)?;
+            Ok(RevisionSummary {
+                revision: row.get(0)?,
+                parent_revision: row.get(1)?,
+                task: task.parse().map_err(|e: String| corrupt(2, e))?,
+                created_at: row.get(3)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    // Every snippet has at least one revision, so an empty list means it doesn't exist.
+    Ok((!revisions.is_empty()).then_some(revisions))
+}
+
+// Copies a revision (or the latest one) into a brand new lineage.
+pub fn fork(
+    conn: &mut Connection,
+    id: &str,
+    revision: Option<i64>,
+) -> rusqlite::Result<Option<Snippet>> {
+    let tx = conn.transaction()?;
+
+    let Some(source) = get(&tx, id, revision)? else {
+        return Ok(None);
+    };
+    let forked_from = SnippetRef {
+        id: source.id,
+        revision: source.revision,
+    };
+    let snippet = insert_lineage(
+        &tx,
+        Some(forked_from),
+        CreateSnippetRequest {
+            task: source.task,
+            code: source.code,
+            toolchain: source.toolchain,
+            flags: source.flags,
+        },
+    )?;
+    tx.commit()?;
+
+    Ok(Some(snippet))
+}
+
+// Renders the changes between two revisions as a unified diff.
+pub fn diff(from: &Snippet, to: &Snippet) -> String {
+    let mut out = String::new();
+
+    if from.task != to.task {
+        out.push_str(&format!(
+            "task: {} -> {}\n",
+            from.task.as_str(),
+            to.task.as_str()
+        ));
+    }
+    if from.toolchain != to.toolchain {
+        out.push_str(&format!(
+            "toolchain: {} -> {}\n",
+            from.toolchain, to.toolchain
+        ));
+    }
+    if from.flags != to.flags {
+        out.push_str(&format!(
+            "flags: {} -> {}\n",
+            from.flags.join(" "),
+            to.flags.join(" ")
+        ));
+    }
+
+    out.push_str(
+        &similar::TextDiff::from_lines(&from.code, &to.code)
+            .unified_diff()
+            .header(
+                &format!("{}@{}", from.id, from.revision),
+                &format!("{}@{}", to.id, to.revision),
+            )
+            .to_string(),
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db, models::TaskType};
+
+    fn request(code: &str) -> CreateSnippetRequest {
+        CreateSnippetRequest {
+            task: TaskType::Llvm,
+            code: code.to_string(),
+            toolchain: "nightly".to_string(),
+            flags: vec!["-O2".to_string()],
+        }
+    }
+
+    #[test]
+    fn snippets_round_trip() {
+        let db = db::open(":memory:").unwrap();
+        let mut conn = db.lock().unwrap();
+        let created = insert(&mut conn, request("fn main() {}")).unwrap();
+        assert_eq!(created.id.len(), ID_LENGTH);
+
+        let loaded = get(&conn, &created.id, None).unwrap().unwrap();
+        assert_eq!((loaded.revision, loaded.parent_revision), (1, None));
+        assert_eq!(loaded.task, TaskType::Llvm);
+        assert_eq!(loaded.toolchain, "nightly");
+        assert_eq!(loaded.flags, ["-O2"]);
+        assert_eq!(loaded.code, "fn main() {}");
+        assert_eq!(loaded.created_at, created.created_at);
+
+        assert!(get(&conn, &created.id, Some(2)).unwrap().is_none());
+        assert!(get(&conn, "unknown", None).unwrap().is_none());
+    }
+
+    #[test]
+    fn revisions_build_on_their_parent() {
+        let db = db::open(":memory:").unwrap();
+        let mut conn = db.lock().unwrap();
+        let id = insert(&mut conn, request("1")).unwrap().id;
+
+        let second = save_revision(&mut conn, &id, None, request("2"))
+            .unwrap()
+            .unwrap();
+        assert_eq!((second.revision, second.parent_revision), (2, Some(1)));
+        // Branching off an older revision still gets the next number
+        let third = save_revision(&mut conn, &id, Some(1), request("3"))
+            .unwrap()
+            .unwrap();
+        assert_eq!((third.revision, third.parent_revision), (3, Some(1)));
+        assert!(
+            save_revision(&mut conn, &id, Some(9), request("4"))
+                .unwrap()
+                .is_none()
+        );
+
+        assert_eq!(get(&conn, &id, None).unwrap().unwrap().code, "3");
+        assert_eq!(get(&conn, &id, Some(2)).unwrap().unwrap().code, "2");
+        let revisions: Vec<_> = list_revisions(&conn, &id)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|summary| (summary.revision, summary.parent_revision))
+            .collect();
+        assert_eq!(revisions, [(1, None), (2, Some(1)), (3, Some(1))]);
+        assert!(list_revisions(&conn, "unknown").unwrap().is_none());
+    }
+
+    #[test]
+    fn forks_start_a_new_lineage() {
+        let db = db::open(":memory:").unwrap();
+        let mut conn = db.lock().unwrap();
+        let id = insert(&mut conn, request("1")).unwrap().id;
+        save_revision(&mut conn, &id, None, request("2")).unwrap();
+
+        let fork = fork(&mut conn, &id, Some(1)).unwrap().unwrap();
+        assert_ne!(fork.id, id);
+        assert_eq!((fork.revision, fork.parent_revision), (1, None));
+        assert_eq!(fork.code, "1");
+
+        let loaded = get(&conn, &fork.id, None).unwrap().unwrap();
+        let forked_from = loaded.forked_from.unwrap();
+        assert_eq!(
+            (forked_from.id.as_str(), forked_from.revision),
+            (id.as_str(), 1)
+        );
+        assert!(super::fork(&mut conn, "unknown", None).unwrap().is_none());
+    }
+
+    #[test]
+    fn corrupt_rows_fail_to_load() {
+        let db = db::open(":memory:").unwrap();
+        let mut conn = db.lock().unwrap();
+        let id = insert(&mut conn, request("1")).unwrap().id;
+
+        conn.execute("UPDATE snippet_revisions SET flags = 'nope'", [])
+            .unwrap();
+        assert!(get(&conn, &id, None).is_err());
+        conn.execute(
+            "UPDATE snippet_revisions SET flags = '[]', task = 'bogus'",
+            [],
+        )
+        .unwrap();
+        assert!(get(&conn, &id, None).is_err());
+        assert!(list_revisions(&conn, &id).is_err());
+    }
+
+    #[test]
+    fn oversized_snippets_are_rejected() {
+        assert!(validate(&request("fn main() {}")).is_ok());
+
+        let code = request(&"x".repeat(MAX_CODE_BYTES + 1));
+        assert_eq!(
+            validate(&code).unwrap_err(),
+            format!("Code exceeds {MAX_CODE_BYTES} bytes")
+        );
+
+        let mut flags = request("");
+        flags.flags = vec!["-O".to_string(); MAX_FLAGS + 1];
+        assert!(validate(&flags).is_err());
+        flags.flags = vec!["x".repeat(MAX_FLAG_BYTES + 1)];
+        assert!(validate(&flags).is_err());
+
+        let mut toolchain = request("");
+        toolchain.toolchain = "x".repeat(MAX_TOOLCHAIN_BYTES + 1);
+        assert!(validate(&toolchain).is_err());
+    }
+}
@@ -209,7 +209,7 @@ require(["vs/editor/editor.main"], function () {
     });
 
     // Create editor
-    monaco.editor.create(document.getElementById("editor"), {
+    const editor = monaco.editor.create(document.getElementById("editor"), {
         value: `#include <libc/stdio.zh>
 fn main() -> i32 {
     printf("Hello, Zirco!\\n");
@@ -220,6 +220,36 @@ fn main() -> i32 {
         theme: "vs-dark",
     });
 
+    // Load a shared snippet if the URL points to one
+    const params = new URLSearchParams(window.location.search);
+    let snippetId = params.get("snippet");
+    let snippetRevision = params.get("revision");
+    if (snippetId) {
+        const query = snippetRevision
+            ? `?revision=${encodeURIComponent(snippetRevision)}`
+            : "";
+        fetch(
+            `https://play.zirco.dev/api/v1/snippets/${encodeURIComponent(snippetId)}${query}`,
+        )
+            .then((res) => {
+                if (!res.ok) {
+                    throw new Error(`HTTP error! status: ${res.status}`);
+                }
+                return res.json();
+            })
+            .then((snippet) => {
+                snippetRevision = snippet.revision;
+                editor.setValue(snippet.code);
+                document.getElementById("action").value = snippet.task;
+            })
+            .catch((e) => {
+                console.error("Failed to load snippet:", e);
+                document.getElementById("output").textContent =
+                    `Error: could not load snippet ${snippetId}`;
+                snippetId = null;
+            });
+    }
+
     const ver = document.getElementById("toolchain");
     fetch("https://play.zirco.dev/api/v1/version")
         .then((res) => res.json())
@@ -231,6 +261,198 @@ fn main() -> i32 {
             ver.textContent = "unknown";
         });
 
+    // The message of an API error response, which carries one in its JSON body
+    async function errorMessage(res) {
+        const body = await res.json().catch(() => null);
+        return body?.error?.message ?? `${res.status} ${res.statusText}`;
+    }
+
+    document.getElementById("share").onclick = async function share() {
+        const output = document.getElementById("output");
+        // Saving on top of a loaded snippet creates a new revision of it
+        const endpoint = snippetId
+            ? `https://play.zirco.dev/api/v1/snippets/${encodeURIComponent(snippetId)}/revisions`
+            : "https://play.zirco.dev/api/v1/snippets";
+        const res = await fetch(endpoint, {
+            method: "POST",
+            headers: {
+                "Content-Type": "application/json",
+            },
+            body: JSON.stringify({
+                code: editor.getValue(),
+                task: document.getElementById("action").value,
+                parent: snippetId ? Number(snippetRevision) : undefined,
+            }),
+        });
+
+        if (!res.ok) {
+            output.textContent =
+                res.status === 413
+                    ? "Error: snippet is too large to share"
+                    : `Error: ${await errorMessage(res)}`;
+            return;
+        }
+
+        const { id, revision } = await res.json();
+        snippetId = id;
+        snippetRevision = revision;
+        const url = new URL(window.location.href);
+        url.search = `?snippet=${id}&revision=${revision}`;
+        window.history.replaceState(null, "", url);
+
+        try {
+            await navigator.clipboard.writeText(url.toString());
+            output.textContent = `Link copied to clipboard: ${url}`;
+        } catch {
+            output.textContent = `Share link: ${url}`;
+        }
+    };
+
+    // Key presses that don't produce text, as the bytes a terminal would send for them
+    const terminalKeys = {
+        Enter: "\r",
+        Backspace: "\x7f",
+        Tab: "\t",
+        Escape: "\x1b",
+        ArrowUp: "\x1b[A",
+        ArrowDown: "\x1b[B",
+        ArrowRight: "\x1b[C",
+        ArrowLeft: "\x1b[D",
+    };
+
+    let session = null;
+
+    // Works out how many characters fit in the output pane
+    function terminalSize(output) {
+        const style = getComputedStyle(output);
+        const charWidth = parseFloat(style.fontSize) * 0.6;
+        const lineHeight = parseFloat(style.fontSize) * 1.2;
+        return {
+            cols: Math.max(20, Math.floor(output.clientWidth / charWidth)),
+            rows: Math.max(5, Math.floor(output.clientHeight / lineHeight)),
+        };
+    }
+
+    document.getElementById("interactive").onclick = function interactive() {
+        const output = document.getElementById("output");
+        if (session) {
+            session.close();
+        }
+
+        const url = new URL("https://play.zirco.dev/api/v1/session");
+        url.protocol = "wss:";
+        const ws = new WebSocket(url);
+        ws.binaryType = "arraybuffer";
+        session = ws;
+
+        const ansi = new AnsiUp();
+        const decoder = new TextDecoder();
+        let screen = "";
+        const render = () => {
+            // safe because ansiup sanitizes the output
+            output.innerHTML = ansi.ansi_to_html(screen);
+            output.scrollTop = output.scrollHeight;
+        };
+        // Applies the terminal's line endings and backspaces to what we show
+        const write = (text) => {
+            for (const ch of text.replace(/\r\n/g, "\n")) {
+                if (ch === "\b") {
+                    screen = screen.slice(0, -1);
+                } else if (ch !== "\r" && ch !== "\x07") {
+                    screen += ch;
+                }
+            }
+            render();
+        };
+
+        output.textContent = "Connecting...\n";
+        ws.onopen = () => {
+            ws.send(
+                JSON.stringify({
+                    type: "start",
+                    code: editor.getValue(),
+                    ...terminalSize(output),
+                }),
+            );
+        };
+        ws.onmessage = (event) => {
+            if (typeof event.data !== "string") {
+                write(decoder.decode(event.data, { stream: true }));
+                return;
+            }
+
+            const message = JSON.parse(event.data);
+            switch (message.type) {
+                case "state":
+                    if (message.state === "running") {
+                        screen = "";
+                        render();
+                        output.focus();
+                    } else {
+                        output.textContent = `${message.state[0].toUpperCase()}${message.state.slice(1)}...\n`;
+                    }
+                    break;
+                case "build_failed":
+                    screen = `${message.result.stderr}${message.result.stdout}- Build failed with exit code ${message.result.exit_code}`;
+                    render();
+                    break;
+                case "error":
+                    write(`\n${message.error}`);
+                    break;
+                case "exit":
+                    write(`\n- Program exited with code ${message.exit_code}`);
+                    break;
+            }
+        };
+        ws.onclose = () => {
+            if (session === ws) {
+                session = null;
+            }
+        };
+    };
+
+    const output = document.getElementById("output");
+    output.tabIndex = 0;
+    output.addEventListener("keydown", (event) => {
+        if (!session || session.readyState !== WebSocket.OPEN) {
+            return;
+        }
+
+        let data = terminalKeys[event.key];
+        if (event.ctrlKey && event.key.length === 1) {
+            // Ctrl+A..Ctrl+Z map to the control characters 0x01..0x1a
+            const code = event.key.toUpperCase().charCodeAt(0) - 64;
+            if (code > 0 && code < 27) {
+                data = String.fromCharCode(code);
+            }
+        } else if (!data && event.key.length === 1 && !event.metaKey) {
+            data = event.key;
+        }
+
+        if (data) {
+            event.preventDefault();
+            session.send(JSON.stringify({ type: "input", data }));
+        }
+    });
+    output.addEventListener("paste", (event) => {
+        if (session && session.readyState === WebSocket.OPEN) {
+            event.preventDefault();
+            session.send(
+                JSON.stringify({
+                    type: "input",
+                    data: event.clipboardData.getData("text"),
+                }),
+            );
+        }
+    });
+    window.addEventListener("resize", () => {
+        if (session && session.readyState === WebSocket.OPEN) {
+            session.send(
+                JSON.stringify({ type: "resize", ...terminalSize(output) }),
+            );
+        }
+    });
+
     document.getElementById("run").onclick = async function run() {
         const code = monaco.editor.getModels()[0].getValue();
         const action = document.getElementById("action").value;
@@ -244,10 +466,13 @@ fn main() -> i32 {
                 code,
                 task: action,
             }),
-        }).then((res) => {
+        }).then(async (res) => {
             if (!res.ok) {
                 const output = document.getElementById("output");
-                output.textContent = `Error: ${res.status} ${res.statusText}`;
+                output.textContent =
+                    res.status === 503 || res.status === 429
+                        ? `${res.status === 503 ? "The playground is busy" : "Too many requests"}, try again in ${res.headers.get("Retry-After") ?? "a few"} seconds.`
+                        : `Error: ${await errorMessage(res)}`;
                 throw new Error(`HTTP error! status: ${res.status}`);
             }
             return res.json();
@@ -260,6 +485,42 @@ fn main() -> i32 {
             const eventSource = new EventSource(
                 `https://play.zirco.dev/api/v1/stream/${jobId}`,
             );
+            const stages = {
+                queued: "Queued...",
+                compiling: "Compiling...",
+                linking: "Linking...",
+                running: "Running...",
+            };
+            for (const [stage, text] of Object.entries(stages)) {
+                eventSource.addEventListener(stage, () => {
+                    output.textContent = `${text}\n`;
+                });
+            }
+            eventSource.addEventListener("pending", (event) => {
+                const data = JSON.parse(event.data);
+                if (data.position === undefined) {
+                    return;
+                }
+                const eta = Math.max(
+                    0,
+                    Math.round((data.estimated_start - Date.now()) / 1000),
+                );
+                output.textContent = `Queued (position ${data.position + 1}, starting in ~${eta}s)...\n`;
+            });
+            // Shown as it comes, then replaced by the full output once the job completes
+            eventSource.addEventListener("output", (event) => {
+                output.textContent += JSON.parse(event.data).data;
+            });
+            for (const state of ["cancelled", "expired", "not_found"]) {
+                eventSource.addEventListener(state, () => {
+                    output.textContent += `\nJob ${state.replace("_", " ")}.`;
+                    eventSource.close();
+                });
+            }
+            eventSource.addEventListener("gone", () => {
+                output.textContent += "\nThe job's result is no longer available.";
+                eventSource.close();
+            });
             eventSource.addEventListener("timeout", (event) => {
                 output.textContent += "\nExecution timed out.";
                 eventSource.close();